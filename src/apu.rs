@@ -0,0 +1,783 @@
+use std::collections::VecDeque;
+
+use log::trace;
+
+use crate::memory::Memory;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+const CPU_CLOCK_RATE: u32 = 4194304;
+//The frame sequencer runs at 512Hz and clocks length, sweep and envelope
+const FRAME_SEQUENCER_PERIOD: u32 = CPU_CLOCK_RATE / 512;
+//Each channel outputs at most 15 so this keeps all four mixed together inside an i16
+const AMPLITUDE_SCALE: i32 = 256;
+//NR50's volumes go from 0 to 7 and even 0 isn't quite silent so each side is scaled by volume + 1
+const MAX_MASTER_VOLUME: i32 = 8;
+
+const NR10: u16 = 0xFF10;
+const NR20: u16 = 0xFF15;
+const NR30: u16 = 0xFF1A;
+//32 4 bit samples packed two to a byte with the first one in the high nibble
+const WAVE_RAM: u16 = 0xFF30;
+const WAVE_SAMPLES: u8 = 32;
+//Channel 4 has no NRx0 so its registers start at NR41
+const NR41: u16 = 0xFF20;
+//The noise clock divider codes from NR43, 0 is treated as half of 16
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+// -LLL -RRR master volume for the left and right outputs
+const NR50: u16 = 0xFF24;
+// Which channels go to each output, the top nibble is left and the bottom right with channel 1 in
+// the lowest bit of each
+const NR51: u16 = 0xFF25;
+// P--- 4321 bit 7 powers the whole apu and the bottom nibble reads back which channels are playing
+pub const NR52: u16 = 0xFF26;
+pub const APU_ENABLED: u8 = 0x80;
+pub const NR52_UNUSED_BITS: u8 = 0x70;
+//Turning the apu off clears every register from NR10 to NR51, wave ram is left alone
+pub const FIRST_SOUND_REGISTER: u16 = NR10;
+pub const LAST_SOUND_REGISTER: u16 = NR51;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+#[derive(Clone)]
+pub struct Apu {
+    channel1: SquareChannel,
+    channel2: SquareChannel,
+    channel3: WaveChannel,
+    channel4: NoiseChannel,
+    frame_sequencer_clocks: u32,
+    frame_sequencer_step: u8,
+    sample_rate: u32,
+    sample_clocks: u32,
+    //Interleaved left/right samples waiting to be played
+    samples: VecDeque<i16>,
+}
+
+#[derive(Clone)]
+struct SquareChannel {
+    //Address of NRx0, the rest of the channel registers follow it
+    base_address: u16,
+    has_sweep: bool,
+    enabled: bool,
+    frequency_timer: u32,
+    duty_position: u8,
+    volume: u8,
+    envelope_timer: u8,
+    length_counter: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    shadow_frequency: u16,
+}
+
+#[derive(Clone)]
+struct WaveChannel {
+    enabled: bool,
+    frequency_timer: u32,
+    //Which of the 32 samples in wave ram is playing
+    position: u8,
+    //Wave lengths go up to 256 so this doesn't fit in a u8 like the others
+    length_counter: u16,
+}
+
+#[derive(Clone)]
+struct NoiseChannel {
+    enabled: bool,
+    frequency_timer: u32,
+    //15 bits of shift register, the output is the inverse of bit 0
+    lfsr: u16,
+    volume: u8,
+    envelope_timer: u8,
+    length_counter: u8,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            channel1: SquareChannel::new(NR10, true),
+            channel2: SquareChannel::new(NR20, false),
+            channel3: WaveChannel::new(),
+            channel4: NoiseChannel::new(),
+            frame_sequencer_clocks: 0,
+            frame_sequencer_step: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_clocks: 0,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.samples.clear();
+    }
+
+    //Throws away any samples that haven't been played yet
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+
+    //Copies as many buffered samples as will fit into out and returns how many were copied
+    pub fn read_samples(&mut self, out: &mut [i16]) -> usize {
+        let count = out.len().min(self.samples.len());
+        for (sample, buffered) in out.iter_mut().zip(self.samples.drain(..count)) {
+            *sample = buffered;
+        }
+        count
+    }
+
+    //Like the ppu the apu keeps running at 4MHz in double speed mode so this takes clocks
+    pub fn step(&mut self, memory: &mut Memory, clocks: u16) {
+        let clocks = clocks as u32;
+
+        if memory.apu_enabled() {
+            self.channel1.check_trigger(memory);
+            self.channel2.check_trigger(memory);
+            self.channel3.check_trigger(memory);
+            self.channel4.check_trigger(memory);
+
+            self.channel1.step(memory, clocks);
+            self.channel2.step(memory, clocks);
+            self.channel3.step(memory, clocks);
+            self.channel4.step(memory, clocks);
+
+            self.frame_sequencer_clocks += clocks;
+            while self.frame_sequencer_clocks >= FRAME_SEQUENCER_PERIOD {
+                self.frame_sequencer_clocks -= FRAME_SEQUENCER_PERIOD;
+                self.step_frame_sequencer(memory);
+            }
+        } else {
+            self.power_off();
+        }
+        self.update_status(memory);
+
+        self.sample_clocks += clocks * self.sample_rate;
+        while self.sample_clocks >= CPU_CLOCK_RATE {
+            self.sample_clocks -= CPU_CLOCK_RATE;
+            self.push_sample(memory);
+        }
+    }
+
+    //Memory has already cleared the registers so only the channels' own state needs resetting
+    fn power_off(&mut self) {
+        self.channel1 = SquareChannel::new(NR10, true);
+        self.channel2 = SquareChannel::new(NR20, false);
+        self.channel3 = WaveChannel::new();
+        self.channel4 = NoiseChannel::new();
+        self.frame_sequencer_clocks = 0;
+        self.frame_sequencer_step = 0;
+    }
+
+    fn update_status(&self, memory: &mut Memory) {
+        let power = memory.read_u8(NR52) & APU_ENABLED;
+        let status = [
+            self.channel1.enabled,
+            self.channel2.enabled,
+            self.channel3.enabled,
+            self.channel4.enabled,
+        ]
+        .iter()
+        .enumerate()
+        .filter(|(_, enabled)| **enabled)
+        .fold(0, |status, (channel, _)| status | 1 << channel);
+        memory.write_special_regsiter(NR52, power | NR52_UNUSED_BITS | status);
+    }
+
+    fn step_frame_sequencer(&mut self, memory: &mut Memory) {
+        // Step   Length Ctr  Vol Env     Sweep
+        // 0      Clock       -           -
+        // 2      Clock       -           Clock
+        // 4      Clock       -           -
+        // 6      Clock       -           Clock
+        // 7      -           Clock       -
+        let step = self.frame_sequencer_step;
+        if step & 1 == 0 {
+            self.channel1.clock_length(memory);
+            self.channel2.clock_length(memory);
+            self.channel3.clock_length(memory);
+            self.channel4.clock_length(memory);
+        }
+        if step == 2 || step == 6 {
+            self.channel1.clock_sweep(memory);
+        }
+        if step == 7 {
+            self.channel1.clock_envelope(memory);
+            self.channel2.clock_envelope(memory);
+            self.channel4.clock_envelope(memory);
+        }
+        self.frame_sequencer_step = (step + 1) & 7;
+    }
+
+    fn push_sample(&mut self, memory: &Memory) {
+        let outputs = [
+            self.channel1.output(memory),
+            self.channel2.output(memory),
+            self.channel3.output(memory),
+            self.channel4.output(memory),
+        ];
+        let panning = memory.read_u8(NR51);
+        let master_volume = memory.read_u8(NR50);
+        let left = Self::mix(&outputs, panning >> 4, (master_volume >> 4) & 0x7);
+        let right = Self::mix(&outputs, panning & 0xF, master_volume & 0x7);
+        // Drop the oldest audio if nobody is draining the buffer
+        while self.samples.len() + 2 > self.max_buffered_samples() {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(left);
+        self.samples.push_back(right);
+    }
+
+    //Adds up the channels routed to one side and scales them by that side's volume
+    fn mix(outputs: &[i16], routing: u8, volume: u8) -> i16 {
+        let mixed: i32 = outputs
+            .iter()
+            .enumerate()
+            .filter(|(channel, _)| routing & (1 << channel) != 0)
+            .map(|(_, output)| *output as i32)
+            .sum();
+        (mixed * (volume as i32 + 1) * AMPLITUDE_SCALE / MAX_MASTER_VOLUME) as i16
+    }
+
+    //Half a second of stereo audio
+    fn max_buffered_samples(&self) -> usize {
+        self.sample_rate as usize
+    }
+}
+
+impl SquareChannel {
+    fn new(base_address: u16, has_sweep: bool) -> Self {
+        Self {
+            base_address,
+            has_sweep,
+            enabled: false,
+            frequency_timer: 0,
+            duty_position: 0,
+            volume: 0,
+            envelope_timer: 0,
+            length_counter: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            shadow_frequency: 0,
+        }
+    }
+
+    // Registers
+    // NRx0 -PPP NSSS Sweep period, negate, shift (channel 1 only)
+    // NRx1 DDLL LLLL Duty, Length load (64-L)
+    // NRx2 VVVV APPP Starting volume, Envelope add mode, period
+    // NRx3 FFFF FFFF Frequency LSB
+    // NRx4 TL-- -FFF Trigger, Length enable, Frequency MSB
+    fn register(&self, memory: &Memory, offset: u16) -> u8 {
+        memory.read_u8(self.base_address + offset)
+    }
+
+    fn frequency(&self, memory: &Memory) -> u16 {
+        ((self.register(memory, 4) as u16 & 0x7) << 8) | self.register(memory, 3) as u16
+    }
+
+    fn set_frequency(&self, memory: &mut Memory, frequency: u16) {
+        let nrx4 = self.register(memory, 4);
+        memory.write_special_regsiter(self.base_address + 3, frequency as u8);
+        memory.write_special_regsiter(
+            self.base_address + 4,
+            (nrx4 & 0xF8) | ((frequency >> 8) as u8 & 0x7),
+        );
+    }
+
+    fn period(&self, memory: &Memory) -> u32 {
+        (2048 - self.frequency(memory) as u32) * 4
+    }
+
+    fn dac_enabled(&self, memory: &Memory) -> bool {
+        self.register(memory, 2) & 0xF8 != 0
+    }
+
+    fn check_trigger(&mut self, memory: &mut Memory) {
+        let nrx4 = self.register(memory, 4);
+        if nrx4 & 0x80 == 0 {
+            return;
+        }
+        //The trigger bit doesn't stick around once it has been handled
+        memory.write_special_regsiter(self.base_address + 4, nrx4 & 0x7F);
+        self.trigger(memory);
+    }
+
+    fn trigger(&mut self, memory: &mut Memory) {
+        trace!("Triggering square channel at {:x}", self.base_address);
+        self.enabled = self.dac_enabled(memory);
+        if self.length_counter == 0 {
+            self.length_counter = 64 - (self.register(memory, 1) & 0x3F);
+        }
+        self.frequency_timer = self.period(memory);
+        let nrx2 = self.register(memory, 2);
+        self.volume = nrx2 >> 4;
+        self.envelope_timer = nrx2 & 0x7;
+
+        if self.has_sweep {
+            let nrx0 = self.register(memory, 0);
+            let sweep_period = (nrx0 >> 4) & 0x7;
+            let sweep_shift = nrx0 & 0x7;
+            self.shadow_frequency = self.frequency(memory);
+            self.sweep_timer = if sweep_period == 0 { 8 } else { sweep_period };
+            self.sweep_enabled = sweep_period != 0 || sweep_shift != 0;
+            if sweep_shift != 0 {
+                self.calculate_sweep(memory);
+            }
+        }
+    }
+
+    fn step(&mut self, memory: &Memory, clocks: u32) {
+        let mut clocks = clocks;
+        while clocks >= self.frequency_timer {
+            clocks -= self.frequency_timer;
+            self.frequency_timer = self.period(memory);
+            self.duty_position = (self.duty_position + 1) & 7;
+        }
+        self.frequency_timer -= clocks;
+    }
+
+    fn clock_length(&mut self, memory: &Memory) {
+        let length_enabled = self.register(memory, 4) & 0x40 != 0;
+        if length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn clock_envelope(&mut self, memory: &Memory) {
+        let nrx2 = self.register(memory, 2);
+        let period = nrx2 & 0x7;
+        if period == 0 {
+            return;
+        }
+        if self.envelope_timer > 0 {
+            self.envelope_timer -= 1;
+        }
+        if self.envelope_timer == 0 {
+            self.envelope_timer = period;
+            let increase = nrx2 & 0x8 != 0;
+            if increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn clock_sweep(&mut self, memory: &mut Memory) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer != 0 {
+            return;
+        }
+        let nrx0 = self.register(memory, 0);
+        let sweep_period = (nrx0 >> 4) & 0x7;
+        self.sweep_timer = if sweep_period == 0 { 8 } else { sweep_period };
+        if self.sweep_enabled && sweep_period != 0 {
+            let new_frequency = self.calculate_sweep(memory);
+            if new_frequency <= 2047 && nrx0 & 0x7 != 0 {
+                self.shadow_frequency = new_frequency;
+                self.set_frequency(memory, new_frequency);
+                //Do the overflow check again with the new frequency
+                self.calculate_sweep(memory);
+            }
+        }
+    }
+
+    //Works out the next sweep frequency and turns the channel off if it overflows
+    fn calculate_sweep(&mut self, memory: &Memory) -> u16 {
+        let nrx0 = self.register(memory, 0);
+        let delta = self.shadow_frequency >> (nrx0 & 0x7);
+        let new_frequency = if nrx0 & 0x8 != 0 {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+        if new_frequency > 2047 {
+            self.enabled = false;
+        }
+        new_frequency
+    }
+
+    fn output(&self, memory: &Memory) -> i16 {
+        if !self.enabled || !self.dac_enabled(memory) {
+            return 0;
+        }
+        let duty = (self.register(memory, 1) >> 6) as usize;
+        let volume = self.volume as i16;
+        if DUTY_PATTERNS[duty][self.duty_position as usize] == 1 {
+            volume
+        } else {
+            -volume
+        }
+    }
+}
+
+impl WaveChannel {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            frequency_timer: 0,
+            position: 0,
+            length_counter: 0,
+        }
+    }
+
+    // Registers
+    // NR30 E--- ---- DAC power
+    // NR31 LLLL LLLL Length load (256-L)
+    // NR32 -VV- ---- Volume code (0=0%, 1=100%, 2=50%, 3=25%)
+    // NR33 FFFF FFFF Frequency LSB
+    // NR34 TL-- -FFF Trigger, Length enable, Frequency MSB
+    fn register(&self, memory: &Memory, offset: u16) -> u8 {
+        memory.read_u8(NR30 + offset)
+    }
+
+    fn frequency(&self, memory: &Memory) -> u16 {
+        ((self.register(memory, 4) as u16 & 0x7) << 8) | self.register(memory, 3) as u16
+    }
+
+    //The wave channel steps through its samples twice as fast as the square channels
+    fn period(&self, memory: &Memory) -> u32 {
+        (2048 - self.frequency(memory) as u32) * 2
+    }
+
+    fn dac_enabled(&self, memory: &Memory) -> bool {
+        self.register(memory, 0) & 0x80 != 0
+    }
+
+    fn check_trigger(&mut self, memory: &mut Memory) {
+        let nr34 = self.register(memory, 4);
+        if nr34 & 0x80 == 0 {
+            return;
+        }
+        //The trigger bit doesn't stick around once it has been handled
+        memory.write_special_regsiter(NR30 + 4, nr34 & 0x7F);
+        self.trigger(memory);
+    }
+
+    fn trigger(&mut self, memory: &Memory) {
+        trace!("Triggering wave channel");
+        self.enabled = self.dac_enabled(memory);
+        if self.length_counter == 0 {
+            self.length_counter = 256 - self.register(memory, 1) as u16;
+        }
+        self.frequency_timer = self.period(memory);
+        self.position = 0;
+    }
+
+    fn step(&mut self, memory: &Memory, clocks: u32) {
+        let mut clocks = clocks;
+        while clocks >= self.frequency_timer {
+            clocks -= self.frequency_timer;
+            self.frequency_timer = self.period(memory);
+            self.position = (self.position + 1) % WAVE_SAMPLES;
+        }
+        self.frequency_timer -= clocks;
+    }
+
+    fn clock_length(&mut self, memory: &Memory) {
+        let length_enabled = self.register(memory, 4) & 0x40 != 0;
+        if length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn sample(&self, memory: &Memory) -> u8 {
+        let byte = memory.read_u8(WAVE_RAM + (self.position / 2) as u16);
+        if self.position & 1 == 0 {
+            byte >> 4
+        } else {
+            byte & 0xF
+        }
+    }
+
+    fn output(&self, memory: &Memory) -> i16 {
+        if !self.enabled || !self.dac_enabled(memory) {
+            return 0;
+        }
+        let volume_code = (self.register(memory, 2) >> 5) & 0x3;
+        if volume_code == 0 {
+            return 0;
+        }
+        //Center the sample around 0 like the square channels before shifting it down, which
+        //keeps quieter volumes from adding an offset
+        let centered = self.sample(memory) as i16 * 2 - 15;
+        centered >> (volume_code - 1)
+    }
+}
+
+impl NoiseChannel {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            frequency_timer: 0,
+            lfsr: 0x7FFF,
+            volume: 0,
+            envelope_timer: 0,
+            length_counter: 0,
+        }
+    }
+
+    // Registers
+    // NR41 --LL LLLL Length load (64-L)
+    // NR42 VVVV APPP Starting volume, Envelope add mode, period
+    // NR43 SSSS WDDD Clock shift, Width mode of LFSR, Divisor code
+    // NR44 TL-- ---- Trigger, Length enable
+    fn register(&self, memory: &Memory, offset: u16) -> u8 {
+        memory.read_u8(NR41 + offset)
+    }
+
+    fn period(&self, memory: &Memory) -> u32 {
+        let nr43 = self.register(memory, 2);
+        NOISE_DIVISORS[(nr43 & 0x7) as usize] << (nr43 >> 4)
+    }
+
+    fn dac_enabled(&self, memory: &Memory) -> bool {
+        self.register(memory, 1) & 0xF8 != 0
+    }
+
+    fn check_trigger(&mut self, memory: &mut Memory) {
+        let nr44 = self.register(memory, 3);
+        if nr44 & 0x80 == 0 {
+            return;
+        }
+        //The trigger bit doesn't stick around once it has been handled
+        memory.write_special_regsiter(NR41 + 3, nr44 & 0x7F);
+        self.trigger(memory);
+    }
+
+    fn trigger(&mut self, memory: &Memory) {
+        trace!("Triggering noise channel");
+        self.enabled = self.dac_enabled(memory);
+        if self.length_counter == 0 {
+            self.length_counter = 64 - (self.register(memory, 0) & 0x3F);
+        }
+        self.frequency_timer = self.period(memory);
+        self.lfsr = 0x7FFF;
+        let nr42 = self.register(memory, 1);
+        self.volume = nr42 >> 4;
+        self.envelope_timer = nr42 & 0x7;
+    }
+
+    fn step(&mut self, memory: &Memory, clocks: u32) {
+        let mut clocks = clocks;
+        while clocks >= self.frequency_timer {
+            clocks -= self.frequency_timer;
+            self.frequency_timer = self.period(memory);
+            let short = self.register(memory, 2) & 0x8 != 0;
+            self.step_lfsr(short);
+        }
+        self.frequency_timer -= clocks;
+    }
+
+    //The xor of the bottom two bits gets shifted in at the top, in 7 bit mode it also goes into
+    //bit 6 which makes the pattern repeat much sooner
+    fn step_lfsr(&mut self, short: bool) {
+        let xor = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (xor << 14);
+        if short {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+        }
+    }
+
+    fn clock_length(&mut self, memory: &Memory) {
+        let length_enabled = self.register(memory, 3) & 0x40 != 0;
+        if length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn clock_envelope(&mut self, memory: &Memory) {
+        let nr42 = self.register(memory, 1);
+        let period = nr42 & 0x7;
+        if period == 0 {
+            return;
+        }
+        if self.envelope_timer > 0 {
+            self.envelope_timer -= 1;
+        }
+        if self.envelope_timer == 0 {
+            self.envelope_timer = period;
+            let increase = nr42 & 0x8 != 0;
+            if increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn output(&self, memory: &Memory) -> i16 {
+        if !self.enabled || !self.dac_enabled(memory) {
+            return 0;
+        }
+        let volume = self.volume as i16;
+        if self.lfsr & 1 == 0 {
+            volume
+        } else {
+            -volume
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::Model;
+    use crate::memory::RomChunk;
+
+    const NR21: u16 = 0xFF16;
+    const NR22: u16 = 0xFF17;
+    const NR23: u16 = 0xFF18;
+    const NR24: u16 = 0xFF19;
+
+    //An apu with the registers the boot rom leaves behind, which has every channel routed to both
+    //sides at full volume
+    fn setup() -> (Apu, Memory) {
+        let mut memory = Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        memory.init_post_boot_io();
+        (Apu::new(), memory)
+    }
+
+    //Runs the apu for that many clocks and hands back every sample it made
+    fn run(apu: &mut Apu, memory: &mut Memory, clocks: u32) -> Vec<i16> {
+        for _ in 0..clocks / 4 {
+            apu.step(memory, 4);
+        }
+        let mut samples = vec![0; apu.samples.len()];
+        apu.read_samples(&mut samples);
+        samples
+    }
+
+    //Channel 2 at full volume on a 50% duty at about 4kHz
+    fn trigger_channel2(memory: &mut Memory) {
+        memory.write_u8(NR21, 0x80);
+        memory.write_u8(NR22, 0xF0);
+        memory.write_u8(NR23, 0x00);
+        memory.write_u8(NR24, 0x87);
+    }
+
+    #[test]
+    fn channel2_makes_a_tone_once_triggered() {
+        let (mut apu, mut memory) = setup();
+        let silent = run(&mut apu, &mut memory, 40_000);
+        assert!(!silent.is_empty());
+        assert!(silent.iter().all(|sample| *sample == 0));
+
+        trigger_channel2(&mut memory);
+        let playing = run(&mut apu, &mut memory, 40_000);
+        assert!(playing.iter().any(|sample| *sample > 0));
+        assert!(playing.iter().any(|sample| *sample < 0));
+    }
+
+    #[test]
+    fn channel2_routed_right_only_is_silent_on_the_left() {
+        let (mut apu, mut memory) = setup();
+        memory.write_u8(NR51, 0x02);
+        trigger_channel2(&mut memory);
+        let samples = run(&mut apu, &mut memory, 40_000);
+        let (left, right): (Vec<_>, Vec<_>) =
+            samples.chunks(2).map(|pair| (pair[0], pair[1])).unzip();
+        assert!(left.iter().all(|sample| *sample == 0));
+        assert!(right.iter().any(|sample| *sample != 0));
+    }
+
+    #[test]
+    fn wave_channel_steps_through_wave_ram() {
+        let (mut apu, mut memory) = setup();
+        //Two ramps from 0 to 15, 0x01 0x23 ... 0xEF
+        for i in 0..16 {
+            memory.write_u8(WAVE_RAM + i, (i % 8) as u8 * 0x22 + 0x01);
+        }
+        memory.write_u8(NR30, 0x80);
+        memory.write_u8(NR30 + 2, 0x20);
+        //Frequency 2040 moves on a sample every 16 clocks
+        memory.write_u8(NR30 + 3, 0xF8);
+        memory.write_u8(NR30 + 4, 0x87);
+        apu.step(&mut memory, 0);
+        for i in 0..64 {
+            let sample = i % 16;
+            assert_eq!(
+                apu.channel3.output(&memory),
+                sample * 2 - 15,
+                "sample {}",
+                i
+            );
+            apu.step(&mut memory, 16);
+        }
+
+        //Half volume shifts the same samples down by one
+        memory.write_u8(NR30 + 2, 0x40);
+        assert_eq!(apu.channel3.output(&memory), -15 >> 1);
+    }
+
+    //The bit the noise channel plays for each step of the shift register from a fresh trigger
+    fn lfsr_outputs(short: bool, steps: usize) -> Vec<u16> {
+        let mut channel = NoiseChannel::new();
+        (0..steps)
+            .map(|_| {
+                channel.step_lfsr(short);
+                channel.lfsr & 1
+            })
+            .collect()
+    }
+
+    #[test]
+    fn short_lfsr_repeats_every_127_steps() {
+        let outputs = lfsr_outputs(true, 127 * 3);
+        assert_eq!(outputs[..127], outputs[127..254]);
+        assert_eq!(outputs[..127], outputs[254..]);
+        assert!(outputs.contains(&0) && outputs.contains(&1));
+    }
+
+    #[test]
+    fn long_lfsr_doesnt_repeat_within_127_steps() {
+        let outputs = lfsr_outputs(false, 127 * 2);
+        assert_ne!(outputs[..127], outputs[127..]);
+        //Its full period is 32767 steps
+        let outputs = lfsr_outputs(false, 32767 * 2);
+        assert_eq!(outputs[..32767], outputs[32767..]);
+    }
+
+    #[test]
+    fn nr52_reports_channels_and_powering_off_clears_the_registers() {
+        let (mut apu, mut memory) = setup();
+        trigger_channel2(&mut memory);
+        apu.step(&mut memory, 4);
+        assert_eq!(memory.read_u8(NR52), APU_ENABLED | NR52_UNUSED_BITS | 0x02);
+
+        memory.write_u8(NR52, 0x00);
+        apu.step(&mut memory, 4);
+        assert_eq!(memory.read_u8(NR52), NR52_UNUSED_BITS);
+        for register in FIRST_SOUND_REGISTER..=LAST_SOUND_REGISTER {
+            assert_eq!(memory.read_u8(register), 0, "register {:04X}", register);
+        }
+        //Writes while it's off are dropped
+        memory.write_u8(NR21, 0x80);
+        assert_eq!(memory.read_u8(NR21), 0);
+
+        memory.write_u8(NR52, APU_ENABLED);
+        memory.write_u8(NR21, 0x80);
+        assert_eq!(memory.read_u8(NR21), 0x80);
+        apu.step(&mut memory, 4);
+        assert_eq!(memory.read_u8(NR52), APU_ENABLED | NR52_UNUSED_BITS);
+    }
+}
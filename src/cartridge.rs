@@ -0,0 +1,128 @@
+use log::warn;
+
+const TITLE_START: usize = 0x0134;
+const TITLE_END: usize = 0x0143;
+const CGB_FLAG_ADDRESS: usize = 0x0143;
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x0147;
+const ROM_SIZE_ADDRESS: usize = 0x0148;
+const RAM_SIZE_ADDRESS: usize = 0x0149;
+const HEADER_CHECKSUM_ADDRESS: usize = 0x014D;
+//Two bytes, high byte first
+const GLOBAL_CHECKSUM_ADDRESS: usize = 0x014E;
+// Bits of the cgb flag
+const CGB_SUPPORTED: u8 = 0x80;
+const CGB_ONLY: u8 = 0xC0;
+// The header checksum covers everything from the title up to the byte before it
+const CHECKSUM_START: usize = TITLE_START;
+const CHECKSUM_END: usize = 0x014C;
+
+//Everything we use out of the cartridge header at 0x0100-0x014F
+#[derive(Debug, Clone)]
+pub struct CartridgeHeader {
+    pub title: String,
+    //0x80 means the game also works on a CGB, 0xC0 that it only works on one
+    pub cgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    //Sum of every other byte in the rom, nothing checks it but it tells apart games with the
+    //same title
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    //The rom has to be at least as long as the header, Memory::new checks that before calling this
+    pub fn parse(rom: &[u8]) -> Self {
+        let header_checksum = rom[HEADER_CHECKSUM_ADDRESS];
+        let checksum = Self::checksum(rom);
+        if checksum != header_checksum {
+            warn!(
+                "Header checksum is {:02x} but the header says it should be {:02x}",
+                checksum, header_checksum
+            );
+        }
+        Self {
+            title: Self::parse_title(rom),
+            cgb_flag: rom[CGB_FLAG_ADDRESS],
+            cartridge_type: rom[CARTRIDGE_TYPE_ADDRESS],
+            rom_size: rom[ROM_SIZE_ADDRESS],
+            ram_size: rom[RAM_SIZE_ADDRESS],
+            global_checksum: u16::from_be_bytes([
+                rom[GLOBAL_CHECKSUM_ADDRESS],
+                rom[GLOBAL_CHECKSUM_ADDRESS + 1],
+            ]),
+        }
+    }
+
+    //The boot rom refuses to start a game unless this matches
+    pub fn checksum(rom: &[u8]) -> u8 {
+        rom[CHECKSUM_START..=CHECKSUM_END]
+            .iter()
+            .fold(0u8, |checksum, byte| {
+                checksum.wrapping_sub(*byte).wrapping_sub(1)
+            })
+    }
+
+    //The title is padded with zeros and newer carts reuse the last few bytes for other flags so
+    //stop at the first thing that isn't printable
+    fn parse_title(rom: &[u8]) -> String {
+        rom[TITLE_START..=TITLE_END]
+            .iter()
+            .take_while(|byte| byte.is_ascii_graphic() || **byte == b' ')
+            .map(|byte| *byte as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+
+    pub fn cgb_supported(&self) -> bool {
+        self.cgb_flag & CGB_SUPPORTED != 0
+    }
+
+    pub fn cgb_only(&self) -> bool {
+        self.cgb_flag & CGB_ONLY == CGB_ONLY
+    }
+
+    //Rom size from the header, 32KB doubled for every step
+    pub fn rom_size_bytes(&self) -> usize {
+        0x8000usize.checked_shl(self.rom_size as u32).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CartridgeHeader;
+
+    //The header Tetris 1.1 has
+    fn tetris_header() -> Vec<u8> {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x13A].copy_from_slice(b"TETRIS");
+        rom[0x14B] = 0x01;
+        rom[0x14C] = 0x01;
+        rom[0x14D] = 0x0A;
+        rom[0x14E] = 0x16;
+        rom[0x14F] = 0xBF;
+        rom
+    }
+
+    #[test]
+    fn parses_a_hand_built_header() {
+        let rom = tetris_header();
+        assert_eq!(CartridgeHeader::checksum(&rom), 0x0A);
+        let header = CartridgeHeader::parse(&rom);
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.global_checksum, 0x16BF);
+        assert_eq!(header.cartridge_type, 0x00);
+        assert!(!header.cgb_supported());
+    }
+
+    #[test]
+    fn title_stops_at_the_cgb_flag() {
+        let mut rom = tetris_header();
+        rom[0x134..=0x143].copy_from_slice(b"POKEMON_GLDAAUE\x80");
+        let header = CartridgeHeader::parse(&rom);
+        assert_eq!(header.title, "POKEMON_GLDAAUE");
+        assert!(header.cgb_supported());
+        assert_ne!(CartridgeHeader::checksum(&rom), 0x0A);
+    }
+}
@@ -0,0 +1,174 @@
+use log::info;
+
+use crate::error::EmuError;
+use crate::Result;
+
+//The last rom address a Game Genie can patch
+const END_OF_ROM: u16 = 0x7FFF;
+
+//Game Genie codes patch what the cpu reads from the rom, GameShark codes keep poking values into
+//ram once a frame
+#[derive(Clone, Default)]
+pub struct Cheats {
+    game_genie: Vec<GameGenie>,
+    game_shark: Vec<GameShark>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct GameGenie {
+    address: u16,
+    value: u8,
+    //Only patch when the rom has this byte, which keeps the code to one bank of a banked rom
+    compare: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct GameShark {
+    address: u16,
+    value: u8,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //Takes a Game Genie code like 00A-17B-C49 or 00A-17B or a GameShark code like 01FF34C2
+    pub fn add(&mut self, code: &str) -> Result<()> {
+        let code = code.trim();
+        if code.contains('-') {
+            let cheat = GameGenie::parse(code).ok_or_else(|| bad_code(code))?;
+            info!(
+                "Added Game Genie code {} patching {:04X} to {:02X}",
+                code, cheat.address, cheat.value
+            );
+            self.game_genie.push(cheat);
+        } else {
+            let cheat = GameShark::parse(code).ok_or_else(|| bad_code(code))?;
+            info!(
+                "Added GameShark code {} writing {:02X} to {:04X}",
+                code, cheat.value, cheat.address
+            );
+            self.game_shark.push(cheat);
+        }
+        Ok(())
+    }
+
+    //Swaps in the patched byte for a rom read when one of the codes matches
+    pub fn patch_rom(&self, address: u16, value: u8) -> u8 {
+        self.game_genie
+            .iter()
+            .find(|cheat| {
+                cheat.address == address && cheat.compare.is_none_or(|compare| compare == value)
+            })
+            .map_or(value, |cheat| cheat.value)
+    }
+
+    //The ram writes to make every frame
+    pub fn ram_writes(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+        self.game_shark
+            .iter()
+            .map(|cheat| (cheat.address, cheat.value))
+    }
+}
+
+impl GameGenie {
+    // ABC-DEF-GHI, AB is the new value, FCDE is the address with F inverted and GI is the compare
+    // value rotated and scrambled, H is only there as a check and is ignored
+    fn parse(code: &str) -> Option<Self> {
+        let digits = hex_digits(&code.replace('-', ""))?;
+        if digits.len() != 6 && digits.len() != 9 {
+            return None;
+        }
+        let value = digits[0] << 4 | digits[1];
+        let address = ((digits[5] ^ 0xF) as u16) << 12
+            | (digits[2] as u16) << 8
+            | (digits[3] as u16) << 4
+            | digits[4] as u16;
+        if address > END_OF_ROM {
+            return None;
+        }
+        let compare =
+            (digits.len() == 9).then(|| (digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA);
+        Some(Self {
+            address,
+            value,
+            compare,
+        })
+    }
+}
+
+impl GameShark {
+    // ABCDEFGH, AB is the ram bank which is ignored, CD is the value and GHEF is the address
+    fn parse(code: &str) -> Option<Self> {
+        let digits = hex_digits(code)?;
+        if digits.len() != 8 {
+            return None;
+        }
+        let byte = |index: usize| digits[index] << 4 | digits[index + 1];
+        Some(Self {
+            address: (byte(6) as u16) << 8 | byte(4) as u16,
+            value: byte(2),
+        })
+    }
+}
+
+fn hex_digits(code: &str) -> Option<Vec<u8>> {
+    code.chars()
+        .map(|digit| digit.to_digit(16).map(|digit| digit as u8))
+        .collect()
+}
+
+fn bad_code(code: &str) -> EmuError {
+    EmuError::InvalidArgument(format!("{} isn't a Game Genie or GameShark code", code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_game_genie_code_with_a_compare_byte() {
+        assert_eq!(
+            GameGenie::parse("00A-17B-C49"),
+            Some(GameGenie {
+                address: 0x4A17,
+                value: 0x00,
+                compare: Some(0xC8),
+            })
+        );
+        assert_eq!(
+            GameGenie::parse("3EA-17B"),
+            Some(GameGenie {
+                address: 0x4A17,
+                value: 0x3E,
+                compare: None,
+            })
+        );
+        //F inverted to 0xA puts the address past the rom
+        assert_eq!(GameGenie::parse("00A-175"), None);
+        assert_eq!(GameGenie::parse("00A-17"), None);
+    }
+
+    #[test]
+    fn game_genie_only_patches_a_matching_byte() {
+        let mut cheats = Cheats::new();
+        cheats.add("00A-17B-C49").unwrap();
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0x00);
+        //Another bank has something else at the same address
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC9), 0xC9);
+        assert_eq!(cheats.patch_rom(0x4A18, 0xC8), 0xC8);
+
+        cheats.add("3EA-17B").unwrap();
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC9), 0x3E);
+    }
+
+    #[test]
+    fn parses_a_gameshark_code() {
+        let mut cheats = Cheats::new();
+        cheats.add("01FF34C2").unwrap();
+        assert_eq!(cheats.ram_writes().collect::<Vec<_>>(), [(0xC234, 0xFF)]);
+        assert!(cheats.add("01FF34").is_err());
+        assert!(cheats.add("not a code").is_err());
+    }
+}
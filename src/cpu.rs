@@ -1,43 +1,563 @@
-use log::{info, trace};
+use std::fmt;
 
-use crate::instructions::Instruction;
-use crate::memory::Memory;
-use crate::registers::Registers;
+use log::{info, trace, warn};
+
+use crate::cpu_state::CpuState;
+use crate::instructions::{Instruction, InstructionCategory};
+use crate::memory::{Memory, INTERRUPT_FLAG_ADDRESS, JOYPAD_INTERRUPT};
+use crate::registers::{Registers, R16, R8};
+
+//Each interrupt's handler is 8 bytes after the previous one's starting with vblank
+const INTERRUPT_VECTOR_BASE: u16 = 0x40;
+//Two wait cycles, pushing the pc and the jump
+const INTERRUPT_DISPATCH_CYCLES: u16 = 5;
+
+//How many instructions are kept around to show how execution got somewhere
+const HISTORY_LENGTH: usize = 64;
+
+//An instruction the cpu has run, the mnemonic is only looked up when it gets shown
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HistoryEntry {
+    pub pc: u16,
+    pub opcode: u8,
+    pub prefixed: bool,
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mnemonic = Instruction::from_byte(self.opcode, self.prefixed)
+            .map(|instruction| instruction.text)
+            .unwrap_or_else(|| "unknown".to_string());
+        let prefix = if self.prefixed { "CB " } else { "" };
+        write!(
+            f,
+            "{:04X}: {}{:02X} {}",
+            self.pc, prefix, self.opcode, mnemonic
+        )
+    }
+}
+
+#[derive(Clone)]
 pub struct Cpu {
     pub registers: Registers,
+    //Logs every instruction with its operands and the registers before and after
+    trace: bool,
+    //Only instructions in these categories get logged or traced
+    logged_categories: Vec<InstructionCategory>,
+    //Machine cycles and instructions since reset
+    total_cycles: u64,
+    instructions_executed: u64,
+    //Ring of the last instructions fetched, history_next is where the next one goes
+    history: [HistoryEntry; HISTORY_LENGTH],
+    history_next: usize,
+    history_len: usize,
+    //Set once an illegal opcode has been run, the real cpu hangs until it is switched off so
+    //this one just lets time pass
+    locked: bool,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cpu {
     pub fn new() -> Self {
         Cpu {
             registers: Registers::default(),
+            trace: false,
+            logged_categories: InstructionCategory::ALL.to_vec(),
+            total_cycles: 0,
+            instructions_executed: 0,
+            history: [HistoryEntry::default(); HISTORY_LENGTH],
+            history_next: 0,
+            history_len: 0,
+            locked: false,
+        }
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        self.instructions_executed
+    }
+
+    //Puts every register into the given state, memory is left for the caller to set up
+    pub fn load_state(&mut self, state: &CpuState) {
+        self.locked = false;
+        let registers = &mut self.registers;
+        registers.write_r16(R16::AF, (state.a as u16) << 8 | state.f as u16);
+        registers.write_r8(R8::B, state.b);
+        registers.write_r8(R8::C, state.c);
+        registers.write_r8(R8::D, state.d);
+        registers.write_r8(R8::E, state.e);
+        registers.write_r8(R8::H, state.h);
+        registers.write_r8(R8::L, state.l);
+        registers.write_r16(R16::SP, state.sp);
+        registers.set_pc(state.pc);
+        registers.set_ime(state.ime);
+        registers.set_ime_pending(false);
+        registers.set_halted(false);
+        registers.set_halt_bug(false);
+        registers.set_stopped(false);
+    }
+
+    pub fn capture_state(&self) -> CpuState {
+        let registers = &self.registers;
+        CpuState {
+            a: registers.read_r8(R8::A),
+            f: registers.get_flags(),
+            b: registers.read_r8(R8::B),
+            c: registers.read_r8(R8::C),
+            d: registers.read_r8(R8::D),
+            e: registers.read_r8(R8::E),
+            h: registers.read_r8(R8::H),
+            l: registers.read_r8(R8::L),
+            sp: registers.read_r16(R16::SP),
+            pc: registers.get_pc(),
+            ime: registers.ime(),
         }
     }
 
-    pub fn step(&mut self, memory: &mut Memory) {
+    //The last instructions fetched, oldest first
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        let start = (self.history_next + HISTORY_LENGTH - self.history_len) % HISTORY_LENGTH;
+        (0..self.history_len)
+            .map(|i| self.history[(start + i) % HISTORY_LENGTH])
+            .collect()
+    }
+
+    //One instruction per line for putting in crash reports
+    pub fn history_text(&self) -> String {
+        self.history()
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn record_history(&mut self, entry: HistoryEntry) {
+        self.history[self.history_next] = entry;
+        self.history_next = (self.history_next + 1) % HISTORY_LENGTH;
+        self.history_len = (self.history_len + 1).min(HISTORY_LENGTH);
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    pub fn set_logged_categories(&mut self, categories: &[InstructionCategory]) {
+        self.logged_categories = categories.to_vec();
+    }
+
+    //Runs one instruction and returns how many machine cycles it took
+    pub fn step(&mut self, memory: &mut Memory) -> u16 {
+        //Not even an interrupt gets it going again
+        if self.locked {
+            self.total_cycles += 1;
+            return 1;
+        }
+        if self.registers.stopped() {
+            //Once stopped the DMG only wakes back up on a joypad interrupt
+            if memory.read_u8(INTERRUPT_FLAG_ADDRESS) & JOYPAD_INTERRUPT == 0 {
+                self.total_cycles += 1;
+                return 1;
+            }
+            self.registers.set_stopped(false);
+        }
+        if self.registers.halted() {
+            if memory.pending_interrupts() == 0 {
+                self.total_cycles += 1;
+                return 1;
+            }
+            self.registers.set_halted(false);
+        }
+
+        if let Some(cycles) = self.service_interrupt(memory) {
+            self.total_cycles += cycles as u64;
+            return cycles;
+        }
+
         let mut opcode = memory.read_u8(self.registers.get_pc());
         let prefixed = opcode == 0xCB;
-        if prefixed {
-            opcode = memory.read_u8(self.registers.get_pc() + 1);
+        let halt_bug = self.registers.halt_bug();
+        //The prefixed table's cycle counts already include fetching the 0xCB so nothing is added for it
+        if prefixed && !halt_bug {
+            opcode = memory.read_u8(self.registers.get_pc().wrapping_add(1));
         }
+        self.record_history(HistoryEntry {
+            pc: self.registers.get_pc(),
+            opcode,
+            prefixed,
+        });
 
-        if let Some(instruction) = Instruction::from_byte(opcode, prefixed) {
+        let instruction = match Instruction::from_byte(opcode, prefixed) {
+            Some(instruction) => instruction,
+            None => {
+                let description = format!("0x{}{:x}", if prefixed { "cb" } else { "" }, opcode);
+                warn!(
+                    "Locked up on illegal opcode {} at pc {:X}, recently executed:\n{}",
+                    description,
+                    self.registers.get_pc(),
+                    self.history_text()
+                );
+                self.locked = true;
+                self.total_cycles += 1;
+                return 1;
+            }
+        };
+        let logged = self.logged_categories.contains(&instruction.category);
+        if logged {
             info!(
                 "Excuting pc {:x} instruction {}",
                 self.registers.get_pc(),
                 instruction
             );
             trace!("{:X?}", self.registers);
-            //Set the number of cycles the instruction will take note that some instructions will edit this later
-            memory.cpu_cycles = instruction.cycles;
-            (instruction.execute)(&mut self.registers, memory);
+        }
+        //Only build the trace when it is on since formatting every instruction is slow
+        let trace_before = if self.trace && logged {
+            let pc = self.registers.get_pc();
+            let text = instruction.resolved_text(memory, pc);
+            Some((pc, text, self.registers.summary()))
         } else {
-            let description = format!("0x{}{:x}", if prefixed { "cb" } else { "" }, opcode);
-            panic!(
-                "Couldn't find insturction for: {} at pc {:X}",
-                description,
-                self.registers.get_pc()
-            );
+            None
+        };
+        //On hardware the opcode after a buggy halt is fetched without moving the pc. Stepping the pc
+        //back onto the opcode before the handler runs gets the same effect, since the handler's own
+        //inc_pc and operand reads then start from the opcode byte again. A one byte instruction runs
+        //twice and anything longer takes its opcode as its first operand
+        if halt_bug {
+            self.registers.set_halt_bug(false);
+            self.registers
+                .set_pc(self.registers.get_pc().wrapping_sub(1));
+        }
+        //An ei from before this instruction takes effect once it is done
+        let enable_interrupts = self.registers.ime_pending();
+        memory.set_current_pc(self.registers.get_pc());
+        let cycles = (instruction.execute)(&mut self.registers, memory);
+        if enable_interrupts && self.registers.ime_pending() {
+            self.registers.set_ime(true);
+            self.registers.set_ime_pending(false);
+        }
+        self.total_cycles += cycles as u64;
+        self.instructions_executed += 1;
+        if let Some((pc, text, before)) = trace_before {
+            info!("{}", Self::trace_line(pc, &text, &before, &self.registers));
+        }
+        cycles
+    }
+
+    //Jumps to the handler of the highest priority interrupt that is both requested and enabled
+    fn service_interrupt(&mut self, memory: &mut Memory) -> Option<u16> {
+        let pending = memory.pending_interrupts();
+        if !self.registers.ime() || pending == 0 {
+            return None;
+        }
+        //The lowest bit wins
+        let interrupt = pending & pending.wrapping_neg();
+        self.registers.set_ime(false);
+        memory.clear_interrupt(interrupt);
+        //After ei; halt the handler returns to the halt itself which then sleeps as normal
+        let pc = if self.registers.halt_bug() {
+            self.registers.set_halt_bug(false);
+            self.registers.get_pc().wrapping_sub(1)
+        } else {
+            self.registers.get_pc()
         };
+        self.registers.stack_push16(pc, memory);
+        let vector = INTERRUPT_VECTOR_BASE + 8 * interrupt.trailing_zeros() as u16;
+        info!("Servicing interrupt {:02x} from pc {:x}", interrupt, pc);
+        self.registers.set_pc(vector);
+        Some(INTERRUPT_DISPATCH_CYCLES)
+    }
+
+    fn trace_line(pc: u16, text: &str, before: &str, after: &Registers) -> String {
+        format!("{:04X}: {:<20} {} -> {}", pc, text, before, after.summary())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::gameboy::Model;
+    use crate::memory::{
+        RomChunk, INTERRUPT_ENABLE_ADDRESS, SERIAL_INTERRUPT, TIMER_INTERRUPT, VBLANK_INTERRUPT,
+    };
+
+    //Code goes in work ram where it can be written like any other memory
+    pub(crate) const CODE: u16 = 0xC000;
+    //The top of high ram where the stack starts after boot
+    pub(crate) const STACK: u16 = 0xFFFE;
+
+    //Every interrupt handler in the rom is just a reti
+    pub(crate) fn setup(code: &[u8], state: CpuState) -> (Cpu, Memory) {
+        let mut rom = vec![0; 0x8000];
+        for interrupt in 0..5 {
+            rom[(INTERRUPT_VECTOR_BASE + 8 * interrupt) as usize] = 0xD9;
+        }
+        let rom = RomChunk::from_bytes(rom).unwrap();
+        let mut memory = Memory::new(None, rom, Model::Dmg).unwrap();
+        for (i, byte) in code.iter().enumerate() {
+            memory.write_u8(CODE + i as u16, *byte);
+        }
+        memory.write_u8(INTERRUPT_FLAG_ADDRESS, 0);
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, 0);
+        let mut cpu = Cpu::new();
+        cpu.load_state(&state.pc(CODE));
+        (cpu, memory)
+    }
+
+    //Halt with interrupts off but one already pending doesn't halt and trips the bug instead
+    fn setup_halt_bug(code: &[u8], state: CpuState) -> (Cpu, Memory) {
+        let (mut cpu, mut memory) = setup(code, state);
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, VBLANK_INTERRUPT);
+        memory.write_u8(INTERRUPT_FLAG_ADDRESS, VBLANK_INTERRUPT);
+        cpu.step(&mut memory);
+        assert!(!cpu.registers.halted());
+        (cpu, memory)
+    }
+
+    #[test]
+    fn halt_bug_runs_a_one_byte_instruction_twice() {
+        //halt, inc a
+        let (mut cpu, mut memory) =
+            setup_halt_bug(&[0x76, 0x3C], CpuState::new().sp(STACK).a(0x10));
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+        let state = cpu.capture_state();
+        assert_eq!(state.a, 0x12);
+        assert_eq!(state.pc, CODE + 2);
+    }
+
+    #[test]
+    fn halt_bug_reads_the_opcode_as_its_operand() {
+        //halt, ld a,0x14 which becomes ld a,0x3E followed by inc d
+        let (mut cpu, mut memory) =
+            setup_halt_bug(&[0x76, 0x3E, 0x14], CpuState::new().sp(STACK).d(0x20));
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().a, 0x3E);
+        assert_eq!(cpu.capture_state().pc, CODE + 2);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().d, 0x21);
+        assert_eq!(cpu.capture_state().pc, CODE + 3);
+    }
+
+    #[test]
+    fn halt_bug_jumps_use_the_shifted_operands() {
+        //halt, jp 0x1234 which becomes jp 0x34C3
+        let (mut cpu, mut memory) =
+            setup_halt_bug(&[0x76, 0xC3, 0x34, 0x12], CpuState::new().sp(STACK));
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().pc, 0x34C3);
+
+        //halt, call 0x1234 which becomes call 0x34CD returning to just past the 0x34
+        let (mut cpu, mut memory) =
+            setup_halt_bug(&[0x76, 0xCD, 0x34, 0x12], CpuState::new().sp(STACK));
+        cpu.step(&mut memory);
+        let state = cpu.capture_state();
+        assert_eq!(state.pc, 0x34CD);
+        assert_eq!(memory.read_u16(state.sp), CODE + 3);
+    }
+
+    #[test]
+    fn interrupts_are_dispatched_in_priority_order() {
+        let (mut cpu, mut memory) = setup(&[0x00; 4], CpuState::new().sp(STACK).ime(true));
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, 0x1F);
+        let requested = SERIAL_INTERRUPT | TIMER_INTERRUPT | VBLANK_INTERRUPT;
+        memory.write_u8(INTERRUPT_FLAG_ADDRESS, requested);
+
+        //Vblank goes first and only its request gets cleared
+        assert_eq!(cpu.step(&mut memory), INTERRUPT_DISPATCH_CYCLES);
+        assert_eq!(cpu.capture_state().pc, 0x40);
+        assert!(!cpu.capture_state().ime);
+        assert_eq!(
+            memory.pending_interrupts(),
+            SERIAL_INTERRUPT | TIMER_INTERRUPT
+        );
+        assert_eq!(memory.read_u16(cpu.capture_state().sp), CODE);
+
+        //With IME off the reti runs instead of the handler being interrupted, then each reti lets
+        //the next one in
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().pc, CODE);
+        assert!(cpu.capture_state().ime);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().pc, 0x50);
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().pc, 0x58);
+        cpu.step(&mut memory);
+        assert_eq!(memory.pending_interrupts(), 0);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().pc, CODE + 1);
+    }
+
+    #[test]
+    fn only_interrupts_enabled_in_ie_are_dispatched() {
+        let (mut cpu, mut memory) = setup(&[0x00; 4], CpuState::new().sp(STACK).ime(true));
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, 0x1F);
+        assert_eq!(memory.read_u8(INTERRUPT_ENABLE_ADDRESS), 0x1F);
+
+        //The top 3 bits of IE don't enable anything
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, 0xE0 | VBLANK_INTERRUPT);
+        memory.write_u8(INTERRUPT_FLAG_ADDRESS, TIMER_INTERRUPT);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().pc, CODE + 1);
+
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, TIMER_INTERRUPT);
+        assert_eq!(cpu.step(&mut memory), INTERRUPT_DISPATCH_CYCLES);
+        assert_eq!(cpu.capture_state().pc, 0x50);
+    }
+
+    #[test]
+    fn ei_waits_an_instruction_before_dispatching() {
+        //ei, nop, nop
+        let (mut cpu, mut memory) = setup(&[0xFB, 0x00, 0x00], CpuState::new().sp(STACK));
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, VBLANK_INTERRUPT);
+        memory.write_u8(INTERRUPT_FLAG_ADDRESS, VBLANK_INTERRUPT);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().pc, CODE + 1);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().pc, CODE + 2);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().pc, 0x40);
+        assert_eq!(memory.read_u16(cpu.capture_state().sp), CODE + 2);
+    }
+
+    #[test]
+    fn call_nz_taken() {
+        let (mut cpu, mut memory) = setup(
+            &[0xC4, 0x34, 0x12],
+            CpuState::new().sp(STACK).flags(false, false, false, false),
+        );
+        assert_eq!(cpu.step(&mut memory), 6);
+        let state = cpu.capture_state();
+        assert_eq!(state.pc, 0x1234);
+        assert_eq!(state.sp, STACK - 2);
+        assert_eq!(memory.read_u16(state.sp), CODE + 3);
+    }
+
+    #[test]
+    fn call_nz_not_taken() {
+        let (mut cpu, mut memory) = setup(
+            &[0xC4, 0x34, 0x12],
+            CpuState::new().sp(STACK).flags(true, false, false, false),
+        );
+        assert_eq!(cpu.step(&mut memory), 3);
+        let state = cpu.capture_state();
+        assert_eq!(state.pc, CODE + 3);
+        assert_eq!(state.sp, STACK);
+    }
+
+    #[test]
+    fn add_a_b() {
+        let state = CpuState::new().sp(STACK).a(0x3A).b(0xC6);
+        let (mut cpu, mut memory) = setup(&[0x80], state);
+        assert_eq!(cpu.step(&mut memory), 1);
+        let expected = state.a(0x00).flags(true, false, true, true).pc(CODE + 1);
+        assert_eq!(cpu.capture_state(), expected);
+    }
+
+    #[test]
+    fn jr_nz() {
+        //Taken forwards, taken backwards and not taken
+        for (offset, zero, pc, cycles) in [
+            (0x05, false, CODE + 7, 3),
+            (0xFE, false, CODE, 3),
+            (0x05, true, CODE + 2, 2),
+        ] {
+            let state = CpuState::new().sp(STACK).flags(zero, false, false, false);
+            let (mut cpu, mut memory) = setup(&[0x20, offset], state);
+            assert_eq!(cpu.step(&mut memory), cycles);
+            assert_eq!(cpu.capture_state(), state.pc(pc));
+        }
+    }
+
+    #[test]
+    fn three_nops_count_three_instructions_and_cycles() {
+        let (mut cpu, mut memory) = setup(&[0x00, 0x00, 0x00], CpuState::new());
+        let (cycles, instructions) = (cpu.total_cycles(), cpu.instructions_executed());
+        for _ in 0..3 {
+            assert_eq!(cpu.step(&mut memory), 1);
+        }
+        //Machine cycles, so 12 of the 4MHz clocks
+        assert_eq!(cpu.total_cycles() - cycles, 3);
+        assert_eq!(cpu.instructions_executed() - instructions, 3);
+    }
+
+    #[test]
+    fn history_lists_instructions_oldest_first() {
+        //ld a,0x12, inc a, swap a, jr +0, nop
+        let code = [0x3E, 0x12, 0x3C, 0xCB, 0x37, 0x18, 0x00, 0x00];
+        let (mut cpu, mut memory) = setup(&code, CpuState::new());
+        for _ in 0..5 {
+            cpu.step(&mut memory);
+        }
+        let entry = |pc: u16, opcode: u8, prefixed: bool| HistoryEntry {
+            pc,
+            opcode,
+            prefixed,
+        };
+        assert_eq!(
+            cpu.history(),
+            [
+                entry(CODE, 0x3E, false),
+                entry(CODE + 2, 0x3C, false),
+                entry(CODE + 3, 0x37, true),
+                entry(CODE + 5, 0x18, false),
+                entry(CODE + 7, 0x00, false),
+            ]
+        );
+        assert_eq!(
+            cpu.history_text().lines().nth(2),
+            Some("C003: CB 37 swap a")
+        );
+    }
+
+    #[test]
+    fn history_keeps_only_the_latest_instructions() {
+        let (mut cpu, mut memory) = setup(&[0x00; HISTORY_LENGTH + 6], CpuState::new());
+        for _ in 0..HISTORY_LENGTH + 6 {
+            cpu.step(&mut memory);
+        }
+        let history = cpu.history();
+        assert_eq!(history.len(), HISTORY_LENGTH);
+        assert_eq!(history[0].pc, CODE + 6);
+        assert_eq!(
+            history[HISTORY_LENGTH - 1].pc,
+            CODE + HISTORY_LENGTH as u16 + 5
+        );
+    }
+
+    #[test]
+    fn illegal_opcode_locks_the_cpu() {
+        let (mut cpu, mut memory) = setup(&[0xD3, 0x00], CpuState::new().sp(STACK).ime(true));
+        assert_eq!(cpu.step(&mut memory), 1);
+        assert!(cpu.locked());
+        //Stays put even with an interrupt waiting
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, VBLANK_INTERRUPT);
+        memory.write_u8(INTERRUPT_FLAG_ADDRESS, VBLANK_INTERRUPT);
+        assert_eq!(cpu.step(&mut memory), 1);
+        assert_eq!(cpu.capture_state().pc, CODE);
+    }
+
+    #[test]
+    fn prefixed_opcode_at_ffff_comes_from_0000() {
+        //IE holds the 0xCB and the blank rom makes it rlc b
+        let (mut cpu, mut memory) = setup(&[], CpuState::new().b(0x80));
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, 0xCB);
+        cpu.load_state(&cpu.capture_state().pc(0xFFFF));
+        cpu.step(&mut memory);
+        let state = cpu.capture_state();
+        assert_eq!(state.b, 0x01);
+        assert_eq!(state.pc, 0x0001);
     }
 }
@@ -0,0 +1,122 @@
+use crate::registers::{CARRY_FLAG, HALF_CARRY_FLAG, SUBTRACT_FLAG, ZERO_FLAG};
+
+//Every register in one plain value so a test can set up the cpu, run something and compare the
+//whole thing against what it expects in one go
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuState {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+}
+
+impl Default for CpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuState {
+    pub const fn new() -> Self {
+        Self {
+            a: 0,
+            f: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            sp: 0,
+            pc: 0,
+            ime: false,
+        }
+    }
+
+    pub const fn a(mut self, a: u8) -> Self {
+        self.a = a;
+        self
+    }
+
+    //The lower nibble doesn't exist so it is dropped here the same way the cpu would
+    pub const fn f(mut self, f: u8) -> Self {
+        self.f = f & 0xF0;
+        self
+    }
+
+    pub const fn b(mut self, b: u8) -> Self {
+        self.b = b;
+        self
+    }
+
+    pub const fn c(mut self, c: u8) -> Self {
+        self.c = c;
+        self
+    }
+
+    pub const fn d(mut self, d: u8) -> Self {
+        self.d = d;
+        self
+    }
+
+    pub const fn e(mut self, e: u8) -> Self {
+        self.e = e;
+        self
+    }
+
+    pub const fn h(mut self, h: u8) -> Self {
+        self.h = h;
+        self
+    }
+
+    pub const fn l(mut self, l: u8) -> Self {
+        self.l = l;
+        self
+    }
+
+    pub const fn sp(mut self, sp: u16) -> Self {
+        self.sp = sp;
+        self
+    }
+
+    pub const fn pc(mut self, pc: u16) -> Self {
+        self.pc = pc;
+        self
+    }
+
+    pub const fn ime(mut self, ime: bool) -> Self {
+        self.ime = ime;
+        self
+    }
+
+    //Sets F from the individual flags which reads better in tests than a hex byte
+    pub const fn flags(
+        mut self,
+        zero: bool,
+        subtract: bool,
+        half_carry: bool,
+        carry: bool,
+    ) -> Self {
+        self.f = 0;
+        if zero {
+            self.f |= ZERO_FLAG;
+        }
+        if subtract {
+            self.f |= SUBTRACT_FLAG;
+        }
+        if half_carry {
+            self.f |= HALF_CARRY_FLAG;
+        }
+        if carry {
+            self.f |= CARRY_FLAG;
+        }
+        self
+    }
+}
@@ -0,0 +1,139 @@
+use crate::apu::Apu;
+use crate::joypad::Button;
+
+//How many times bigger than the gameboy's screen a window starts out
+pub const DEFAULT_SCALE: u32 = 8;
+
+//Whatever shows the screen and reads the keyboard, Emu::run only talks to the frontend through
+//this so it can be driven by sdl or by something headless
+pub trait Display {
+    //Called with every frame that should be shown
+    fn present(&mut self, framebuffer: &[u8]);
+
+    //Like present when everything but the marked lines is already showing from the last frame,
+    //backends where uploading the frame is costly can send just those
+    fn present_dirty(&mut self, framebuffer: &[u8], _dirty_lines: &[bool]) {
+        self.present(framebuffer);
+    }
+
+    //Everything that has happened since the last poll
+    fn poll_input(&mut self) -> InputState;
+
+    //Called once a frame so backends with a sound device can take the new samples
+    fn play_audio(&mut self, _apu: &mut Apu) {}
+}
+
+//Backends keep one of these around between polls so held buttons carry over from frame to frame,
+//only the one off events get cleared each time
+#[derive(Default, Debug, Clone)]
+pub struct InputState {
+    pub quit: bool,
+    pub toggle_pause: bool,
+    //Run one more frame while paused
+    pub step_frame: bool,
+    //Run a single instruction while paused
+    pub step_instruction: bool,
+    //Whether fast forward is currently held down
+    pub turbo: bool,
+    //Whether rewind is currently held down
+    pub rewind: bool,
+    pub screenshot: bool,
+    pub toggle_frame_blend: bool,
+    pub toggle_perf_overlay: bool,
+    //Start the game over, the only way out of a lock up when rewinding is off
+    pub reset: bool,
+    held: Vec<Button>,
+    //Everything pressed since the last clear, so a tap that starts and ends inside one poll still
+    //reaches the joypad
+    pressed: Vec<Button>,
+}
+
+impl InputState {
+    pub fn key_down(&mut self, button: Button) {
+        if !self.is_held(button) {
+            self.held.push(button);
+        }
+        if !self.was_pressed(button) {
+            self.pressed.push(button);
+        }
+    }
+
+    pub fn key_up(&mut self, button: Button) {
+        self.held.retain(|held| *held != button);
+    }
+
+    pub fn is_held(&self, button: Button) -> bool {
+        self.held.contains(&button)
+    }
+
+    pub fn was_pressed(&self, button: Button) -> bool {
+        self.pressed.contains(&button)
+    }
+
+    //Forgets everything except what is still being held down
+    pub fn clear_events(&mut self) {
+        self.quit = false;
+        self.toggle_pause = false;
+        self.step_frame = false;
+        self.step_instruction = false;
+        self.screenshot = false;
+        self.toggle_frame_blend = false;
+        self.toggle_perf_overlay = false;
+        self.reset = false;
+        self.pressed.clear();
+    }
+}
+
+//Turns an analog stick axis into whichever of its two directions is pushed past the threshold, so
+//the stick can stand in for the d-pad, the threshold is clamped to at least 1
+pub fn axis_button(
+    value: i16,
+    threshold: i16,
+    negative: Button,
+    positive: Button,
+) -> Option<Button> {
+    //Widen first since the axis goes one further negative than positive
+    let value = value as i32;
+    //A threshold of zero or less would count a centered stick as pushed
+    let threshold = (threshold as i32).max(1);
+    if value <= -threshold {
+        Some(negative)
+    } else if value >= threshold {
+        Some(positive)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn horizontal(value: i16, threshold: i16) -> Option<Button> {
+        axis_button(value, threshold, Button::Left, Button::Right)
+    }
+
+    #[test]
+    fn stick_past_the_threshold_picks_a_direction() {
+        assert_eq!(horizontal(-20_000, 16_000), Some(Button::Left));
+        assert_eq!(horizontal(20_000, 16_000), Some(Button::Right));
+        assert_eq!(horizontal(-16_000, 16_000), Some(Button::Left));
+        assert_eq!(horizontal(16_000, 16_000), Some(Button::Right));
+        assert_eq!(horizontal(i16::MIN, i16::MAX), Some(Button::Left));
+    }
+
+    #[test]
+    fn stick_inside_the_threshold_picks_nothing() {
+        assert_eq!(horizontal(0, 16_000), None);
+        assert_eq!(horizontal(15_999, 16_000), None);
+        assert_eq!(horizontal(-15_999, 16_000), None);
+    }
+
+    #[test]
+    fn centered_stick_is_never_pushed() {
+        assert_eq!(horizontal(0, 0), None);
+        assert_eq!(horizontal(0, -5), None);
+        assert_eq!(horizontal(1, 0), Some(Button::Right));
+        assert_eq!(horizontal(-1, i16::MIN), Some(Button::Left));
+    }
+}
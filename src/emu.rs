@@ -0,0 +1,367 @@
+use std::{
+    fs,
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::{
+    display::{Display, InputState},
+    gameboy::{GameBoy, SaveState},
+    overlay::PerfOverlay,
+    png,
+    ppu::{FRAMEBUFFER_SIZE, GAMEBOY_SCREEN_HEIGHT, GAMEBOY_SCREEN_WIDTH},
+    rewind::Rewind,
+};
+
+//A frame is 70224 clocks at 4194304Hz which works out to about 59.7 frames a second
+const FRAME_DURATION: Duration = Duration::from_nanos(16_742_706);
+//While fast forwarding only every few frames make it to the screen
+const TURBO_FRAMES_PER_RENDER: u32 = 4;
+
+//Runs the gameboy at the right speed and handles pausing, fast forward and screenshots for
+//whichever display it is given
+pub struct Emu {
+    //Set when the window is closed so run can return and the game gets saved
+    quit: bool,
+    paused: bool,
+    //Set while paused to run until the next frame is drawn
+    step_frame: bool,
+    //Set while paused to run a single instruction
+    step_instruction: bool,
+    //Held down to run as fast as possible
+    turbo: bool,
+    //Runs as fast as possible until the boot rom hands over so the logo doesn't hold things up
+    turbo_boot: bool,
+    //Held down to step back through the rewind buffer instead of running
+    rewinding: bool,
+    //None when rewinding is turned off
+    rewind: Option<Rewind>,
+    //How the machine was when run started, resetting goes back to it
+    power_on: Option<SaveState>,
+    screenshot_dir: PathBuf,
+    //Mixes each frame with the one before to fake the DMG lcd's slow pixels, which some games
+    //rely on to make flickering sprites look see through
+    frame_blend: bool,
+    previous_frame: Vec<u8>,
+    blended_frame: Vec<u8>,
+    frames_since_render: u32,
+    //None while the fps and speed aren't being shown. They are drawn over a copy of the frame so
+    //screenshots and rewinding never pick them up
+    perf_overlay: Option<PerfOverlay>,
+    overlay_frame: Vec<u8>,
+    //Lines the ppu has changed since the frame was last shown
+    dirty_lines: [bool; GAMEBOY_SCREEN_HEIGHT as usize],
+    //Set when the display was last given something other than the framebuffer, like a blended
+    //frame, so only sending the dirty lines would leave the rest out of date
+    full_present: bool,
+    next_frame: Instant,
+    //Whether the player has been told the cpu locked up, cleared again if rewinding unlocks it
+    lock_reported: bool,
+}
+
+impl Default for Emu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Emu {
+    pub fn new() -> Self {
+        Self {
+            quit: false,
+            paused: false,
+            step_frame: false,
+            step_instruction: false,
+            turbo: false,
+            turbo_boot: false,
+            rewinding: false,
+            rewind: None,
+            power_on: None,
+            screenshot_dir: PathBuf::from("."),
+            frame_blend: false,
+            previous_frame: vec![0; FRAMEBUFFER_SIZE],
+            blended_frame: vec![0; FRAMEBUFFER_SIZE],
+            frames_since_render: 0,
+            perf_overlay: None,
+            overlay_frame: vec![0; FRAMEBUFFER_SIZE],
+            dirty_lines: [false; GAMEBOY_SCREEN_HEIGHT as usize],
+            full_present: true,
+            next_frame: Instant::now(),
+            lock_reported: false,
+        }
+    }
+
+    pub fn set_screenshot_dir(&mut self, screenshot_dir: PathBuf) {
+        self.screenshot_dir = screenshot_dir;
+    }
+
+    //Starting paused gives a chance to step through the very first instructions
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn set_turbo_boot(&mut self, turbo_boot: bool) {
+        self.turbo_boot = turbo_boot;
+    }
+
+    pub fn set_frame_blend(&mut self, frame_blend: bool) {
+        self.frame_blend = frame_blend;
+    }
+
+    //Keeps depth snapshots taken every interval frames, a depth of 0 turns rewinding off
+    pub fn set_rewind(&mut self, depth: usize, interval: u32) {
+        self.rewind = (depth > 0).then(|| Rewind::new(depth, interval));
+    }
+
+    fn handle_input(&mut self, input: InputState, gameboy: &mut GameBoy, pixel_data: &[u8]) {
+        self.quit |= input.quit;
+        if input.toggle_pause {
+            self.paused = !self.paused;
+        }
+        if input.step_frame && self.paused {
+            self.step_frame = true;
+        }
+        if input.step_instruction && self.paused {
+            self.step_instruction = true;
+        }
+        self.turbo = input.turbo;
+        self.rewinding = input.rewind;
+        if input.toggle_frame_blend {
+            self.frame_blend = !self.frame_blend;
+            //Start from what is on screen now rather than whatever was there when it was last on
+            self.previous_frame.copy_from_slice(pixel_data);
+            info!(
+                "Frame blending {}",
+                if self.frame_blend { "on" } else { "off" }
+            );
+        }
+        if input.toggle_perf_overlay {
+            self.perf_overlay = match self.perf_overlay {
+                Some(_) => None,
+                None => Some(PerfOverlay::new()),
+            };
+        }
+        if input.screenshot {
+            self.save_screenshot(pixel_data);
+        }
+        if input.reset {
+            if let Some(power_on) = &self.power_on {
+                gameboy.reset(power_on);
+                info!("Reset");
+            }
+        }
+        gameboy.inject_input(&input);
+    }
+
+    //Named after the time it was taken so screenshots never overwrite each other
+    fn save_screenshot(&self, pixel_data: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis())
+            .unwrap_or(0);
+        let path = self
+            .screenshot_dir
+            .join(format!("rust_boi-{}.png", timestamp));
+        let image = png::encode_rgb(GAMEBOY_SCREEN_WIDTH, GAMEBOY_SCREEN_HEIGHT, pixel_data);
+        match fs::create_dir_all(&self.screenshot_dir).and_then(|_| fs::write(&path, image)) {
+            Ok(()) => info!("Saved screenshot to {}", path.display()),
+            Err(error) => warn!("Couldn't save screenshot to {}: {}", path.display(), error),
+        }
+    }
+
+    //Keeps the last frame around even when it isn't shown so blending always mixes neighbours
+    fn blend_frame(&mut self, pixel_data: &[u8]) {
+        blend_frames(&self.previous_frame, pixel_data, &mut self.blended_frame);
+        self.previous_frame.copy_from_slice(pixel_data);
+    }
+
+    fn present_frame(&mut self, display: &mut dyn Display, pixel_data: &[u8]) {
+        if let Some(overlay) = &mut self.perf_overlay {
+            overlay.frame_shown();
+            let frame = if self.frame_blend {
+                &self.blended_frame
+            } else {
+                pixel_data
+            };
+            self.overlay_frame.copy_from_slice(frame);
+            overlay.draw(&mut self.overlay_frame);
+            display.present(&self.overlay_frame);
+            self.full_present = true;
+        } else if self.frame_blend {
+            display.present(&self.blended_frame);
+            self.full_present = true;
+        } else if self.full_present {
+            display.present(pixel_data);
+            self.full_present = false;
+        } else {
+            display.present_dirty(pixel_data, &self.dirty_lines);
+        }
+        self.dirty_lines = [false; GAMEBOY_SCREEN_HEIGHT as usize];
+    }
+
+    fn frames_per_render(turbo: bool) -> u32 {
+        if turbo {
+            TURBO_FRAMES_PER_RENDER
+        } else {
+            1
+        }
+    }
+
+    //Sleep until it is time for the next frame so games run at the real speed
+    fn limit_frame_rate(&mut self) {
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        }
+        //If we have fallen behind don't try to catch up by rushing frames
+        self.next_frame = self.next_frame.max(now) + FRAME_DURATION;
+    }
+
+    //Shows whatever has been drawn so far since the line the ppu is on may have just changed
+    fn run_instruction(
+        &mut self,
+        display: &mut dyn Display,
+        gameboy: &mut GameBoy,
+        pixel_data: &mut [u8],
+    ) {
+        let pc = gameboy.cpu.registers.get_pc();
+        let (clocks, _) = gameboy.step_instruction(pixel_data);
+        let registers = &gameboy.cpu.registers;
+        info!(
+            "Stepped {:04X} in {} clocks to pc {:04X} {} flags {}",
+            pc,
+            clocks,
+            registers.get_pc(),
+            registers.summary(),
+            registers.flags_summary()
+        );
+        display.present(pixel_data);
+    }
+
+    //Runs until the display asks to quit
+    pub fn run(&mut self, display: &mut dyn Display, gameboy: &mut GameBoy) {
+        let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+        self.power_on = Some(gameboy.save_state());
+        while !self.quit {
+            let was_paused = self.paused;
+            self.handle_input(display.poll_input(), gameboy, &pixel_data);
+            if self.quit {
+                break;
+            }
+            if self.paused && !was_paused {
+                info!(
+                    "Paused on frame {} after {} instructions and {} cycles",
+                    gameboy.frame_count(),
+                    gameboy.instruction_count(),
+                    gameboy.cpu.total_cycles()
+                );
+            }
+            if self.paused && !self.step_frame {
+                if self.step_instruction {
+                    self.step_instruction = false;
+                    self.run_instruction(display, gameboy, &mut pixel_data);
+                }
+                //Nothing to run so just wait around for the next event
+                self.limit_frame_rate();
+                continue;
+            }
+            if self.rewinding {
+                if let Some(rewind) = &mut self.rewind {
+                    //Each frame goes back one snapshot, stopping at the oldest one
+                    if rewind.rewind(gameboy, &mut pixel_data) {
+                        display.present(&pixel_data);
+                    }
+                }
+                self.limit_frame_rate();
+                continue;
+            }
+            let turbo = self.turbo || (self.turbo_boot && gameboy.memory.booting());
+            gameboy.step_frame(&mut pixel_data);
+            if let Some(overlay) = &mut self.perf_overlay {
+                overlay.frame_run();
+            }
+            //Frames that don't get shown still count towards what has changed on screen
+            for (dirty, changed) in self
+                .dirty_lines
+                .iter_mut()
+                .zip(gameboy.ppu.take_dirty_lines())
+            {
+                *dirty |= changed;
+            }
+            if gameboy.cpu.locked() != self.lock_reported {
+                self.lock_reported = gameboy.cpu.locked();
+                if self.lock_reported && self.rewind.is_some() {
+                    warn!("The game has locked up, hold backspace to rewind, F5 to reset or escape to quit");
+                } else if self.lock_reported {
+                    warn!("The game has locked up, press F5 to reset or escape to quit");
+                }
+            }
+            if let Some(rewind) = &mut self.rewind {
+                rewind.record(gameboy, &pixel_data);
+            }
+            display.play_audio(&mut gameboy.apu);
+            if self.frame_blend {
+                self.blend_frame(&pixel_data);
+            }
+            self.frames_since_render += 1;
+            if self.step_frame || self.frames_since_render >= Self::frames_per_render(turbo) {
+                self.frames_since_render = 0;
+                self.present_frame(display, &pixel_data);
+            }
+            if !turbo {
+                self.limit_frame_rate();
+            }
+
+            if self.step_frame {
+                self.step_frame = false;
+                info!(
+                    "Stepped to frame {} after {} instructions and {} cycles",
+                    gameboy.frame_count(),
+                    gameboy.instruction_count(),
+                    gameboy.cpu.total_cycles()
+                );
+            }
+        }
+    }
+}
+
+//Averages every color channel of two frames
+fn blend_frames(previous: &[u8], current: &[u8], out: &mut [u8]) {
+    for ((out, previous), current) in out.iter_mut().zip(previous).zip(current) {
+        *out = ((*previous as u16 + *current as u16) / 2) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::shade_to_rgb;
+
+    #[test]
+    fn turbo_runs_several_frames_for_each_one_shown() {
+        assert_eq!(Emu::frames_per_render(false), 1);
+        assert_eq!(Emu::frames_per_render(true), TURBO_FRAMES_PER_RENDER);
+        assert!(Emu::frames_per_render(true) > Emu::frames_per_render(false));
+    }
+
+    #[test]
+    fn blending_shade_0_into_shade_3_lands_in_between() {
+        let frame = |shade: u8| shade_to_rgb(shade).repeat(FRAMEBUFFER_SIZE / 3);
+        let mut emu = Emu::new();
+        emu.set_frame_blend(true);
+        emu.blend_frame(&frame(0));
+        emu.blend_frame(&frame(3));
+        let lightest = shade_to_rgb(0);
+        let darkest = shade_to_rgb(3);
+        for (i, byte) in emu.blended_frame[..3].iter().enumerate() {
+            assert!(darkest[i] < *byte && *byte < lightest[i]);
+        }
+        assert_eq!(emu.blended_frame[..3], [127, 127, 127]);
+        //Blending with itself changes nothing
+        emu.blend_frame(&frame(3));
+        assert_eq!(emu.blended_frame, frame(3));
+    }
+}
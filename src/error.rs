@@ -0,0 +1,51 @@
+use std::{error::Error, fmt::Display, io, path::PathBuf};
+
+#[derive(Debug)]
+pub enum EmuError {
+    //Smaller than the least a rom of that kind can hold
+    RomTooSmall(usize),
+    FileNotFound(PathBuf),
+    //Any other problem reading or writing a file
+    Io(PathBuf, io::Error),
+    UnsupportedMapper(u8),
+    BadHeader(String),
+    InvalidArgument(String),
+}
+
+impl EmuError {
+    pub fn from_io(path: PathBuf, error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::NotFound {
+            EmuError::FileNotFound(path)
+        } else {
+            EmuError::Io(path, error)
+        }
+    }
+}
+
+impl Display for EmuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmuError::RomTooSmall(size) => {
+                write!(f, "rom is too small, it is only {} bytes", size)
+            }
+            EmuError::FileNotFound(path) => write!(f, "couldn't find {}", path.display()),
+            EmuError::Io(path, error) => write!(f, "couldn't access {}: {}", path.display(), error),
+            EmuError::UnsupportedMapper(cartridge_type) => write!(
+                f,
+                "cartridge type 0x{:02x} uses a memory bank controller that isn't supported",
+                cartridge_type
+            ),
+            EmuError::BadHeader(reason) => write!(f, "bad cartridge header: {}", reason),
+            EmuError::InvalidArgument(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for EmuError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EmuError::Io(_, error) => Some(error),
+            _ => None,
+        }
+    }
+}
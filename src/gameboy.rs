@@ -1,28 +1,323 @@
+use crate::apu::Apu;
 use crate::cpu::Cpu;
-use crate::memory::{Memory, RomChunk};
-use crate::ppu::Ppu;
+use crate::display::InputState;
+use crate::instructions::Instruction;
+use crate::joypad::Button;
+use crate::memory::{Memory, RamFill, RomChunk, LY_ADDRESS};
+use crate::ppu::{Ppu, FRAMEBUFFER_SIZE, LCDC, STAT};
+use crate::registers::Registers;
+use crate::watchdog::Watchdog;
+use crate::Result;
 
-use log::trace;
+use log::{info, trace, warn};
+
+//Keeps a batch's worth of clocks from overflowing even if every instruction is a 6 cycle call
+pub const MAX_BATCH_SIZE: u32 = 1024;
+//A frame fits about 35000 instructions at most in double speed, so a frame that runs far past that
+//is stuck rather than just busy. Every GameBoy starts with this cap on
+const DEFAULT_INSTRUCTION_CAP: u64 = 100_000;
+
+//Which console to emulate, a CGB still runs roms that don't support it like a DMG would
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    Dmg,
+    Cgb,
+}
 
 pub struct GameBoy {
     pub cpu: Cpu,
     pub ppu: Ppu,
+    pub apu: Apu,
     pub memory: Memory,
+    frame_count: u64,
+    watchdog: Option<Watchdog>,
+    //How many instructions the cpu runs before the ppu and apu catch up
+    batch_size: u32,
+    //Logs every write to LCDC and STAT along with where it came from
+    log_lcd_writes: bool,
+    //Logs every mode the ppu enters and when
+    log_ppu_modes: bool,
+    //step_frame gives up after this many instructions so a runaway cpu can't hang the caller
+    instruction_cap: Option<u64>,
+    //What run_frame draws into, for frontends that would rather not hold their own buffer
+    framebuffer: Vec<u8>,
+}
+
+//A copy of everything that changes while running, the rom is shared with the running machine so
+//holding a few of these around is cheap
+#[derive(Clone)]
+pub struct SaveState {
+    cpu: Cpu,
+    ppu: Ppu,
+    apu: Apu,
+    memory: Memory,
+    frame_count: u64,
 }
 
 impl GameBoy {
-    pub fn new(boot_rom: RomChunk, cart_rom: RomChunk) -> Self {
+    //Without a boot rom everything starts off where the boot rom would have left it
+    pub fn new(boot_rom: Option<RomChunk>, cart_rom: RomChunk, model: Model) -> Result<Self> {
+        Self::new_with_fill(boot_rom, cart_rom, model, &RamFill::Zero)
+    }
+
+    //Like new but with ram starting out as something other than zeros
+    pub fn new_with_fill(
+        boot_rom: Option<RomChunk>,
+        cart_rom: RomChunk,
+        model: Model,
+        fill: &RamFill,
+    ) -> Result<Self> {
         trace!("Creating gameboy");
-        Self {
+        let skip_boot = boot_rom.is_none();
+        let mut gameboy = Self {
             cpu: Cpu::new(),
             ppu: Ppu::new(),
-            memory: Memory::new(boot_rom, cart_rom),
+            apu: Apu::new(),
+            memory: Memory::new_with_fill(boot_rom, cart_rom, model, fill)?,
+            frame_count: 0,
+            watchdog: None,
+            batch_size: 1,
+            log_lcd_writes: false,
+            log_ppu_modes: false,
+            instruction_cap: Some(DEFAULT_INSTRUCTION_CAP),
+            framebuffer: vec![0; FRAMEBUFFER_SIZE],
+        };
+        if skip_boot {
+            gameboy.cpu.registers = if gameboy.memory.cgb_mode() {
+                Registers::post_boot_cgb()
+            } else {
+                Registers::post_boot()
+            };
+            gameboy.memory.init_post_boot_io();
         }
+        Ok(gameboy)
     }
 
-    pub fn step(&mut self, pixel_data: &mut [u8]) -> bool {
+    //Runs a batch of instructions and returns how many 4MHz clocks it took and whether a frame
+    //finished
+    pub fn step(&mut self, pixel_data: &mut [u8]) -> (u16, bool) {
+        self.run_instructions(self.batch_size, pixel_data)
+    }
+
+    //Runs exactly one instruction and lets the ppu and apu catch up on it, for debugging
+    pub fn step_instruction(&mut self, pixel_data: &mut [u8]) -> (u16, bool) {
+        self.run_instructions(1, pixel_data)
+    }
+
+    fn run_instructions(&mut self, instructions: u32, pixel_data: &mut [u8]) -> (u16, bool) {
         trace!("stepping gameboy");
-        self.cpu.step(&mut self.memory);
-        self.ppu.step(&mut self.memory, pixel_data)
+        let mut cycles = 0;
+        let mut clocks = 0;
+        for _ in 0..instructions {
+            let instruction_cycles = self.cpu.step(&mut self.memory);
+            //The timer counts cpu cycles so it speeds up along with the cpu
+            self.memory.step_timer(instruction_cycles);
+            self.memory.step_serial(instruction_cycles);
+            cycles += instruction_cycles;
+            //A machine cycle is 4 clocks normally but only 2 in double speed mode
+            clocks += if self.memory.double_speed() {
+                instruction_cycles * 2
+            } else {
+                instruction_cycles * 4
+            };
+        }
+        if self.log_lcd_writes {
+            self.log_watched_writes();
+        }
+        //Everything else catches up on however long the cpu's instructions took
+        self.apu.step(&mut self.memory, clocks);
+        let frame_completed = self.ppu.step(&mut self.memory, pixel_data, clocks);
+        if self.log_ppu_modes {
+            for change in self.ppu.take_mode_changes() {
+                info!(
+                    "Entered {:?} on line {} at dot {}",
+                    change.mode, change.scanline, change.dot
+                );
+            }
+        }
+        if frame_completed {
+            self.frame_count += 1;
+            self.memory.apply_cheats();
+        }
+        if let Some(watchdog) = &mut self.watchdog {
+            //Frames still finish while the lcd is off but those never reach vblank
+            let vblank = frame_completed && self.ppu.lcd_enabled();
+            watchdog.check(cycles, vblank, &self.cpu);
+        }
+        (clocks, frame_completed)
+    }
+
+    //Runs until the ppu finishes a frame and returns how many 4MHz clocks it took, or until the
+    //instruction cap runs out
+    pub fn step_frame(&mut self, pixel_data: &mut [u8]) -> u32 {
+        let mut clocks = 0;
+        let start = self.cpu.instructions_executed();
+        loop {
+            let (step_clocks, frame_completed) = self.step(pixel_data);
+            clocks += step_clocks as u32;
+            if frame_completed {
+                return clocks;
+            }
+            let executed = self.cpu.instructions_executed() - start;
+            if self.instruction_cap.is_some_and(|cap| executed >= cap) {
+                warn!(
+                    "Gave up on frame {} after {} instructions at pc {:04X}",
+                    self.frame_count,
+                    executed,
+                    self.cpu.registers.get_pc()
+                );
+                return clocks;
+            }
+        }
+    }
+
+    //The cap is on from the start, a cap of 0 lets step_frame run for as long as the frame takes
+    pub fn set_instruction_cap(&mut self, cap: u64) {
+        self.instruction_cap = (cap > 0).then_some(cap);
+    }
+
+    //Runs a frame into the gameboy's own framebuffer, which is all a frontend without its own
+    //buffer needs along with inject_input
+    pub fn run_frame(&mut self) -> u32 {
+        let mut framebuffer = std::mem::take(&mut self.framebuffer);
+        let clocks = self.step_frame(&mut framebuffer);
+        self.framebuffer = framebuffer;
+        clocks
+    }
+
+    //The last frame drawn by run_frame as rgb bytes a row at a time
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    //Presses every button held or tapped in the input and releases the rest, only buttons that
+    //weren't already held raise the joypad interrupt. A tap stays pressed until the input's events
+    //are cleared so the game gets to see it
+    pub fn inject_input(&mut self, input: &InputState) {
+        for button in Button::ALL {
+            if input.is_held(button) || input.was_pressed(button) {
+                self.memory.press_button(button);
+            } else {
+                self.memory.release_button(button);
+            }
+        }
+    }
+
+    //Bigger batches run faster but anything the ppu or apu does lands up to a batch late
+    pub fn set_batch_size(&mut self, batch_size: u32) {
+        self.batch_size = batch_size.clamp(1, MAX_BATCH_SIZE);
+    }
+
+    pub fn set_lcd_write_logging(&mut self, enabled: bool) {
+        self.log_lcd_writes = enabled;
+        if enabled {
+            self.memory.watch(LCDC);
+            self.memory.watch(STAT);
+        }
+    }
+
+    pub fn set_ppu_mode_logging(&mut self, enabled: bool) {
+        self.log_ppu_modes = enabled;
+        self.ppu.set_mode_change_recording(enabled);
+    }
+
+    fn log_watched_writes(&mut self) {
+        for write in self.memory.take_watched_writes() {
+            let name = match write.address {
+                LCDC => "LCDC",
+                STAT => "STAT",
+                _ => "watched address",
+            };
+            info!(
+                "{:04X} wrote {:02X} to {} on line {}",
+                write.pc,
+                write.value,
+                name,
+                self.memory.read_u8(LY_ADDRESS)
+            );
+        }
+    }
+
+    //Warn if the given number of machine cycles pass without a vblank
+    pub fn set_watchdog(&mut self, budget: u64) {
+        self.watchdog = Some(Watchdog::new(budget));
+    }
+
+    pub fn save_state(&self) -> SaveState {
+        //Samples waiting to be played have already been heard so they don't belong in the state
+        let mut apu = self.apu.clone();
+        apu.clear_samples();
+        SaveState {
+            cpu: self.cpu.clone(),
+            ppu: self.ppu.clone(),
+            apu,
+            memory: self.memory.clone(),
+            frame_count: self.frame_count,
+        }
+    }
+
+    //Settings like the batch size and watchdog aren't part of the state and stay as they are
+    pub fn load_state(&mut self, state: SaveState) {
+        self.cpu = state.cpu;
+        self.ppu = state.ppu;
+        self.apu = state.apu;
+        self.memory = state.memory;
+        self.frame_count = state.frame_count;
+    }
+
+    //Goes back to the given power on state like switching it off and on again, so the cartridge's
+    //save survives and everything else starts over
+    pub fn reset(&mut self, power_on: &SaveState) {
+        let save = self.memory.save_data();
+        self.load_state(power_on.clone());
+        self.memory.load_save_data(&save);
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.cpu.instructions_executed()
+    }
+
+    //Lists every instruction from start up to and including end one per line with its address and
+    //bytes. It just walks forward so data mixed in with the code gets shown as instructions too and
+    //bytes that aren't an instruction we know come out as db
+    pub fn disassemble_rom(&self, start: u16, end: u16) -> String {
+        let mut listing = String::new();
+        let mut address = start as u32;
+        while address <= end as u32 {
+            let pc = address as u16;
+            let opcode = self.memory.read_u8(pc);
+            let instruction = if opcode == 0xCB {
+                Instruction::from_byte(self.memory.read_u8(pc.wrapping_add(1)), true)
+            } else {
+                Instruction::from_byte(opcode, false)
+            };
+            let (length, text) = match instruction {
+                Some(instruction) => (
+                    instruction.length(),
+                    instruction.resolved_text(&self.memory, pc),
+                ),
+                None => (1, format!("db 0x{:02X}", opcode)),
+            };
+            let bytes = (0..length)
+                .map(|offset| format!("{:02X}", self.memory.read_u8(pc.wrapping_add(offset))))
+                .collect::<Vec<_>>()
+                .join(" ");
+            listing.push_str(&format!("{:04X}: {:<8} {}\n", pc, bytes, text));
+            address += length as u32;
+        }
+        listing
+    }
+
+    //Runs without any frontend until the given number of frames have been drawn
+    pub fn run_frames(&mut self, frames: u32) -> Vec<u8> {
+        let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+        for _ in 0..frames {
+            self.step_frame(&mut pixel_data);
+        }
+        pixel_data
     }
 }
@@ -12,6 +12,12 @@ pub struct InstructionData {
     pub bit: Option<u8>,
 }
 
+impl Default for InstructionData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl InstructionData {
     pub const fn new() -> Self {
         Self {
@@ -7,9 +7,55 @@ use crate::registers::{Registers, CARRY_FLAG, R16, R8, ZERO_FLAG};
 
 pub struct Instruction {
     pub opcode: u8,
-    pub execute: fn(registers: &mut Registers, memory: &mut Memory),
+    //Runs the instruction and returns how many machine cycles it took
+    pub execute: fn(registers: &mut Registers, memory: &mut Memory) -> u16,
     pub cycles: u16,
     pub text: String,
+    pub category: InstructionCategory,
+}
+
+//Rough groups of instructions so logging can be cut down to just the interesting ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstructionCategory {
+    Load,
+    //Anything that can move the pc somewhere other than the next instruction
+    Jump,
+    Arithmetic,
+    Prefixed,
+    //nop, stop and the interrupt enables
+    Misc,
+}
+
+impl InstructionCategory {
+    pub const ALL: [InstructionCategory; 5] = [
+        InstructionCategory::Load,
+        InstructionCategory::Jump,
+        InstructionCategory::Arithmetic,
+        InstructionCategory::Prefixed,
+        InstructionCategory::Misc,
+    ];
+
+    //Goes off the mnemonic, prefixed instructions are all retagged when they are looked up
+    fn of(text: &str) -> Self {
+        match text.split(' ').next().unwrap_or_default() {
+            "ld" | "push" | "pop" => InstructionCategory::Load,
+            "jp" | "jr" | "call" | "ret" | "reti" | "rst" => InstructionCategory::Jump,
+            "nop" | "stop" | "halt" | "di" | "ei" => InstructionCategory::Misc,
+            _ => InstructionCategory::Arithmetic,
+        }
+    }
+
+    //The names used to pick categories from the environment
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "loads" => Some(InstructionCategory::Load),
+            "jumps" => Some(InstructionCategory::Jump),
+            "arithmetic" => Some(InstructionCategory::Arithmetic),
+            "prefixed" => Some(InstructionCategory::Prefixed),
+            "misc" => Some(InstructionCategory::Misc),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Instruction {
@@ -22,18 +68,74 @@ impl Display for Instruction {
     }
 }
 
+impl Instruction {
+    //Swaps the immediate operands in the text for the values that follow the opcode
+    pub fn resolved_text(&self, memory: &Memory, pc: u16) -> String {
+        let imm8 = memory.read_u8(pc.wrapping_add(1));
+        let imm16 = memory.read_u16(pc.wrapping_add(1));
+        //Relative jumps are shown with where they will land
+        let relative_target = pc.wrapping_add(2).wrapping_add(imm8 as i8 as u16);
+        self.text
+            .replace("a16", &format!("0x{:04X}", imm16))
+            .replace("d16", &format!("0x{:04X}", imm16))
+            .replace("a8", &format!("0x{:02X}", imm8))
+            .replace("d8", &format!("0x{:02X}", imm8))
+            .replace("s8", &format!("0x{:04X}", relative_target))
+    }
+
+    //Bytes taken up by the opcode and its operands, worked out from the operand names in the text
+    pub fn length(&self) -> u16 {
+        if self.category == InstructionCategory::Prefixed {
+            2
+        } else if self.opcode == 0x10 {
+            //stop has no operand in the text but is always followed by a padding byte
+            2
+        } else if self.text.contains("a16") || self.text.contains("d16") {
+            3
+        } else if ["a8", "d8", "s8"]
+            .iter()
+            .any(|operand| self.text.contains(operand))
+        {
+            2
+        } else {
+            1
+        }
+    }
+}
+
 macro_rules! instr {
     ($op:expr, $name:expr, $cycles:expr, $method:ident, $additional:expr) => {{
         const INSTRUCTION_DATA: InstructionData = $additional;
-        fn evaluate(registers: &mut Registers, memory: &mut Memory) {
+        fn evaluate(registers: &mut Registers, memory: &mut Memory) -> u16 {
             trace!("{:X?}", INSTRUCTION_DATA);
             $method(registers, memory, &INSTRUCTION_DATA);
+            $cycles
+        }
+        Some(Instruction {
+            opcode: $op,
+            execute: evaluate,
+            cycles: $cycles,
+            text: $name.to_string(),
+            category: InstructionCategory::of($name),
+        })
+    }};
+    //Branches take fewer cycles when they aren't taken, the method returns whether it was
+    ($op:expr, $name:expr, $cycles:expr, $untaken_cycles:expr, $method:ident, $additional:expr) => {{
+        const INSTRUCTION_DATA: InstructionData = $additional;
+        fn evaluate(registers: &mut Registers, memory: &mut Memory) -> u16 {
+            trace!("{:X?}", INSTRUCTION_DATA);
+            if $method(registers, memory, &INSTRUCTION_DATA) {
+                $cycles
+            } else {
+                $untaken_cycles
+            }
         }
         Some(Instruction {
             opcode: $op,
             execute: evaluate,
             cycles: $cycles,
             text: $name.to_string(),
+            category: InstructionCategory::of($name),
         })
     }};
 }
@@ -42,6 +144,16 @@ fn check_for_half_carry_8bit(lhs: u8, rhs: u8) -> bool {
     (lhs & 0xF) + (rhs & 0xF) > 0xF
 }
 
+//Subtraction sets the half carry when the low nibble has to borrow from the high one
+fn check_for_half_borrow_8bit(lhs: u8, rhs: u8) -> bool {
+    (lhs & 0xF) < (rhs & 0xF)
+}
+
+//sbc borrows the carry as well so it can need a half borrow even when the nibbles are equal
+fn check_for_half_borrow_with_carry_8bit(lhs: u8, rhs: u8, carry: u8) -> bool {
+    (lhs & 0xF) < (rhs & 0xF) + carry
+}
+
 fn check_for_half_carry_16bit(lhs: u16, rhs: u16) -> bool {
     (lhs & 0xFF) + (rhs & 0xFF) > 0xFF
 }
@@ -56,28 +168,44 @@ pub fn jump_r16(registers: &mut Registers, _memory: &mut Memory, additional: &In
     registers.set_pc(target_address);
 }
 
-pub fn jump_imm16(registers: &mut Registers, memory: &mut Memory, additional: &InstructionData) {
+//Whether a conditional jump, call or ret is taken, a mask of 0 means it always is
+fn condition_met(registers: &Registers, additional: &InstructionData) -> bool {
+    let expected = additional.flag_expected.unwrap() != 0;
+    match additional.flag_mask.unwrap() {
+        0 => true,
+        ZERO_FLAG => registers.zero_flag() == expected,
+        CARRY_FLAG => registers.carry_flag() == expected,
+        mask => unreachable!("no condition checks the flags {:02x}", mask),
+    }
+}
+
+pub fn jump_imm16(
+    registers: &mut Registers,
+    memory: &mut Memory,
+    additional: &InstructionData,
+) -> bool {
     registers.inc_pc(1);
-    //should we jump mask out the flag we are checking for and see if it is a go
-    if (registers.get_flags() & additional.flag_mask.unwrap()) == additional.flag_expected.unwrap()
-    {
+    //jp a16 uses a mask of 0 so it is always taken
+    if condition_met(registers, additional) {
         //immediate jump get the address immediately after the pc
         let target_address = memory.read_u16(registers.get_pc());
         registers.set_pc(target_address);
-    //if we don't jump we need to increment the pc by 3 for the width of the jump op
+        true
     } else {
-        //If we don't jump skip over the address
+        //If we don't jump skip over the address so the pc moves by 3 for the whole op
         registers.inc_pc(2);
-        //only 3 cycles on non jump
-        memory.cpu_cycles = 3;
+        false
     }
 }
 
-pub fn jump_rel_imm8(registers: &mut Registers, memory: &mut Memory, additional: &InstructionData) {
+pub fn jump_rel_imm8(
+    registers: &mut Registers,
+    memory: &mut Memory,
+    additional: &InstructionData,
+) -> bool {
     registers.inc_pc(1);
     //If we want to follow the jump
-    if (registers.get_flags() & additional.flag_mask.unwrap()) == additional.flag_expected.unwrap()
-    {
+    if condition_met(registers, additional) {
         //Get the relative jump we want to make and make it
         let rel = memory.read_u8(registers.get_pc());
         registers.inc_pc(1);
@@ -97,11 +225,11 @@ pub fn jump_rel_imm8(registers: &mut Registers, memory: &mut Memory, additional:
             new_pc
         );
         registers.set_pc(new_pc);
+        true
     } else {
         //If we don't follow the jump advance pc by one more
         registers.inc_pc(1);
-        //Also it only takes 2 cycles if not taking branch
-        memory.cpu_cycles = 2;
+        false
     }
 }
 
@@ -239,7 +367,7 @@ fn ldi_r8_indir_r16(registers: &mut Registers, memory: &mut Memory, additional:
     let address = registers.read_r16(additional.r16_src.unwrap());
     let value = memory.read_u8(address);
     registers.write_r8(additional.r8_dst.unwrap(), value);
-    registers.write_r16(additional.r16_src.unwrap(), address + 1);
+    registers.write_r16(additional.r16_src.unwrap(), address.wrapping_add(1));
 }
 
 fn ldd_r8_indir_r16(registers: &mut Registers, memory: &mut Memory, additional: &InstructionData) {
@@ -247,7 +375,7 @@ fn ldd_r8_indir_r16(registers: &mut Registers, memory: &mut Memory, additional:
     let address = registers.read_r16(additional.r16_src.unwrap());
     let value = memory.read_u8(address);
     registers.write_r8(additional.r8_dst.unwrap(), value);
-    registers.write_r16(additional.r16_src.unwrap(), address - 1);
+    registers.write_r16(additional.r16_src.unwrap(), address.wrapping_sub(1));
 }
 
 //Bit logic funcitons
@@ -334,7 +462,7 @@ fn cp_r8(registers: &mut Registers, _memory: &mut Memory, additional: &Instructi
     registers.set_flags(
         Some(result == 0),
         Some(true),
-        Some(check_for_half_carry_8bit(a, value)),
+        Some(check_for_half_borrow_8bit(a, value)),
         Some(carried),
     );
 }
@@ -348,7 +476,7 @@ fn cp_indir_r16(registers: &mut Registers, memory: &mut Memory, additional: &Ins
     registers.set_flags(
         Some(result == 0),
         Some(true),
-        Some(check_for_half_carry_8bit(a, value)),
+        Some(check_for_half_borrow_8bit(a, value)),
         Some(carried),
     );
 }
@@ -362,7 +490,7 @@ fn cp_imm8(registers: &mut Registers, memory: &mut Memory, _additional: &Instruc
     registers.set_flags(
         Some(result == 0),
         Some(true),
-        Some(check_for_half_carry_8bit(a, value)),
+        Some(check_for_half_borrow_8bit(a, value)),
         Some(carried),
     );
 }
@@ -503,6 +631,7 @@ fn inc_indir_r16(registers: &mut Registers, memory: &mut Memory, additional: &In
     let value = memory.read_u8(address);
     let result = value.wrapping_add(1);
     memory.write_u8(address, result);
+    //Unlike add, inc leaves the carry flag alone
     registers.set_flags(
         Some(result == 0),
         Some(false),
@@ -520,7 +649,7 @@ fn sub_r8(registers: &mut Registers, _memory: &mut Memory, additional: &Instruct
     registers.set_flags(
         Some(result == 0),
         Some(true),
-        Some(check_for_half_carry_8bit(lhs, rhs)),
+        Some(check_for_half_borrow_8bit(lhs, rhs)),
         Some(carried),
     );
 }
@@ -535,7 +664,7 @@ fn sub_indir_r16(registers: &mut Registers, memory: &mut Memory, additional: &In
     registers.set_flags(
         Some(result == 0),
         Some(true),
-        Some(check_for_half_carry_8bit(lhs, rhs)),
+        Some(check_for_half_borrow_8bit(lhs, rhs)),
         Some(carried),
     );
 }
@@ -550,7 +679,7 @@ fn sub_imm8(registers: &mut Registers, memory: &mut Memory, _additional: &Instru
     registers.set_flags(
         Some(result == 0),
         Some(true),
-        Some(check_for_half_carry_8bit(lhs, rhs)),
+        Some(check_for_half_borrow_8bit(lhs, rhs)),
         Some(carried),
     );
 }
@@ -560,12 +689,14 @@ fn sbc_r8(registers: &mut Registers, _memory: &mut Memory, additional: &Instruct
     let lhs = registers.read_r8(R8::A);
     let rhs = registers.read_r8(additional.r8_src.unwrap());
     let carry = registers.carry_flag() as u8;
-    let (result, carried) = lhs.overflowing_sub(rhs - carry);
+    let (partial, borrowed) = lhs.overflowing_sub(rhs);
+    let (result, borrowed_carry) = partial.overflowing_sub(carry);
+    let carried = borrowed || borrowed_carry;
     registers.write_r8(R8::A, result);
     registers.set_flags(
         Some(result == 0),
         Some(true),
-        Some(check_for_half_carry_8bit(lhs, rhs)),
+        Some(check_for_half_borrow_with_carry_8bit(lhs, rhs, carry)),
         Some(carried),
     );
 }
@@ -576,12 +707,14 @@ fn sbc_indir_r16(registers: &mut Registers, memory: &mut Memory, additional: &In
     let address = registers.read_r16(additional.r16_src.unwrap());
     let rhs = memory.read_u8(address);
     let carry = registers.carry_flag() as u8;
-    let (result, carried) = lhs.overflowing_sub(rhs - carry);
+    let (partial, borrowed) = lhs.overflowing_sub(rhs);
+    let (result, borrowed_carry) = partial.overflowing_sub(carry);
+    let carried = borrowed || borrowed_carry;
     registers.write_r8(R8::A, result);
     registers.set_flags(
         Some(result == 0),
         Some(true),
-        Some(check_for_half_carry_8bit(lhs, rhs)),
+        Some(check_for_half_borrow_with_carry_8bit(lhs, rhs, carry)),
         Some(carried),
     );
 }
@@ -592,12 +725,14 @@ fn sbc_imm8(registers: &mut Registers, memory: &mut Memory, _additional: &Instru
     let address = registers.get_pc();
     let rhs = memory.read_u8(address);
     let carry = registers.carry_flag() as u8;
-    let (result, carried) = lhs.overflowing_sub(rhs - carry);
+    let (partial, borrowed) = lhs.overflowing_sub(rhs);
+    let (result, borrowed_carry) = partial.overflowing_sub(carry);
+    let carried = borrowed || borrowed_carry;
     registers.write_r8(R8::A, result);
     registers.set_flags(
         Some(result == 0),
         Some(true),
-        Some(check_for_half_carry_8bit(lhs, rhs)),
+        Some(check_for_half_borrow_with_carry_8bit(lhs, rhs, carry)),
         Some(carried),
     );
 }
@@ -611,7 +746,7 @@ fn dec_r8(registers: &mut Registers, _memory: &mut Memory, additional: &Instruct
     registers.set_flags(
         Some(result == 0),
         Some(true),
-        Some(check_for_half_carry_8bit(value, 1)),
+        Some(check_for_half_borrow_8bit(value, 1)),
         None,
     );
 }
@@ -630,10 +765,11 @@ fn dec_indir_r16(registers: &mut Registers, memory: &mut Memory, additional: &In
     let value = memory.read_u8(address);
     let result = value.wrapping_sub(1);
     memory.write_u8(address, result);
+    //Unlike sub, dec leaves the carry flag alone
     registers.set_flags(
         Some(result == 0),
         Some(true),
-        Some(check_for_half_carry_8bit(value, 1)),
+        Some(check_for_half_borrow_8bit(value, 1)),
         None,
     );
 }
@@ -644,14 +780,24 @@ fn ret(registers: &mut Registers, memory: &mut Memory, _additional: &Instruction
     registers.set_pc(new_pc);
 }
 
-fn ret_conditional(registers: &mut Registers, memory: &mut Memory, additional: &InstructionData) {
+//Returns from an interrupt handler with interrupts enabled again straight away
+fn reti(registers: &mut Registers, memory: &mut Memory, additional: &InstructionData) {
+    ret(registers, memory, additional);
+    registers.set_ime(true);
+}
+
+fn ret_conditional(
+    registers: &mut Registers,
+    memory: &mut Memory,
+    additional: &InstructionData,
+) -> bool {
     registers.inc_pc(1);
-    if (registers.get_flags() & additional.flag_mask.unwrap()) == additional.flag_expected.unwrap()
-    {
+    if condition_met(registers, additional) {
         let new_pc = registers.stack_pop16(memory);
         registers.set_pc(new_pc);
+        true
     } else {
-        memory.cpu_cycles = 2;
+        false
     }
 }
 
@@ -673,25 +819,43 @@ fn pop_r16(registers: &mut Registers, memory: &mut Memory, additional: &Instruct
     registers.write_r16(additional.r16_dst.unwrap(), value);
 }
 
-fn call(registers: &mut Registers, memory: &mut Memory, additional: &InstructionData) {
+fn call(registers: &mut Registers, memory: &mut Memory, additional: &InstructionData) -> bool {
     registers.inc_pc(1);
     let address = memory.read_u16(registers.get_pc());
     registers.inc_pc(2);
-    if (registers.get_flags() & additional.flag_mask.unwrap()) == additional.flag_expected.unwrap()
-    {
+    if condition_met(registers, additional) {
         registers.stack_push16(registers.get_pc(), memory);
         registers.set_pc(address);
+        true
     } else {
-        // If we don't take the call its only 3 cycles
-        memory.cpu_cycles = 3;
+        false
     }
 }
 
 //Special functions
 
-//Meant to save battery but I don't think we have to do anything since we aren't on battery
-fn stop(registers: &mut Registers, _memory: &mut Memory, _additional: &InstructionData) {
+//Stop is encoded as 0x10 0x00 so we skip over the padding byte too
+//It resets DIV and halts everything until a button is pressed, unless a CGB speed switch has
+//been armed through KEY1 in which case it switches speed and carries on
+fn stop(registers: &mut Registers, memory: &mut Memory, _additional: &InstructionData) {
     registers.inc_pc(2);
+    memory.reset_div();
+    if memory.speed_switch_armed() {
+        memory.switch_speed();
+    } else {
+        registers.set_stopped(true);
+    }
+}
+
+//Halt sleeps until an interrupt is requested, whether or not IME lets it be serviced. If one is
+//already waiting with IME off the cpu never sleeps and trips over the halt bug instead
+fn halt(registers: &mut Registers, memory: &mut Memory, _additional: &InstructionData) {
+    registers.inc_pc(1);
+    if memory.pending_interrupts() == 0 {
+        registers.set_halted(true);
+    } else if !registers.ime() {
+        registers.set_halt_bug(true);
+    }
 }
 
 //Bit manipulation functions
@@ -717,7 +881,7 @@ fn rrca(registers: &mut Registers, _memory: &mut Memory, _additional: &Instructi
     registers.inc_pc(1);
     let value = registers.read_r8(R8::A);
     let new_carry = (value & 0b1) == 0b1;
-    let value = (value >> 1) | ((new_carry as u8) << 7) as u8;
+    let value = (value >> 1) | ((new_carry as u8) << 7);
     registers.write_r8(R8::A, value);
     registers.set_flags(Some(false), Some(false), Some(false), Some(new_carry));
 }
@@ -726,7 +890,7 @@ fn rra(registers: &mut Registers, _memory: &mut Memory, _additional: &Instructio
     registers.inc_pc(1);
     let value = registers.read_r8(R8::A);
     let new_carry = (value & 0b1) == 0b1;
-    let value = (value >> 1) | ((registers.carry_flag() as u8) << 7) as u8;
+    let value = (value >> 1) | ((registers.carry_flag() as u8) << 7);
     registers.write_r8(R8::A, value);
     registers.set_flags(Some(false), Some(false), Some(false), Some(new_carry));
 }
@@ -738,14 +902,42 @@ fn cpl(registers: &mut Registers, _memory: &mut Memory, _additional: &Instructio
     registers.write_r8(R8::A, ones_complement);
 }
 
+//Fixes up A after adding or subtracting two binary coded decimal numbers
+fn daa(registers: &mut Registers, _memory: &mut Memory, _additional: &InstructionData) {
+    registers.inc_pc(1);
+    let mut a = registers.read_r8(R8::A);
+    let mut carry = registers.carry_flag();
+    if !registers.sub_flag() {
+        if carry || a > 0x99 {
+            a = a.wrapping_add(0x60);
+            carry = true;
+        }
+        if registers.half_carry_flag() || (a & 0x0F) > 0x09 {
+            a = a.wrapping_add(0x06);
+        }
+    } else {
+        if carry {
+            a = a.wrapping_sub(0x60);
+        }
+        if registers.half_carry_flag() {
+            a = a.wrapping_sub(0x06);
+        }
+    }
+    registers.write_r8(R8::A, a);
+    registers.set_flags(Some(a == 0), None, Some(false), Some(carry));
+}
+
 fn di(registers: &mut Registers, _memory: &mut Memory, _additional: &InstructionData) {
     registers.inc_pc(1);
     registers.set_ime(false);
+    //A di straight after an ei stops the enable from ever happening
+    registers.set_ime_pending(false);
 }
 
+//The cpu turns IME on after the next instruction so ei; ret can't be interrupted in between
 fn ei(registers: &mut Registers, _memory: &mut Memory, _additional: &InstructionData) {
     registers.inc_pc(1);
-    registers.set_ime(true);
+    registers.set_ime_pending(true);
 }
 
 fn scf(registers: &mut Registers, _memory: &mut Memory, _additional: &InstructionData) {
@@ -995,12 +1187,17 @@ fn ext_set_bit_indir_r16(
 impl Instruction {
     pub fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction> {
         if prefixed {
-            Instruction::from_byte_prefixed(byte)
+            Instruction::from_byte_prefixed(byte).map(|instruction| Instruction {
+                category: InstructionCategory::Prefixed,
+                ..instruction
+            })
         } else {
             Instruction::from_byte_not_prefixed(byte)
         }
     }
 
+    //Cycles here count the whole op including the 0xCB fetch and the handlers step the pc over
+    //both bytes, so bit n, (hl) is 3 cycles and every other (hl) op is 4
     #[rustfmt::skip]
     fn from_byte_prefixed(byte: u8) -> Option<Instruction> {
         match byte {
@@ -1282,7 +1479,7 @@ impl Instruction {
             0x0D => instr!(byte, "dec c", 1, dec_r8, InstructionData::new().r8_dst(R8::C)),
             0x0E => instr!(byte, "ld c, d8", 2, ld_r8_imm8, InstructionData::new().r8_dst(R8::C)),
             0x0F => instr!(byte, "rrca", 1, rrca, InstructionData::new()),
-            0x10 => instr!(byte, "stop", 1, stop, InstructionData::new()),
+            0x10 => instr!(byte, "stop 0", 1, stop, InstructionData::new()),
             0x11 => instr!(byte, "ld de, d16", 3, ld_r16_imm16, InstructionData::new().r16_dst(R16::DE)),
             0x12 => instr!(byte, "ld (de), a", 2, ld_indir_r16_r8, InstructionData::new().r16_dst(R16::DE).r8_src(R8::A)),
             0x13 => instr!(byte, "inc de", 2, inc_r16, InstructionData::new().r16_dst(R16::DE)),
@@ -1290,7 +1487,7 @@ impl Instruction {
             0x15 => instr!(byte, "dec d", 1, dec_r8, InstructionData::new().r8_dst(R8::D)),
             0x16 => instr!(byte, "ld d, d8", 2, ld_r8_imm8, InstructionData::new().r8_dst(R8::D)),
             0x17 => instr!(byte, "rla", 1, rla, InstructionData::new()),
-            0x18 => instr!(byte, "jr s8", 3, jump_rel_imm8, InstructionData::new().with_flags(0, 0)),
+            0x18 => instr!(byte, "jr s8", 3, 2, jump_rel_imm8, InstructionData::new().with_flags(0, 0)),
             0x19 => instr!(byte, "add hl, de", 2, add_r16_r16, InstructionData::new().r16_src(R16::DE).r16_dst(R16::HL)),
             0x1A => instr!(byte, "ld a, (de)", 2, ld_r8_indir_r16, InstructionData::new().r8_dst(R8::A).r16_src(R16::DE)),
             0x1B => instr!(byte, "dec de", 2, dec_r16, InstructionData::new().r16_dst(R16::DE)),
@@ -1298,15 +1495,15 @@ impl Instruction {
             0x1D => instr!(byte, "dec e", 1, dec_r8, InstructionData::new().r8_dst(R8::E)),
             0x1E => instr!(byte, "ld e, d8", 2, ld_r8_imm8, InstructionData::new().r8_dst(R8::E)),
             0x1F => instr!(byte, "rra", 1, rra, InstructionData::new()),
-            0x20 => instr!(byte, "jr nz, s8", 3, jump_rel_imm8, InstructionData::new().with_flags(ZERO_FLAG, 0)),
+            0x20 => instr!(byte, "jr nz, s8", 3, 2, jump_rel_imm8, InstructionData::new().with_flags(ZERO_FLAG, 0)),
             0x21 => instr!(byte, "ld hl, d16", 3, ld_r16_imm16, InstructionData::new().r16_dst(R16::HL)),
             0x22 => instr!(byte, "ld (hl+), a", 2, ldi_indir_r16_r8, InstructionData::new().r8_src(R8::A).r16_dst(R16::HL)),
             0x23 => instr!(byte, "inc hl", 2, inc_r16, InstructionData::new().r16_dst(R16::HL)),
             0x24 => instr!(byte, "inc h", 1, inc_r8, InstructionData::new().r8_dst(R8::H)),
             0x25 => instr!(byte, "dec h", 1, dec_r8, InstructionData::new().r8_dst(R8::H)),
             0x26 => instr!(byte, "ld h, d8", 2, ld_r8_imm8, InstructionData::new().r8_dst(R8::H)),
-            0x27 => None,
-            0x28 => instr!(byte, "jr z, s8", 3, jump_rel_imm8, InstructionData::new().with_flags(ZERO_FLAG, ZERO_FLAG)),
+            0x27 => instr!(byte, "daa", 1, daa, InstructionData::new()),
+            0x28 => instr!(byte, "jr z, s8", 3, 2, jump_rel_imm8, InstructionData::new().with_flags(ZERO_FLAG, ZERO_FLAG)),
             0x29 => instr!(byte, "add hl, hl", 2, add_r16_r16, InstructionData::new().r16_src(R16::HL).r16_dst(R16::HL)),
             0x2A => instr!(byte, "ld a, (hl+)", 2, ldi_r8_indir_r16, InstructionData::new().r16_src(R16::HL).r8_dst(R8::A)),
             0x2B => instr!(byte, "dec hl", 2, dec_r16, InstructionData::new().r16_dst(R16::HL)),
@@ -1314,7 +1511,7 @@ impl Instruction {
             0x2D => instr!(byte, "dec l", 1, dec_r8, InstructionData::new().r8_dst(R8::L)),
             0x2E => instr!(byte, "ld l, d8", 2, ld_r8_imm8, InstructionData::new().r8_dst(R8::L)),
             0x2F => instr!(byte, "cpl", 1, cpl, InstructionData::new()),
-            0x30 => instr!(byte, "jr nc, s8", 3, jump_rel_imm8, InstructionData::new().with_flags(CARRY_FLAG, 0)),
+            0x30 => instr!(byte, "jr nc, s8", 3, 2, jump_rel_imm8, InstructionData::new().with_flags(CARRY_FLAG, 0)),
             0x31 => instr!(byte, "ld sp, d16", 3, ld_r16_imm16, InstructionData::new().r16_dst(R16::SP)),
             0x32 => instr!(byte, "ld (hl-), a", 2, ldd_indir_r16_r8, InstructionData::new().r8_src(R8::A).r16_dst(R16::HL)),
             0x33 => instr!(byte, "inc sp", 2, inc_r16, InstructionData::new().r16_dst(R16::SP)),
@@ -1322,7 +1519,7 @@ impl Instruction {
             0x35 => instr!(byte, "dec (hl)", 3, dec_indir_r16, InstructionData::new().r16_dst(R16::HL)),
             0x36 => instr!(byte, "ld (hl), d8", 3, ld_indir_r16_imm8, InstructionData::new().r16_dst(R16::HL)),
             0x37 => instr!(byte, "scf", 1, scf, InstructionData::new()),
-            0x38 => instr!(byte, "jr s8", 3, jump_rel_imm8, InstructionData::new().with_flags(CARRY_FLAG, CARRY_FLAG)),
+            0x38 => instr!(byte, "jr s8", 3, 2, jump_rel_imm8, InstructionData::new().with_flags(CARRY_FLAG, CARRY_FLAG)),
             0x39 => instr!(byte, "add hl, sp", 2, add_r16_r16, InstructionData::new().r16_src(R16::SP).r16_dst(R16::HL)),
             0x3A => instr!(byte, "ld a, (hl-)", 2, ldd_r8_indir_r16, InstructionData::new().r16_src(R16::HL).r8_dst(R8::A)),
             0x3B => instr!(byte, "dec sp", 2, dec_r16, InstructionData::new().r16_dst(R16::SP)),
@@ -1384,7 +1581,7 @@ impl Instruction {
             0x73 => instr!(byte, "ld (hl) e", 2, ld_indir_r16_r8, InstructionData::new().r16_dst(R16::HL).r8_src(R8::E)),
             0x74 => instr!(byte, "ld (hl) h", 2, ld_indir_r16_r8, InstructionData::new().r16_dst(R16::HL).r8_src(R8::H)),
             0x75 => instr!(byte, "ld (hl) l", 2, ld_indir_r16_r8, InstructionData::new().r16_dst(R16::HL).r8_src(R8::L)),
-            0x76 => None,
+            0x76 => instr!(byte, "halt", 1, halt, InstructionData::new()),
             0x77 => instr!(byte, "ld (hl) a", 2, ld_indir_r16_r8, InstructionData::new().r16_dst(R16::HL).r8_src(R8::A)),
             0x78 => instr!(byte, "ld a b", 1, ld_r8_r8, InstructionData::new().r8_dst(R8::A).r8_src(R8::B)),
             0x79 => instr!(byte, "ld a c", 1, ld_r8_r8, InstructionData::new().r8_dst(R8::A).r8_src(R8::C)),
@@ -1458,35 +1655,35 @@ impl Instruction {
             0xBD => instr!(byte, "cp l",  1, cp_r8, InstructionData::new().r8_src(R8::L)),
             0xBE => instr!(byte, "cp hl", 2, cp_indir_r16, InstructionData::new().r16_src(R16::HL)),
             0xBF => instr!(byte, "cp a",  1, cp_r8, InstructionData::new().r8_src(R8::A)),
-            0xC0 => instr!(byte, "ret nz", 5, ret_conditional, InstructionData::new().with_flags(ZERO_FLAG, 0)),
+            0xC0 => instr!(byte, "ret nz", 5, 2, ret_conditional, InstructionData::new().with_flags(ZERO_FLAG, 0)),
             0xC1 => instr!(byte, "pop bc", 3, pop_r16, InstructionData::new().r16_dst(R16::BC)),
-            0xC2 => instr!(byte, "jp nz, a16", 4, jump_imm16, InstructionData::new().with_flags(ZERO_FLAG, 0)),
-            0xC3 => instr!(byte, "jp a16", 4, jump_imm16, InstructionData::new().with_flags(0, 0)),
-            0xC4 => instr!(byte, "call nz, a16", 6, call, InstructionData::new().with_flags(ZERO_FLAG, 0)),
+            0xC2 => instr!(byte, "jp nz, a16", 4, 3, jump_imm16, InstructionData::new().with_flags(ZERO_FLAG, 0)),
+            0xC3 => instr!(byte, "jp a16", 4, 3, jump_imm16, InstructionData::new().with_flags(0, 0)),
+            0xC4 => instr!(byte, "call nz, a16", 6, 3, call, InstructionData::new().with_flags(ZERO_FLAG, 0)),
             0xC5 => instr!(byte, "push bc", 4, push_r16, InstructionData::new().r16_src(R16::BC)),
             0xC6 => instr!(byte, "add a, d8", 2, add_imm8, InstructionData::new()),
             0xC7 => instr!(byte, "rst 0", 4, rst_n, InstructionData::new().rst_code(0x00)),
-            0xC8 => instr!(byte, "ret z", 5, ret_conditional, InstructionData::new().with_flags(ZERO_FLAG, ZERO_FLAG)),
+            0xC8 => instr!(byte, "ret z", 5, 2, ret_conditional, InstructionData::new().with_flags(ZERO_FLAG, ZERO_FLAG)),
             0xC9 => instr!(byte, "ret", 4, ret, InstructionData::new()),
-            0xCA => instr!(byte, "jp z, a16", 4, jump_imm16, InstructionData::new().with_flags(ZERO_FLAG, ZERO_FLAG)),
+            0xCA => instr!(byte, "jp z, a16", 4, 3, jump_imm16, InstructionData::new().with_flags(ZERO_FLAG, ZERO_FLAG)),
             0xCB => None, // Not an instruction
-            0xCC => instr!(byte, "call z, a16", 6, call, InstructionData::new().with_flags(ZERO_FLAG, ZERO_FLAG)),
-            0xCD => instr!(byte, "call a16", 6, call, InstructionData::new().with_flags(0, 0)),
+            0xCC => instr!(byte, "call z, a16", 6, 3, call, InstructionData::new().with_flags(ZERO_FLAG, ZERO_FLAG)),
+            0xCD => instr!(byte, "call a16", 6, 3, call, InstructionData::new().with_flags(0, 0)),
             0xCE => instr!(byte, "adc a, d8", 2, adc_imm8, InstructionData::new()),
             0xCF => instr!(byte, "rst 1", 4, rst_n, InstructionData::new().rst_code(0x08)),
-            0xD0 => instr!(byte, "ret nc", 5, ret_conditional, InstructionData::new().with_flags(CARRY_FLAG, 0)),
+            0xD0 => instr!(byte, "ret nc", 5, 2, ret_conditional, InstructionData::new().with_flags(CARRY_FLAG, 0)),
             0xD1 => instr!(byte, "pop de", 3, pop_r16, InstructionData::new().r16_dst(R16::DE)),
-            0xD2 => instr!(byte, "jp nc, a16", 4, jump_imm16, InstructionData::new().with_flags(CARRY_FLAG, 0)),
+            0xD2 => instr!(byte, "jp nc, a16", 4, 3, jump_imm16, InstructionData::new().with_flags(CARRY_FLAG, 0)),
             0xD3 => None, // Not an instruction
-            0xD4 => instr!(byte, "call nc, a16", 6, call, InstructionData::new().with_flags(CARRY_FLAG, 0)),
+            0xD4 => instr!(byte, "call nc, a16", 6, 3, call, InstructionData::new().with_flags(CARRY_FLAG, 0)),
             0xD5 => instr!(byte, "push de", 4, push_r16, InstructionData::new().r16_src(R16::DE)),
             0xD6 => instr!(byte, "sub d8", 2, sub_imm8, InstructionData::new()),
             0xD7 => instr!(byte, "rst 2", 4, rst_n, InstructionData::new().rst_code(0x10)),
-            0xD8 => instr!(byte, "ret c", 5, ret_conditional, InstructionData::new().with_flags(CARRY_FLAG, CARRY_FLAG)),
-            0xD9 => None,
-            0xDA => instr!(byte, "jp c, a16", 4, jump_imm16, InstructionData::new().with_flags(CARRY_FLAG, CARRY_FLAG)),
+            0xD8 => instr!(byte, "ret c", 5, 2, ret_conditional, InstructionData::new().with_flags(CARRY_FLAG, CARRY_FLAG)),
+            0xD9 => instr!(byte, "reti", 4, reti, InstructionData::new()),
+            0xDA => instr!(byte, "jp c, a16", 4, 3, jump_imm16, InstructionData::new().with_flags(CARRY_FLAG, CARRY_FLAG)),
             0xDB => None, // Not an instruction
-            0xDC => instr!(byte, "call c, a16", 6, call, InstructionData::new().with_flags(CARRY_FLAG, CARRY_FLAG)),
+            0xDC => instr!(byte, "call c, a16", 6, 3, call, InstructionData::new().with_flags(CARRY_FLAG, CARRY_FLAG)),
             0xDD => None, // Not an instruction
             0xDE => instr!(byte, "sbc d8", 2, sbc_imm8, InstructionData::new()),
             0xDF => instr!(byte, "rst 3", 4, rst_n, InstructionData::new().rst_code(0x18)),
@@ -1525,3 +1722,222 @@ impl Instruction {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::tests::{setup, CODE};
+    use crate::cpu_state::CpuState;
+    use crate::memory::INTERRUPT_ENABLE_ADDRESS;
+    use crate::registers::HALF_CARRY_FLAG;
+    use crate::timer::DIV_ADDRESS;
+
+    //Runs the code's first instruction and hands back the registers after along with its cycles
+    fn run(code: &[u8], state: CpuState) -> (CpuState, u16) {
+        let (mut cpu, mut memory) = setup(code, state);
+        let cycles = cpu.step(&mut memory);
+        (cpu.capture_state(), cycles)
+    }
+
+    //Runs an (hl) instruction against value stored at hl and hands back the registers and the value after
+    fn run_indir(opcode: u8, value: u8, state: CpuState) -> (CpuState, u8) {
+        let (mut cpu, mut memory) = setup(&[opcode], state.h(0xC1).l(0x00));
+        memory.write_u8(0xC100, value);
+        cpu.step(&mut memory);
+        (cpu.capture_state(), memory.read_u8(0xC100))
+    }
+
+    fn r16(state: &CpuState, opcode: u8) -> u16 {
+        match opcode & 0x30 {
+            0x00 => (state.b as u16) << 8 | state.c as u16,
+            0x10 => (state.d as u16) << 8 | state.e as u16,
+            0x20 => (state.h as u16) << 8 | state.l as u16,
+            _ => state.sp,
+        }
+    }
+
+    fn with_r16(state: CpuState, opcode: u8, value: u16) -> CpuState {
+        let (high, low) = ((value >> 8) as u8, value as u8);
+        match opcode & 0x30 {
+            0x00 => state.b(high).c(low),
+            0x10 => state.d(high).e(low),
+            0x20 => state.h(high).l(low),
+            _ => state.sp(value),
+        }
+    }
+
+    //inc and dec of bc, de, hl and sp with the value before and after
+    const INC_DEC_R16: [(u8, u16, u16); 12] = [
+        (0x03, 0xFFFF, 0x0000),
+        (0x13, 0xFFFF, 0x0000),
+        (0x23, 0xFFFF, 0x0000),
+        (0x33, 0xFFFF, 0x0000),
+        (0x0B, 0x0000, 0xFFFF),
+        (0x1B, 0x0000, 0xFFFF),
+        (0x2B, 0x0000, 0xFFFF),
+        (0x3B, 0x0000, 0xFFFF),
+        (0x03, 0x12FF, 0x1300),
+        (0x33, 0x00FF, 0x0100),
+        (0x0B, 0x1300, 0x12FF),
+        (0x3B, 0x0100, 0x00FF),
+    ];
+
+    #[test]
+    fn inc_dec_r16_wrap_and_leave_flags_alone() {
+        for (opcode, before, after) in INC_DEC_R16 {
+            for flags in [0x00, 0xF0, 0xA0, 0x50] {
+                let state = with_r16(CpuState::new().f(flags), opcode, before);
+                let (result, cycles) = run(&[opcode], state);
+                assert_eq!(r16(&result, opcode), after, "opcode {:02X}", opcode);
+                assert_eq!(result.f, flags, "opcode {:02X}", opcode);
+                assert_eq!(result.pc, CODE + 1);
+                assert_eq!(cycles, 2);
+            }
+        }
+    }
+
+    #[test]
+    fn inc_indir_half_carries_and_keeps_carry() {
+        for carry in [0x00, 0x10] {
+            let (result, value) = run_indir(0x34, 0x0F, CpuState::new().f(carry | 0x40));
+            assert_eq!(value, 0x10);
+            assert_eq!(result.f, 0x20 | carry);
+
+            let (result, value) = run_indir(0x34, 0xFF, CpuState::new().f(carry));
+            assert_eq!(value, 0x00);
+            assert_eq!(result.f, 0xA0 | carry);
+        }
+    }
+
+    #[test]
+    fn dec_indir_half_borrows_and_keeps_carry() {
+        for carry in [0x00, 0x10] {
+            let (result, value) = run_indir(0x35, 0x10, CpuState::new().f(carry));
+            assert_eq!(value, 0x0F);
+            assert_eq!(result.f, 0x60 | carry);
+
+            let (result, value) = run_indir(0x35, 0x01, CpuState::new().f(carry | 0x20));
+            assert_eq!(value, 0x00);
+            assert_eq!(result.f, 0xC0 | carry);
+        }
+    }
+
+    #[test]
+    fn prefixed_cycles_include_the_prefix_fetch() {
+        for opcode in 0x00..=0xFF {
+            let expected = match (opcode & 0x07, opcode & 0xC0) {
+                (0x06, 0x40) => 3,
+                (0x06, _) => 4,
+                _ => 2,
+            };
+            let state = CpuState::new().h(0xC1).l(0x00);
+            let (result, cycles) = run(&[0xCB, opcode], state);
+            assert_eq!(cycles, expected, "opcode CB {:02X}", opcode);
+            assert_eq!(result.pc, CODE + 2, "opcode CB {:02X}", opcode);
+        }
+    }
+
+    //jp cc, a16 with the flags that take it and the flags that don't
+    const JP_CONDITIONAL: [(u8, u8, u8); 4] = [
+        (0xC2, 0x00, 0x80),
+        (0xCA, 0x80, 0x00),
+        (0xD2, 0x00, 0x10),
+        (0xDA, 0x10, 0x00),
+    ];
+
+    #[test]
+    fn jp_conditional_taken_and_not_taken() {
+        for (opcode, taken, not_taken) in JP_CONDITIONAL {
+            let (result, cycles) = run(&[opcode, 0x34, 0x12], CpuState::new().f(taken));
+            assert_eq!(result.pc, 0x1234, "opcode {:02X}", opcode);
+            assert_eq!(cycles, 4, "opcode {:02X}", opcode);
+
+            let (result, cycles) = run(&[opcode, 0x34, 0x12], CpuState::new().f(not_taken));
+            assert_eq!(result.pc, CODE + 3, "opcode {:02X}", opcode);
+            assert_eq!(cycles, 3, "opcode {:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn jp_a16_is_always_taken() {
+        for flags in [0x00, 0xF0] {
+            let (result, cycles) = run(&[0xC3, 0x34, 0x12], CpuState::new().f(flags));
+            assert_eq!(result.pc, 0x1234);
+            assert_eq!(cycles, 4);
+        }
+    }
+
+    #[test]
+    fn ld_a_hl_inc_and_dec_wrap_hl() {
+        //ld a,(hl+) reads IE at 0xFFFF
+        let (mut cpu, mut memory) = setup(&[0x2A], CpuState::new().h(0xFF).l(0xFF));
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, 0x1F);
+        cpu.step(&mut memory);
+        let state = cpu.capture_state();
+        assert_eq!((state.a, state.h, state.l), (0x1F, 0x00, 0x00));
+
+        //ld a,(hl-) reads the blank rom at 0x0000
+        let (result, _) = run(&[0x3A], CpuState::new().a(0x55).h(0x00).l(0x00));
+        assert_eq!((result.a, result.h, result.l), (0x00, 0xFF, 0xFF));
+    }
+
+    #[test]
+    fn stop_skips_its_operand_and_resets_div() {
+        let (mut cpu, mut memory) = setup(&[0x10, 0x00], CpuState::new());
+        memory.step_timer(1000);
+        assert_ne!(memory.read_u8(DIV_ADDRESS), 0);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().pc, CODE + 2);
+        assert_eq!(memory.read_u8(DIV_ADDRESS), 0);
+        assert!(cpu.registers.stopped());
+    }
+
+    #[test]
+    fn pop_af_drops_the_low_nibble_of_f() {
+        let (mut cpu, mut memory) = setup(&[0xF1], CpuState::new().sp(0xC100));
+        memory.write_u16(0xC100, 0xFFFF);
+        cpu.step(&mut memory);
+        let state = cpu.capture_state();
+        assert_eq!((state.a, state.f, state.sp), (0xFF, 0xF0, 0xC102));
+    }
+
+    #[test]
+    fn push_and_pop_wrap_the_stack_pointer() {
+        //push bc from sp 0x0001 puts the high byte at 0x0000 where the rom ignores it
+        let (mut cpu, mut memory) = setup(&[0xC5], CpuState::new().b(0x12).c(0x34).sp(0x0001));
+        cpu.step(&mut memory);
+        assert_eq!(cpu.capture_state().sp, 0xFFFF);
+        assert_eq!(memory.read_u8(INTERRUPT_ENABLE_ADDRESS), 0x34);
+
+        //pop bc from 0xFFFF takes the low byte from IE and the high byte from the rom
+        let (mut cpu, mut memory) = setup(&[0xC1], CpuState::new().sp(0xFFFF));
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, 0x1F);
+        cpu.step(&mut memory);
+        let state = cpu.capture_state();
+        assert_eq!((state.b, state.c, state.sp), (0x00, 0x1F, 0x0001));
+    }
+
+    #[test]
+    fn subtraction_half_borrows_out_of_the_low_nibble() {
+        assert!(super::check_for_half_borrow_8bit(0x10, 0x01));
+        assert!(!super::check_for_half_borrow_8bit(0x1F, 0x01));
+        assert!(super::check_for_half_borrow_with_carry_8bit(0x11, 0x01, 1));
+        assert!(!super::check_for_half_borrow_with_carry_8bit(0x12, 0x01, 1));
+
+        //sub d8, cp d8 and sbc d8 with the carry clear all take 0x01 from a
+        for opcode in [0xD6, 0xFE, 0xDE] {
+            let half_carry =
+                |a: u8| run(&[opcode, 0x01], CpuState::new().a(a)).0.f & HALF_CARRY_FLAG;
+            assert_eq!(half_carry(0x10), HALF_CARRY_FLAG, "{:02x}", opcode);
+            assert_eq!(half_carry(0x1F), 0, "{:02x}", opcode);
+        }
+        //dec a
+        assert_eq!(
+            run(&[0x3D], CpuState::new().a(0x10)).0.f & HALF_CARRY_FLAG,
+            HALF_CARRY_FLAG
+        );
+        assert_eq!(
+            run(&[0x3D], CpuState::new().a(0x1F)).0.f & HALF_CARRY_FLAG,
+            0
+        );
+    }
+}
@@ -0,0 +1,98 @@
+pub const JOYPAD_ADDRESS: u16 = 0xFF00;
+
+const SELECT_DIRECTIONS: u8 = 0x10;
+const SELECT_ACTIONS: u8 = 0x20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+#[derive(Clone)]
+pub struct Joypad {
+    //Bits 4 and 5 of P1 pick which group of buttons shows up in the low nibble
+    select: u8,
+    //Directions in the low nibble and actions in the high nibble, set bits are held
+    pressed: u8,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    fn mask(self) -> u8 {
+        match self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        }
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+            pressed: 0,
+        }
+    }
+
+    // P1 is active low so a 0 means selected or pressed
+    // Bit 5 - Select action buttons    (0=Select)
+    // Bit 4 - Select direction buttons (0=Select)
+    // Bit 3 - Down or Start
+    // Bit 2 - Up or Select
+    // Bit 1 - Left or B
+    // Bit 0 - Right or A
+    pub fn read(&self) -> u8 {
+        let mut held = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            held |= self.pressed & 0x0F;
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            held |= self.pressed >> 4;
+        }
+        0xC0 | self.select | (!held & 0x0F)
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.select = value & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+    }
+
+    //Returns true if the button wasn't already held
+    pub fn press(&mut self, button: Button) -> bool {
+        let newly_pressed = self.pressed & button.mask() == 0;
+        self.pressed |= button.mask();
+        newly_pressed
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.pressed &= !button.mask();
+    }
+}
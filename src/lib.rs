@@ -0,0 +1,28 @@
+//The emulator core. Frontends either drive a GameBoy themselves with run_frame and inject_input
+//or hand Emu a Display, the sdl feature adds the window, keyboard and audio one
+pub mod apu;
+pub mod cartridge;
+pub mod cheats;
+pub mod cpu;
+pub mod cpu_state;
+pub mod display;
+pub mod emu;
+pub mod error;
+pub mod gameboy;
+pub mod instruction_data;
+pub mod instructions;
+pub mod joypad;
+pub mod link;
+pub mod mbc;
+pub mod memory;
+pub mod overlay;
+pub mod png;
+pub mod ppu;
+pub mod registers;
+pub mod rewind;
+#[cfg(feature = "sdl")]
+pub mod sdl;
+pub mod timer;
+pub mod watchdog;
+
+pub type Result<T> = std::result::Result<T, error::EmuError>;
@@ -0,0 +1,45 @@
+use crate::gameboy::GameBoy;
+
+//Plugs the cable in, transfers on either side then wait for a clock instead of finishing straight
+//away
+pub fn connect(first: &mut GameBoy, second: &mut GameBoy) {
+    first.memory.connect_link(true);
+    second.memory.connect_link(true);
+}
+
+//Runs two gameboys connected with connect until both have finished a frame. They take turns a
+//batch at a time so neither gets more than a batch ahead of the other
+pub fn run_linked_frame(
+    first: &mut GameBoy,
+    second: &mut GameBoy,
+    first_pixels: &mut [u8],
+    second_pixels: &mut [u8],
+) {
+    let mut first_done = false;
+    let mut second_done = false;
+    while !(first_done && second_done) {
+        //Whichever finishes first waits for the other so the frames line up
+        if !first_done {
+            first_done = first.step(first_pixels).1;
+        }
+        if !second_done {
+            second_done = second.step(second_pixels).1;
+        }
+        exchange_bits(first, second);
+        exchange_bits(second, first);
+    }
+}
+
+//Every clock pulse from the side using its internal clock swaps one bit each way. A side that
+//hasn't started a transfer of its own doesn't shift and its end of the cable reads as held high
+fn exchange_bits(clocking: &mut GameBoy, other: &mut GameBoy) {
+    for _ in 0..clocking.memory.take_serial_clocks() {
+        let other_transferring = other.memory.serial_transferring();
+        let other_bit = !other_transferring || other.memory.serial_out_bit();
+        let clocking_bit = clocking.memory.serial_out_bit();
+        clocking.memory.shift_serial(other_bit);
+        if other_transferring {
+            other.memory.shift_serial(clocking_bit);
+        }
+    }
+}
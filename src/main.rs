@@ -1,27 +1,359 @@
-mod cpu;
-mod gameboy;
-mod instruction_data;
-mod instructions;
-mod memory;
-mod ppu;
-mod registers;
-mod sdl;
-
 use log::info;
 
-use crate::{gameboy::GameBoy, memory::RomChunk, sdl::Emu};
+use rust_boi::{
+    cartridge::CartridgeHeader,
+    display::DEFAULT_SCALE,
+    emu::Emu,
+    error::EmuError,
+    gameboy::{GameBoy, Model, MAX_BATCH_SIZE},
+    instructions::InstructionCategory,
+    memory::{RamFill, RomChunk},
+    rewind::{DEFAULT_REWIND_DEPTH, DEFAULT_REWIND_INTERVAL},
+    Result,
+};
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+const USAGE: &str =
+    "usage: rust_boi [--boot <boot rom>] [--dmg] [--blend] [--scale <window scale>] \
+                     [--screenshots <dir>] [--batch <instructions>] [--rewind <snapshots>] \
+                     [--rewind-interval <frames>] [--cheat <code>]... [--debug] [--turbo-boot] \
+                     [--ram-fill <zero|ff|hex pattern>] [--no-tile-cache] [rom]";
+const DEFAULT_ROM: &str = "roms/Tetris.gb";
 
-use std::{error, path::Path};
+// Set this to log every instruction along with the registers before and after it
+const TRACE_ENV: &str = "RUST_BOI_TRACE";
+// Set this to a range of addresses in hex like 0150-01FF to print the rom's code there and exit
+const DISASSEMBLE_ENV: &str = "RUST_BOI_DISASSEMBLE";
+// Set this to log every write to LCDC and STAT with the pc that made it
+const LOG_LCD_WRITES_ENV: &str = "RUST_BOI_LOG_LCD_WRITES";
+// Set this to log every ppu mode change with its line and dot, to line up with the LCD writes
+const LOG_PPU_MODES_ENV: &str = "RUST_BOI_LOG_PPU_MODES";
+// Set this to a comma separated list of loads, jumps, arithmetic, prefixed and misc to only log
+// those instructions
+const LOG_CATEGORIES_ENV: &str = "RUST_BOI_LOG_CATEGORIES";
+// Set this to a number of cpu cycles to warn with the recent opcodes when that many pass without a vblank
+const WATCHDOG_ENV: &str = "RUST_BOI_WATCHDOG";
+// Set this to the most instructions a frame can run before giving up on it, 0 turns the cap off
+const INSTRUCTION_CAP_ENV: &str = "RUST_BOI_INSTRUCTION_CAP";
 
-type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+struct Args {
+    //Without one the game starts straight away from the state the boot rom would leave behind
+    boot_rom: Option<PathBuf>,
+    rom: PathBuf,
+    //--dmg runs CGB enhanced roms the way an original gameboy would
+    model: Model,
+    //Start with frame blending on, it can still be toggled with B
+    frame_blend: bool,
+    scale: u32,
+    screenshot_dir: Option<PathBuf>,
+    //Instructions to run between ppu updates, more is faster but less accurate
+    batch_size: u32,
+    //Snapshots kept for rewinding and how many frames apart they are, 0 snapshots turns it off
+    rewind_depth: usize,
+    rewind_interval: u32,
+    //Game Genie and GameShark codes, --cheat can be given more than once
+    cheats: Vec<String>,
+    //Start paused so the game can be stepped through one instruction at a time with S
+    debug: bool,
+    //Run the boot rom as fast as possible and only slow down once the game starts
+    turbo_boot: bool,
+    //What ram holds at power on
+    ram_fill: RamFill,
+    //--no-tile-cache decodes every tile as it is drawn instead of keeping the decoded ones around
+    tile_cache: bool,
+}
+
+impl Args {
+    //Takes the arguments without the program name
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut boot_rom = None;
+        let mut rom = None;
+        let mut model = Model::Cgb;
+        let mut frame_blend = false;
+        let mut scale = DEFAULT_SCALE;
+        let mut screenshot_dir = None;
+        let mut batch_size = 1;
+        let mut rewind_depth = DEFAULT_REWIND_DEPTH;
+        let mut rewind_interval = DEFAULT_REWIND_INTERVAL;
+        let mut cheats = Vec::new();
+        let mut debug = false;
+        let mut turbo_boot = false;
+        let mut ram_fill = RamFill::Zero;
+        let mut tile_cache = true;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--boot" => {
+                    boot_rom = Some(args.next().map(PathBuf::from).ok_or_else(|| {
+                        EmuError::InvalidArgument("--boot needs the path to a boot rom".to_string())
+                    })?);
+                }
+                "--dmg" => model = Model::Dmg,
+                "--blend" => frame_blend = true,
+                "--debug" => debug = true,
+                "--turbo-boot" => turbo_boot = true,
+                "--no-tile-cache" => tile_cache = false,
+                "--scale" => {
+                    scale = args
+                        .next()
+                        .and_then(|scale| scale.parse().ok())
+                        .filter(|scale| *scale > 0)
+                        .ok_or_else(|| {
+                            EmuError::InvalidArgument(
+                                "--scale needs a whole number above 0".to_string(),
+                            )
+                        })?;
+                }
+                "--screenshots" => {
+                    screenshot_dir = Some(args.next().map(PathBuf::from).ok_or_else(|| {
+                        EmuError::InvalidArgument(
+                            "--screenshots needs a directory to save to".to_string(),
+                        )
+                    })?);
+                }
+                "--batch" => {
+                    batch_size = args
+                        .next()
+                        .and_then(|batch_size| batch_size.parse().ok())
+                        .filter(|batch_size| (1..=MAX_BATCH_SIZE).contains(batch_size))
+                        .ok_or_else(|| {
+                            EmuError::InvalidArgument(format!(
+                                "--batch needs a whole number from 1 to {}",
+                                MAX_BATCH_SIZE
+                            ))
+                        })?;
+                }
+                "--rewind" => {
+                    rewind_depth = args
+                        .next()
+                        .and_then(|depth| depth.parse().ok())
+                        .ok_or_else(|| {
+                            EmuError::InvalidArgument(
+                                "--rewind needs a number of snapshots to keep".to_string(),
+                            )
+                        })?;
+                }
+                "--rewind-interval" => {
+                    rewind_interval = args
+                        .next()
+                        .and_then(|interval| interval.parse().ok())
+                        .filter(|interval| *interval > 0)
+                        .ok_or_else(|| {
+                            EmuError::InvalidArgument(
+                                "--rewind-interval needs a whole number of frames above 0"
+                                    .to_string(),
+                            )
+                        })?;
+                }
+                "--cheat" => {
+                    cheats.push(args.next().ok_or_else(|| {
+                        EmuError::InvalidArgument("--cheat needs a cheat code".to_string())
+                    })?);
+                }
+                "--ram-fill" => {
+                    ram_fill = args
+                        .next()
+                        .and_then(|fill| RamFill::parse(&fill))
+                        .ok_or_else(|| {
+                            EmuError::InvalidArgument(
+                                "--ram-fill needs zero, ff or hex bytes to repeat like 00ff"
+                                    .to_string(),
+                            )
+                        })?;
+                }
+                option if option.starts_with("--") => {
+                    return Err(EmuError::InvalidArgument(format!(
+                        "unknown option {}",
+                        option
+                    )));
+                }
+                path if rom.is_none() => rom = Some(PathBuf::from(path)),
+                extra => {
+                    return Err(EmuError::InvalidArgument(format!(
+                        "unexpected argument {}",
+                        extra
+                    )));
+                }
+            }
+        }
+        //Without a boot rom the game starts straight away so there is nothing to speed through
+        if turbo_boot && boot_rom.is_none() {
+            return Err(EmuError::InvalidArgument(
+                "--turbo-boot needs a boot rom given with --boot".to_string(),
+            ));
+        }
+        Ok(Self {
+            boot_rom,
+            rom: rom.unwrap_or_else(|| PathBuf::from(DEFAULT_ROM)),
+            model,
+            frame_blend,
+            scale,
+            screenshot_dir,
+            batch_size,
+            rewind_depth,
+            rewind_interval,
+            cheats,
+            debug,
+            turbo_boot,
+            ram_fill,
+            tile_cache,
+        })
+    }
+}
 
 fn main() {
     env_logger::init();
     info!("starting up");
-    let boot_rom = RomChunk::new(Some(Path::new("roms/dmg_rom.bin"))).unwrap();
-    // let cart_rom = RomChunk::new(Some(Path::new("roms/test_roms/cpu_instrs/cpu_instrs.gb"))).unwrap();
-    let cart_rom = RomChunk::new(Some(Path::new("roms/Tetris.gb"))).unwrap();
-    let gameboy = GameBoy::new(boot_rom, cart_rom);
+    if let Err(error) = run() {
+        eprintln!("rust_boi: {}", error);
+        if let EmuError::InvalidArgument(_) = error {
+            eprintln!("{}", USAGE);
+        }
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
+    let args = Args::parse(std::env::args().skip(1))?;
+    let boot_rom = args
+        .boot_rom
+        .as_deref()
+        .map(|path| RomChunk::new(Some(path)))
+        .transpose()?;
+    if let Ok(range) = std::env::var(DISASSEMBLE_ENV) {
+        let listing = disassemble(&args, &range)?;
+        std::io::stdout()
+            .write_all(listing.as_bytes())
+            .map_err(|error| EmuError::from_io(PathBuf::from("stdout"), error))?;
+        return Ok(());
+    }
+    let cart_rom = RomChunk::new(Some(&args.rom))?;
+    let mut gameboy = GameBoy::new_with_fill(boot_rom, cart_rom, args.model, &args.ram_fill)?;
+    let save_path = save_path(&args.rom, gameboy.memory.header());
+    gameboy.cpu.set_trace(std::env::var(TRACE_ENV).is_ok());
+    gameboy.set_batch_size(args.batch_size);
+    set_watchdog(&mut gameboy)?;
+    set_instruction_cap(&mut gameboy)?;
+    set_logged_categories(&mut gameboy)?;
+    gameboy.set_lcd_write_logging(std::env::var(LOG_LCD_WRITES_ENV).is_ok());
+    gameboy.set_ppu_mode_logging(std::env::var(LOG_PPU_MODES_ENV).is_ok());
+    gameboy.ppu.set_tile_cache(args.tile_cache);
+    for cheat in &args.cheats {
+        gameboy.memory.add_cheat(cheat)?;
+    }
+    if gameboy.memory.has_battery() && save_path.exists() {
+        gameboy.memory.load_save(&save_path)?;
+    }
     let mut emu = Emu::new();
-    emu.run(gameboy);
+    emu.set_frame_blend(args.frame_blend);
+    emu.set_paused(args.debug);
+    emu.set_turbo_boot(args.turbo_boot);
+    emu.set_rewind(args.rewind_depth, args.rewind_interval);
+    if let Some(screenshot_dir) = args.screenshot_dir {
+        emu.set_screenshot_dir(screenshot_dir);
+    }
+    run_window(&mut emu, &mut gameboy, args.scale)?;
+    if gameboy.memory.has_battery() {
+        gameboy.memory.write_save(&save_path)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sdl")]
+fn run_window(emu: &mut Emu, gameboy: &mut GameBoy, scale: u32) -> Result<()> {
+    rust_boi::sdl::run(
+        emu,
+        gameboy,
+        rust_boi::sdl::KeyMap::default(),
+        rust_boi::sdl::PadMap::default(),
+        scale,
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "sdl"))]
+fn run_window(_emu: &mut Emu, _gameboy: &mut GameBoy, _scale: u32) -> Result<()> {
+    Err(EmuError::InvalidArgument(
+        "built without the sdl feature so only the headless modes can run".to_string(),
+    ))
+}
+
+fn set_watchdog(gameboy: &mut GameBoy) -> Result<()> {
+    if let Ok(budget) = std::env::var(WATCHDOG_ENV) {
+        let budget = budget
+            .parse()
+            .ok()
+            .filter(|budget| *budget > 0)
+            .ok_or_else(|| {
+                EmuError::InvalidArgument(format!(
+                    "{} must be a whole number above 0",
+                    WATCHDOG_ENV
+                ))
+            })?;
+        gameboy.set_watchdog(budget);
+    }
+    Ok(())
+}
+
+fn set_instruction_cap(gameboy: &mut GameBoy) -> Result<()> {
+    if let Ok(cap) = std::env::var(INSTRUCTION_CAP_ENV) {
+        let cap = cap.parse().map_err(|_| {
+            EmuError::InvalidArgument(format!("{} must be a whole number", INSTRUCTION_CAP_ENV))
+        })?;
+        gameboy.set_instruction_cap(cap);
+    }
+    Ok(())
+}
+
+fn disassemble(args: &Args, range: &str) -> Result<String> {
+    let (start, end) = parse_address_range(range).ok_or_else(|| {
+        EmuError::InvalidArgument(format!(
+            "{} must be a range of hex addresses like 0150-01FF",
+            DISASSEMBLE_ENV
+        ))
+    })?;
+    let gameboy = GameBoy::new(None, RomChunk::new(Some(&args.rom))?, args.model)?;
+    Ok(gameboy.disassemble_rom(start, end))
+}
+
+fn parse_address_range(range: &str) -> Option<(u16, u16)> {
+    let (start, end) = range.split_once('-')?;
+    let start = u16::from_str_radix(start.trim(), 16).ok()?;
+    let end = u16::from_str_radix(end.trim(), 16).ok()?;
+    (start <= end).then_some((start, end))
+}
+
+fn set_logged_categories(gameboy: &mut GameBoy) -> Result<()> {
+    if let Ok(names) = std::env::var(LOG_CATEGORIES_ENV) {
+        let categories = names
+            .split(',')
+            .map(|name| {
+                InstructionCategory::from_name(name.trim()).ok_or_else(|| {
+                    EmuError::InvalidArgument(format!(
+                        "unknown instruction category {} in {}",
+                        name, LOG_CATEGORIES_ENV
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        gameboy.cpu.set_logged_categories(&categories);
+    }
+    Ok(())
+}
+
+//Saves sit next to the rom named after the game and its checksum so revisions with the same title
+//don't share one. Untitled roms and saves left over from before that use the rom's name instead
+fn save_path(rom: &Path, header: &CartridgeHeader) -> PathBuf {
+    let rom_named = rom.with_extension("sav");
+    if header.title.is_empty() || rom_named.exists() {
+        return rom_named;
+    }
+    rom.with_file_name(format!(
+        "{}-{:04X}.sav",
+        header.title.replace(['/', '\\'], "_"),
+        header.global_checksum
+    ))
 }
@@ -0,0 +1,461 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::error::EmuError;
+use crate::memory::{END_OF_FIXED_ROM, RAM_BANK_SIZE, START_OF_CARTRIDGE_RAM};
+use crate::Result;
+
+// Ram bank values that map an rtc register into the cartridge ram window instead
+const RTC_SECONDS: u8 = 0x08;
+const RTC_DAY_HIGH: u8 = 0x0C;
+const RTC_REGISTERS: usize = 5;
+// Bits of the rtc day high register
+const RTC_DAY_HIGH_BIT: u8 = 0x01;
+const RTC_HALT: u8 = 0x40;
+const RTC_DAY_CARRY: u8 = 0x80;
+// Current registers and latched registers as u32s followed by a u64 timestamp, which is the
+// layout most other emulators append to their .sav files as well
+const RTC_SAVE_SIZE: usize = RTC_REGISTERS * 4 * 2 + 8;
+
+// Cartridge ram only answers once this is written to the low nibble of the ram enable register,
+// anything else turns it back off
+const RAM_ENABLE: u8 = 0x0A;
+// What the cpu reads from the ram window while the ram is turned off
+const DISABLED_RAM_VALUE: u8 = 0xFF;
+
+// MBC2 has 512 half bytes of ram built in instead of ram chips on the cartridge
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const MAX_DAYS: u64 = 512;
+
+//Seconds since the unix epoch, the rtc counts from whichever one it is given
+pub type Clock = fn() -> u64;
+
+//The memory bank controller on the cartridge, picks which rom and ram banks the cpu can see
+#[derive(Clone)]
+pub enum Mbc {
+    RomOnly,
+    Mbc2(Mbc2),
+    Mbc3(Mbc3),
+}
+
+#[derive(Clone)]
+pub struct Mbc2 {
+    rom_bank: u8,
+    ram_enabled: bool,
+}
+
+#[derive(Clone)]
+pub struct Mbc3 {
+    rom_bank: u8,
+    ram_bank: u8,
+    //Covers the rtc registers as well as the ram
+    ram_enabled: bool,
+    //Latching takes a write of 0 followed by a write of 1
+    latch_armed: bool,
+    rtc: Option<Rtc>,
+}
+
+//MBC3 clock which keeps counting from the host's clock even while the emulator isn't running
+#[derive(Clone)]
+pub struct Rtc {
+    clock: Clock,
+    //Unix time the counter would have started from to reach its current value
+    start: u64,
+    //While halted the counter is frozen at this many seconds
+    halted_at: Option<u64>,
+    day_carry: bool,
+    latched: [u8; RTC_REGISTERS],
+}
+
+impl Mbc {
+    pub fn new(cartridge_type: u8) -> Result<Self> {
+        match cartridge_type {
+            0x00 | 0x08 | 0x09 => Ok(Mbc::RomOnly),
+            //MBC1 isn't implemented but 32KB MBC1 roms like the test roms never switch banks
+            0x01..=0x03 => {
+                warn!(
+                    "MBC1 cartridge type {:x} isn't supported, running without banking",
+                    cartridge_type
+                );
+                Ok(Mbc::RomOnly)
+            }
+            0x05 | 0x06 => Ok(Mbc::Mbc2(Mbc2 {
+                rom_bank: 1,
+                ram_enabled: false,
+            })),
+            0x0F..=0x13 => Ok(Mbc::Mbc3(Mbc3::new(matches!(cartridge_type, 0x0F | 0x10)))),
+            _ => Err(EmuError::UnsupportedMapper(cartridge_type)),
+        }
+    }
+
+    //The bank mapped into 0x4000-0x7FFF
+    pub fn rom_bank(&self) -> usize {
+        match self {
+            Mbc::RomOnly => 1,
+            Mbc::Mbc2(mbc) => mbc.rom_bank as usize,
+            Mbc::Mbc3(mbc) => mbc.rom_bank as usize,
+        }
+    }
+
+    //Writes into the rom area don't change the rom they set the controller's registers
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match self {
+            Mbc::RomOnly => {}
+            Mbc::Mbc2(mbc) => mbc.write_register(address, value),
+            Mbc::Mbc3(mbc) => mbc.write_register(address, value),
+        }
+    }
+
+    pub fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        match self {
+            Mbc::RomOnly => read_ram_bank(ram, 0, address),
+            Mbc::Mbc2(mbc) => mbc.read_ram(ram, address),
+            Mbc::Mbc3(mbc) => mbc.read_ram(ram, address),
+        }
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        match self {
+            Mbc::RomOnly => write_ram_bank(ram, 0, address, value),
+            Mbc::Mbc2(mbc) => mbc.write_ram(ram, address, value),
+            Mbc::Mbc3(mbc) => mbc.write_ram(ram, address, value),
+        }
+    }
+
+    //Anything beyond the cartridge ram that needs to go in the .sav file
+    pub fn save_data(&self) -> Vec<u8> {
+        match self {
+            Mbc::Mbc3(Mbc3 { rtc: Some(rtc), .. }) => rtc.save_data(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        if let Mbc::Mbc3(Mbc3 { rtc: Some(rtc), .. }) = self {
+            rtc.load_save_data(data);
+        }
+    }
+
+    //Only matters for cartridges with a clock, anything else ignores it
+    pub fn set_clock(&mut self, clock: Clock) {
+        if let Mbc::Mbc3(Mbc3 { rtc: Some(rtc), .. }) = self {
+            rtc.set_clock(clock);
+        }
+    }
+}
+
+//Whether the cartridge keeps its ram (and clock) powered so it needs a .sav file
+pub fn has_battery(cartridge_type: u8) -> bool {
+    matches!(cartridge_type, 0x03 | 0x06 | 0x09 | 0x0F | 0x10 | 0x13)
+}
+
+fn ram_offset(bank: usize, address: u16) -> usize {
+    bank * RAM_BANK_SIZE + (address - START_OF_CARTRIDGE_RAM) as usize
+}
+
+fn read_ram_bank(ram: &[u8], bank: usize, address: u16) -> u8 {
+    if ram.is_empty() {
+        return 0xFF;
+    }
+    ram[ram_offset(bank, address) % ram.len()]
+}
+
+fn write_ram_bank(ram: &mut [u8], bank: usize, address: u16, value: u8) {
+    if ram.is_empty() {
+        return;
+    }
+    let len = ram.len();
+    ram[ram_offset(bank, address) % len] = value;
+}
+
+impl Mbc2 {
+    fn write_register(&mut self, address: u16, value: u8) {
+        //Only the bottom half of rom has registers and bit 8 of the address picks which one
+        if address > END_OF_FIXED_ROM {
+            return;
+        }
+        if address & 0x0100 != 0 {
+            self.rom_bank = (value & 0x0F).max(1);
+        } else {
+            self.ram_enabled = value & 0x0F == RAM_ENABLE;
+        }
+    }
+
+    //Only the low 4 bits of each byte exist and the 512 of them repeat through the whole window
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled {
+            return DISABLED_RAM_VALUE;
+        }
+        read_ram_bank(ram, 0, address) & 0x0F
+    }
+
+    fn write_ram(&self, ram: &mut [u8], address: u16, value: u8) {
+        if self.ram_enabled {
+            write_ram_bank(ram, 0, address, value & 0x0F);
+        }
+    }
+}
+
+impl Mbc3 {
+    fn new(has_rtc: bool) -> Self {
+        Self {
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            latch_armed: false,
+            rtc: if has_rtc { Some(Rtc::new()) } else { None },
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == RAM_ENABLE,
+            //Bank 0 can't be mapped into the switchable area so it becomes bank 1
+            0x2000..=0x3FFF => self.rom_bank = (value & 0x7F).max(1),
+            0x4000..=0x5FFF => self.ram_bank = value,
+            _ => {
+                if self.latch_armed && value == 0x01 {
+                    if let Some(rtc) = &mut self.rtc {
+                        rtc.latch();
+                    }
+                }
+                self.latch_armed = value == 0x00;
+            }
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled {
+            return DISABLED_RAM_VALUE;
+        }
+        match (self.ram_bank, &self.rtc) {
+            (RTC_SECONDS..=RTC_DAY_HIGH, Some(rtc)) => rtc.read(self.ram_bank),
+            (bank, _) => read_ram_bank(ram, (bank & 0x03) as usize, address),
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match (self.ram_bank, &mut self.rtc) {
+            (RTC_SECONDS..=RTC_DAY_HIGH, Some(rtc)) => rtc.write(self.ram_bank, value),
+            (bank, _) => write_ram_bank(ram, (bank & 0x03) as usize, address, value),
+        }
+    }
+}
+
+//The host's clock, which is the default for every rtc
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub fn system_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+//There is no clock in std on plain wasm and asking for one panics, so the rtc stands still until
+//the frontend hands in one of its own with Memory::set_rtc_clock
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub fn system_clock() -> u64 {
+    0
+}
+
+impl Rtc {
+    fn new() -> Self {
+        Self {
+            clock: system_clock,
+            start: system_clock(),
+            halted_at: None,
+            day_carry: false,
+            latched: [0; RTC_REGISTERS],
+        }
+    }
+
+    fn seconds(&self) -> u64 {
+        match self.halted_at {
+            Some(seconds) => seconds,
+            None => (self.clock)().saturating_sub(self.start),
+        }
+    }
+
+    //Carries on counting from where the old clock had got to
+    fn set_clock(&mut self, clock: Clock) {
+        let seconds = self.seconds();
+        self.clock = clock;
+        if self.halted_at.is_none() {
+            self.start = clock().saturating_sub(seconds);
+        }
+    }
+
+    //Seconds, minutes, hours, low 8 bits of the day then the day high register
+    fn registers(&self) -> [u8; RTC_REGISTERS] {
+        let seconds = self.seconds();
+        let days = seconds / SECONDS_PER_DAY;
+        let mut day_high = ((days >> 8) as u8) & RTC_DAY_HIGH_BIT;
+        if self.halted_at.is_some() {
+            day_high |= RTC_HALT;
+        }
+        //The carry sticks around once the 9 bit day counter overflows until it is written
+        if self.day_carry || days >= MAX_DAYS {
+            day_high |= RTC_DAY_CARRY;
+        }
+        [
+            (seconds % 60) as u8,
+            (seconds / 60 % 60) as u8,
+            (seconds / 3600 % 24) as u8,
+            days as u8,
+            day_high,
+        ]
+    }
+
+    //Rebuilds the counter from register values which are taken as being the time at `now`
+    fn set_registers(&mut self, registers: [u8; RTC_REGISTERS], now: u64) {
+        let days = registers[3] as u64 | ((registers[4] & RTC_DAY_HIGH_BIT) as u64) << 8;
+        let seconds = days * SECONDS_PER_DAY
+            + (registers[2] & 0x1F) as u64 * 3600
+            + (registers[1] & 0x3F) as u64 * 60
+            + (registers[0] & 0x3F) as u64;
+        self.day_carry = registers[4] & RTC_DAY_CARRY != 0;
+        if registers[4] & RTC_HALT != 0 {
+            self.halted_at = Some(seconds);
+        } else {
+            self.halted_at = None;
+            self.start = now.saturating_sub(seconds);
+        }
+    }
+
+    fn latch(&mut self) {
+        self.latched = self.registers();
+    }
+
+    //Reads come from the latched copy so the value can't change halfway through reading it
+    fn read(&self, register: u8) -> u8 {
+        self.latched[(register - RTC_SECONDS) as usize]
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        let mut registers = self.registers();
+        registers[(register - RTC_SECONDS) as usize] = value;
+        self.set_registers(registers, (self.clock)());
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(RTC_SAVE_SIZE);
+        for register in self.registers().iter().chain(self.latched.iter()) {
+            data.extend((*register as u32).to_le_bytes());
+        }
+        data.extend((self.clock)().to_le_bytes());
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        if data.len() < RTC_SAVE_SIZE {
+            warn!("Save file has no clock data, starting the clock from zero");
+            return;
+        }
+        let mut registers = [0; RTC_REGISTERS * 2];
+        for (i, register) in registers.iter_mut().enumerate() {
+            *register = data[i * 4];
+        }
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&data[RTC_REGISTERS * 8..RTC_SAVE_SIZE]);
+        //Picking up from when the save was written means the clock kept running while we were off
+        let saved_at = u64::from_le_bytes(timestamp);
+        let mut current = [0; RTC_REGISTERS];
+        current.copy_from_slice(&registers[..RTC_REGISTERS]);
+        self.set_registers(current, saved_at);
+        self.latched.copy_from_slice(&registers[RTC_REGISTERS..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static NOW: AtomicU64 = AtomicU64::new(1_000_000);
+
+    fn test_clock() -> u64 {
+        NOW.load(Ordering::Relaxed)
+    }
+
+    //Latches the clock and reads back the seconds and minutes as a count of seconds
+    fn latched_seconds(mbc: &mut Mbc) -> u64 {
+        mbc.write_register(0x6000, 0x00);
+        mbc.write_register(0x6000, 0x01);
+        mbc.write_register(0x4000, RTC_SECONDS);
+        let seconds = mbc.read_ram(&[], START_OF_CARTRIDGE_RAM) as u64;
+        mbc.write_register(0x4000, RTC_SECONDS + 1);
+        let minutes = mbc.read_ram(&[], START_OF_CARTRIDGE_RAM) as u64;
+        minutes * 60 + seconds
+    }
+
+    //Runs through enabling and disabling ram and hands back what the byte at the start of it read
+    //as before enabling, after writing 0x5A and after disabling again
+    fn ram_gating(cartridge_type: u8, ram_size: usize) -> [u8; 3] {
+        let mut mbc = Mbc::new(cartridge_type).unwrap();
+        let mut ram = vec![0; ram_size];
+        //Writes while disabled go nowhere
+        mbc.write_ram(&mut ram, START_OF_CARTRIDGE_RAM, 0x33);
+        let before = mbc.read_ram(&ram, START_OF_CARTRIDGE_RAM);
+        mbc.write_register(0x0000, RAM_ENABLE);
+        mbc.write_ram(&mut ram, START_OF_CARTRIDGE_RAM, 0x5A);
+        let enabled = mbc.read_ram(&ram, START_OF_CARTRIDGE_RAM);
+        mbc.write_register(0x0000, 0x00);
+        let after = mbc.read_ram(&ram, START_OF_CARTRIDGE_RAM);
+        assert!(ram.iter().all(|byte| *byte != 0x33));
+        [before, enabled, after]
+    }
+
+    #[test]
+    fn mbc2_ram_reads_ff_until_enabled() {
+        //Only the low nibble of mbc2 ram exists
+        assert_eq!(ram_gating(0x06, MBC2_RAM_SIZE), [0xFF, 0x0A, 0xFF]);
+    }
+
+    #[test]
+    fn mbc2_ram_keeps_the_low_nibble_and_repeats() {
+        let mut mbc = Mbc::new(0x06).unwrap();
+        let mut ram = vec![0; MBC2_RAM_SIZE];
+        mbc.write_register(0x0000, RAM_ENABLE);
+        mbc.write_ram(&mut ram, START_OF_CARTRIDGE_RAM, 0xFF);
+        assert_eq!(mbc.read_ram(&ram, START_OF_CARTRIDGE_RAM), 0x0F);
+        assert_eq!(mbc.read_ram(&ram, START_OF_CARTRIDGE_RAM + 0x200), 0x0F);
+    }
+
+    #[test]
+    fn mbc2_address_bit_8_picks_the_register() {
+        let mut mbc = Mbc::new(0x05).unwrap();
+        //Bit 8 clear is the ram enable even in the upper half of the register area
+        mbc.write_register(0x2000, 0x03);
+        assert_eq!(mbc.rom_bank(), 1);
+        mbc.write_register(0x2100, 0x03);
+        assert_eq!(mbc.rom_bank(), 3);
+        mbc.write_register(0x0100, 0x00);
+        assert_eq!(mbc.rom_bank(), 1);
+        mbc.write_register(0x0000, RAM_ENABLE);
+        assert_eq!(
+            mbc.read_ram(&[0x05; MBC2_RAM_SIZE], START_OF_CARTRIDGE_RAM),
+            0x05
+        );
+    }
+
+    #[test]
+    fn mbc3_ram_reads_ff_until_enabled() {
+        assert_eq!(ram_gating(0x13, 4 * RAM_BANK_SIZE), [0xFF, 0x5A, 0xFF]);
+    }
+
+    #[test]
+    fn rtc_counts_from_the_injected_clock() {
+        let mut mbc = Mbc::new(0x10).unwrap();
+        mbc.write_register(0x0000, RAM_ENABLE);
+        mbc.set_clock(test_clock);
+        let start = latched_seconds(&mut mbc);
+        NOW.fetch_add(65, Ordering::Relaxed);
+        assert_eq!(latched_seconds(&mut mbc), start + 65);
+    }
+}
@@ -1,119 +1,372 @@
-use std::{fs::File, io::Read, path::Path};
+use std::{collections::VecDeque, fs, path::Path, rc::Rc};
 
+use log::{info, warn};
+
+use crate::apu::{APU_ENABLED, FIRST_SOUND_REGISTER, LAST_SOUND_REGISTER, NR52, NR52_UNUSED_BITS};
+use crate::cartridge::CartridgeHeader;
+use crate::cheats::Cheats;
+use crate::error::EmuError;
+use crate::gameboy::Model;
+use crate::joypad::{Button, Joypad, JOYPAD_ADDRESS};
+use crate::mbc::{self, Mbc};
+use crate::ppu::STAT;
+use crate::timer::{Timer, DIV_ADDRESS};
 use crate::Result;
 
-const RAM_BANK_SIZE: usize = 0x2000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
 const START_OF_FIXED_ROM: u16 = 0x0000;
 const END_OF_BOOT: u16 = 0xFF;
-const END_OF_FIXED_ROM: u16 = 0x3FFF;
+pub const END_OF_FIXED_ROM: u16 = 0x3FFF;
 const START_OF_BANKED_ROM: u16 = 0x4000;
 const END_OF_BANKED_ROM: u16 = 0x7FFF;
 const START_OF_VRAM: u16 = 0x8000;
 const END_OF_VRAM: u16 = 0x9FFF;
-const START_OF_CARTRIDGE_RAM: u16 = 0xA000;
+pub const START_OF_CARTRIDGE_RAM: u16 = 0xA000;
 const END_OF_CARTRIDGE_RAM: u16 = 0xBFFF;
 const START_OF_INTERNAL_RAM: u16 = 0xC000;
 const END_OF_INTERNAL_RAM: u16 = 0xDFFF;
+//The second half of internal ram is the part the CGB can switch between banks
+const START_OF_SWITCHABLE_INTERNAL_RAM: u16 = 0xD000;
 const START_OF_ECHO_RAM: u16 = 0xE000;
 const END_OF_ECHO_RAM: u16 = 0xFDFF;
 const START_OF_HIGH_RAM: u16 = 0xFE00;
+const START_OF_OAM: u16 = 0xFE00;
+const END_OF_OAM: u16 = 0xFE9F;
 
 const ROM_BANK_SIZE: usize = 0x4000;
-// const GAMEPAD_ADDRESS: u16 = 0xFF00;
+//The boot rom is the smallest thing we load and a cartridge has to at least fit its header
+const MIN_ROM_SIZE: usize = 0x100;
+const CARTRIDGE_HEADER_END: usize = 0x150;
+const SERIAL_DATA_ADDRESS: u16 = 0xFF01;
+const SERIAL_CONTROL_ADDRESS: u16 = 0xFF02;
 const BOOT_ROM_ADDRESS: u16 = 0xFF50;
+//CGB speed switch, bit 7 is the current speed and bit 0 arms a switch for the next STOP
+const KEY1_ADDRESS: u16 = 0xFF4D;
+const KEY1_DOUBLE_SPEED: u8 = 0x80;
+const KEY1_SWITCH_ARMED: u8 = 0x01;
+//CGB vram bank select, only bit 0 exists
+const VBK_ADDRESS: u16 = 0xFF4F;
+const VRAM_BANKS: usize = 2;
+//Tile data takes up the first 0x1800 bytes of each vram bank, 16 bytes a tile
+pub const VRAM_TILES: usize = 384;
+const VRAM_TILE_SIZE: usize = 16;
+//CGB background palette index and data, the index's top bit moves it on after every data write
+const BCPS_ADDRESS: u16 = 0xFF68;
+const BCPD_ADDRESS: u16 = 0xFF69;
+const PALETTE_AUTO_INCREMENT: u8 = 0x80;
+const PALETTE_INDEX_MASK: u8 = 0x3F;
+// 8 palettes of 4 colors each stored as 2 byte little endian rgb555
+const PALETTE_RAM_SIZE: usize = 64;
+//CGB internal ram bank select for 0xD000-0xDFFF, bank 0 can't be selected and gives bank 1
+const SVBK_ADDRESS: u16 = 0xFF70;
+const INTERNAL_RAM_BANK_SIZE: usize = 0x1000;
+const CGB_INTERNAL_RAM_BANKS: usize = 8;
+//The ppu's current line, only the ppu sets it and a cpu write sends it back to line 0
+pub const LY_ADDRESS: u16 = 0xFF44;
+const STAT_READ_ONLY: u8 = 0x07;
+pub const INTERRUPT_FLAG_ADDRESS: u16 = 0xFF0F;
+pub const INTERRUPT_ENABLE_ADDRESS: u16 = 0xFFFF;
+
+// Bits of the interrupt flag and enable registers, lower bits have the higher priority
+pub const VBLANK_INTERRUPT: u8 = 0x01;
+pub const LCD_STAT_INTERRUPT: u8 = 0x02;
+pub const TIMER_INTERRUPT: u8 = 0x04;
+pub const SERIAL_INTERRUPT: u8 = 0x08;
+pub const JOYPAD_INTERRUPT: u8 = 0x10;
+// The upper 3 bits of IE and IF can hold anything but never trigger an interrupt
+const INTERRUPT_MASK: u8 = 0x1F;
+
+//Oldest watched writes are dropped past this so nobody has to collect them
+const MAX_WATCHED_WRITES: usize = 4096;
+
+// Writing this to the serial control starts a transfer using the internal clock
+const SERIAL_TRANSFER_START: u8 = 0x81;
+// Bits of the serial control, a transfer runs while the top one is set
+const SERIAL_TRANSFER_ACTIVE: u8 = 0x80;
+const SERIAL_INTERNAL_CLOCK: u8 = 0x01;
+// The internal clock shifts a bit at 8192Hz which is every 128 machine cycles
+const SERIAL_CYCLES_PER_BIT: u16 = 128;
+
+// What the DMG boot rom leaves in the io registers when it hands over to the cartridge. The
+// trigger bit of NR14, NR24, NR34 and NR44 reads back as 1 on hardware but is left clear here
+// since a set bit would restart the channel
+const POST_BOOT_IO: [(u16, u8); 33] = [
+    (0xFF02, 0x7E), // SC
+    (0xFF05, 0x00), // TIMA
+    (0xFF06, 0x00), // TMA
+    (0xFF07, 0xF8), // TAC
+    (0xFF0F, 0xE1), // IF
+    (0xFF10, 0x80), // NR10
+    (0xFF11, 0xBF), // NR11
+    (0xFF12, 0xF3), // NR12
+    (0xFF13, 0xFF), // NR13
+    (0xFF14, 0x3F), // NR14
+    (0xFF16, 0x3F), // NR21
+    (0xFF17, 0x00), // NR22
+    (0xFF18, 0xFF), // NR23
+    (0xFF19, 0x3F), // NR24
+    (0xFF1A, 0x7F), // NR30
+    (0xFF1B, 0xFF), // NR31
+    (0xFF1C, 0x9F), // NR32
+    (0xFF1D, 0xFF), // NR33
+    (0xFF1E, 0x3F), // NR34
+    (0xFF20, 0xFF), // NR41
+    (0xFF21, 0x00), // NR42
+    (0xFF22, 0x00), // NR43
+    (0xFF23, 0x3F), // NR44
+    (0xFF24, 0x77), // NR50
+    (0xFF25, 0xF3), // NR51
+    (0xFF26, 0xF1), // NR52
+    (0xFF40, 0x91), // LCDC
+    (0xFF41, 0x85), // STAT
+    (0xFF42, 0x00), // SCY
+    (0xFF43, 0x00), // SCX
+    (0xFF45, 0x00), // LYC
+    (0xFF47, 0xFC), // BGP
+    (0xFFFF, 0x00), // IE
+];
 
+#[derive(Clone)]
 pub struct Memory {
-    boot: RomChunk,
-    cart_bank_0: RomChunk,
-    cart_bank_n: RomChunk,
+    //Left out when starting straight from the cartridge
+    boot: Option<RomChunk>,
+    cart: RomChunk,
+    header: CartridgeHeader,
+    cgb_mode: bool,
+    mbc: Mbc,
+    //Battery backed cartridges keep their ram in a .sav file between runs
+    battery: bool,
     cart_ram: RamChunk,
+    //Both CGB banks one after the other, a DMG only ever uses the first
     vram: RamChunk,
+    vram_bank: u8,
+    //Tiles written since the ppu last decoded them, every bank one after the other
+    dirty_tiles: Vec<bool>,
+    //Every bank one after the other, 2 on a DMG and 8 on a CGB
     iram: RamChunk,
+    iram_bank: u8,
     high_ram: RamChunk,
+    //IE sits at the very top of the address space just past high ram
+    interrupt_enable: u8,
     boot_enabled: bool,
-    pub cpu_cycles: u16,
+    //The ppu locks the cpu out of vram and oam while it is reading them
+    vram_accessible: bool,
+    oam_accessible: bool,
+    //Set when the cpu writes LY until the ppu gets round to restarting its frame
+    ly_reset: bool,
+    timer: Timer,
+    joypad: Joypad,
+    serial_output: String,
+    //With a cable plugged in transfers take real time and the bits go to whatever is on the other
+    //end, otherwise they finish straight away
+    link_connected: bool,
+    serial_cycles: u16,
+    //Bits shifted so far in the current transfer
+    serial_bits: u8,
+    //Clock pulses sent out since the other end last took them, each one swaps a bit
+    serial_clocks: u8,
+    //Debug pokes leave the cartridge alone unless this is turned on
+    rom_pokes_allowed: bool,
+    //CGB double speed runs the cpu and timer at 8MHz while everything else stays at 4MHz
+    double_speed: bool,
+    speed_switch_armed: bool,
+    background_palettes: [u8; PALETTE_RAM_SIZE],
+    background_palette_index: u8,
+    cheats: Cheats,
+    //Addresses whose writes get recorded for debugging
+    watchpoints: Vec<u16>,
+    watched_writes: VecDeque<WatchedWrite>,
+    //Where the instruction that is running started, set by the cpu so writes can be traced back
+    current_pc: u16,
 }
 
+//A write the cpu made to a watched address and the pc of the instruction that did it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchedWrite {
+    pub address: u16,
+    pub value: u8,
+    pub pc: u16,
+}
+
+//Shared between copies of memory so save states don't each carry the whole rom, pokes copy it
+#[derive(Clone)]
 pub struct RomChunk {
-    bytes: Vec<u8>,
+    bytes: Rc<Vec<u8>>,
 }
 
+#[derive(Clone)]
 struct RamChunk {
     bytes: Vec<u8>,
 }
 
+//What work ram, vram and cartridge ram hold at power on. Real hardware comes up with a mix that
+//differs between units and some roms behave differently depending on it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum RamFill {
+    #[default]
+    Zero,
+    Ones,
+    //Repeated over and over from the start of each chunk of ram
+    Pattern(Vec<u8>),
+}
+
 impl Memory {
-    pub fn new(boot: RomChunk, cart: RomChunk) -> Self {
-        // Split the cart into the fixed and variable banks
-        let mut cart_bank_0 = RomChunk::new_empty(ROM_BANK_SIZE);
-        for i in 0..ROM_BANK_SIZE {
-            cart_bank_0.bytes[i] = cart.bytes[i];
+    pub fn new(boot: Option<RomChunk>, cart: RomChunk, model: Model) -> Result<Self> {
+        Self::new_with_fill(boot, cart, model, &RamFill::Zero)
+    }
+
+    pub fn new_with_fill(
+        boot: Option<RomChunk>,
+        cart: RomChunk,
+        model: Model,
+        fill: &RamFill,
+    ) -> Result<Self> {
+        if cart.bytes.len() < CARTRIDGE_HEADER_END {
+            return Err(EmuError::RomTooSmall(cart.bytes.len()));
         }
-        let mut cart_bank_n = RomChunk::new_empty(ROM_BANK_SIZE);
-        for i in 0..ROM_BANK_SIZE {
-            cart_bank_n.bytes[i] = cart.bytes[i + ROM_BANK_SIZE];
+        let header = cart.header();
+        info!("Loading {}", header.title);
+        if cart.bytes.len() != header.rom_size_bytes() {
+            warn!(
+                "Rom is {:x} bytes but the header says it should be {:x}",
+                cart.bytes.len(),
+                header.rom_size_bytes()
+            );
         }
-        Self {
+        let mbc = Mbc::new(header.cartridge_type)?;
+        let ram_size = match mbc {
+            Mbc::Mbc2(_) => mbc::MBC2_RAM_SIZE,
+            _ => cart_ram_size(header.ram_size)?,
+        };
+        //Only roms that say they work on a CGB get to use its features
+        let cgb_mode = model == Model::Cgb && header.cgb_supported();
+        if model == Model::Dmg && header.cgb_only() {
+            warn!(
+                "{} only works on a CGB but is running on a DMG",
+                header.title
+            );
+        }
+        let iram_size = if cgb_mode {
+            INTERNAL_RAM_BANK_SIZE * CGB_INTERNAL_RAM_BANKS
+        } else {
+            RAM_BANK_SIZE
+        };
+        Ok(Self {
+            boot_enabled: boot.is_some(),
             boot,
-            cart_bank_0,
-            cart_bank_n,
-            cart_ram: RamChunk::new(RAM_BANK_SIZE * 4),
-            vram: RamChunk::new(RAM_BANK_SIZE),
-            iram: RamChunk::new(RAM_BANK_SIZE),
+            cart,
+            mbc,
+            battery: mbc::has_battery(header.cartridge_type),
+            header,
+            cgb_mode,
+            cart_ram: RamChunk::filled(ram_size, fill),
+            vram: RamChunk::filled(RAM_BANK_SIZE * VRAM_BANKS, fill),
+            vram_bank: 0,
+            dirty_tiles: vec![true; VRAM_TILES * VRAM_BANKS],
+            iram: RamChunk::filled(iram_size, fill),
+            iram_bank: 1,
             high_ram: RamChunk::new(0x200),
-            boot_enabled: true,
-            cpu_cycles: 0,
-        }
+            interrupt_enable: 0,
+            vram_accessible: true,
+            oam_accessible: true,
+            ly_reset: false,
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            serial_output: String::new(),
+            link_connected: false,
+            serial_cycles: 0,
+            serial_bits: 0,
+            serial_clocks: 0,
+            rom_pokes_allowed: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            background_palettes: [0; PALETTE_RAM_SIZE],
+            background_palette_index: 0,
+            cheats: Cheats::new(),
+            watchpoints: Vec::new(),
+            watched_writes: VecDeque::new(),
+            current_pc: 0,
+        })
     }
 
     pub fn read_u8(&self, address: u16) -> u8 {
+        //Blocked reads see an undriven bus which reads back as all 1s
         match address {
-            START_OF_FIXED_ROM..=END_OF_FIXED_ROM => {
-                if self.boot_enabled && address <= END_OF_BOOT {
-                    self.boot.read_u8(address)
-                } else {
-                    self.cart_bank_0.read_u8(address)
-                }
-            }
-            START_OF_BANKED_ROM..=END_OF_BANKED_ROM => {
-                self.cart_bank_n.read_u8(address - START_OF_BANKED_ROM)
-            }
-            START_OF_VRAM..=END_OF_VRAM => self.vram.read_u8(address - START_OF_VRAM),
+            START_OF_VRAM..=END_OF_VRAM if !self.vram_accessible => 0xFF,
+            START_OF_OAM..=END_OF_OAM if !self.oam_accessible => 0xFF,
+            START_OF_FIXED_ROM..=END_OF_BANKED_ROM => self
+                .cheats
+                .patch_rom(address, self.read_u8_unchecked(address)),
+            _ => self.read_u8_unchecked(address),
+        }
+    }
+
+    //The boot rom covers 0x0000-0x00FF inclusive until it is turned off through 0xFF50. Cpu writes
+    //there still reach the cartridge's controller like they would on hardware
+    fn boot_overlaid(&self, address: u16) -> bool {
+        self.boot.is_some() && self.boot_enabled && address <= END_OF_BOOT
+    }
+
+    //Reads ignoring whether the ppu has vram and oam locked, used by the ppu itself
+    pub fn read_u8_unchecked(&self, address: u16) -> u8 {
+        match address {
+            START_OF_FIXED_ROM..=END_OF_FIXED_ROM => match &self.boot {
+                Some(boot) if self.boot_overlaid(address) => boot.read_u8(address),
+                _ => self.cart.read_u8(address),
+            },
+            START_OF_BANKED_ROM..=END_OF_BANKED_ROM => self
+                .cart
+                .read_banked(self.mbc.rom_bank(), address - START_OF_BANKED_ROM),
+            START_OF_VRAM..=END_OF_VRAM => self.read_vram(self.vram_bank, address),
             START_OF_CARTRIDGE_RAM..=END_OF_CARTRIDGE_RAM => {
-                self.cart_ram.read_u8(address - START_OF_CARTRIDGE_RAM)
+                self.mbc.read_ram(&self.cart_ram.bytes, address)
             }
             START_OF_INTERNAL_RAM..=END_OF_INTERNAL_RAM => {
-                self.iram.read_u8(address - START_OF_INTERNAL_RAM)
+                self.iram.read_u8(self.iram_offset(address))
+            }
+            //Echo ram mirrors the first 0x1E00 bytes of internal ram, banks included
+            START_OF_ECHO_RAM..=END_OF_ECHO_RAM => self
+                .iram
+                .read_u8(self.iram_offset(address - START_OF_ECHO_RAM + START_OF_INTERNAL_RAM)),
+            JOYPAD_ADDRESS => self.joypad.read(),
+            DIV_ADDRESS => self.timer.div(),
+            INTERRUPT_ENABLE_ADDRESS => self.interrupt_enable,
+            KEY1_ADDRESS if self.cgb_mode => self.key1(),
+            //The unused bits of these read back as 1s
+            VBK_ADDRESS if self.cgb_mode => 0xFE | self.vram_bank,
+            BCPS_ADDRESS if self.cgb_mode => self.background_palette_index | 0x40,
+            SVBK_ADDRESS if self.cgb_mode => 0xF8 | self.iram_bank,
+            BCPD_ADDRESS if self.cgb_mode => {
+                self.background_palettes
+                    [(self.background_palette_index & PALETTE_INDEX_MASK) as usize]
             }
-            START_OF_ECHO_RAM..=END_OF_ECHO_RAM => todo!(),
             _ => self.high_ram.read_u8(address - START_OF_HIGH_RAM),
         }
     }
 
+    //The high byte of a word at 0xFFFF comes from 0x0000
     pub fn read_u16(&self, address: u16) -> u16 {
-        (self.read_u8(address + 1) as u16) << 8 | self.read_u8(address) as u16
+        (self.read_u8(address.wrapping_add(1)) as u16) << 8 | self.read_u8(address) as u16
     }
 
     pub fn write_u8(&mut self, address: u16, value: u8) {
+        if self.watchpoints.contains(&address) {
+            self.record_watched_write(address, value);
+        }
         match address {
-            START_OF_FIXED_ROM..=END_OF_FIXED_ROM => {
-                if self.boot_enabled && address < END_OF_BOOT {
-                    self.boot.write_u8(address, value);
-                } else {
-                    self.cart_bank_0.write_u8(address, value);
-                }
+            START_OF_FIXED_ROM..=END_OF_BANKED_ROM => self.mbc.write_register(address, value),
+            START_OF_VRAM..=END_OF_VRAM => self.write_vram(address, value),
+            START_OF_CARTRIDGE_RAM..=END_OF_CARTRIDGE_RAM => {
+                self.mbc.write_ram(&mut self.cart_ram.bytes, address, value)
             }
-            START_OF_BANKED_ROM..=END_OF_BANKED_ROM => self
-                .cart_bank_n
-                .write_u8(address - START_OF_BANKED_ROM, value),
-            START_OF_VRAM..=END_OF_VRAM => self.vram.write_u8(address - START_OF_VRAM, value),
-            START_OF_CARTRIDGE_RAM..=END_OF_CARTRIDGE_RAM => self
-                .cart_ram
-                .write_u8(address - START_OF_CARTRIDGE_RAM, value),
             START_OF_INTERNAL_RAM..=END_OF_INTERNAL_RAM => {
-                self.iram.write_u8(address - START_OF_INTERNAL_RAM, value)
+                let offset = self.iram_offset(address);
+                self.iram.write_u8(offset, value)
+            }
+            START_OF_ECHO_RAM..=END_OF_ECHO_RAM => {
+                let offset = self.iram_offset(address - START_OF_ECHO_RAM + START_OF_INTERNAL_RAM);
+                self.iram.write_u8(offset, value)
             }
-            START_OF_ECHO_RAM..=END_OF_ECHO_RAM => todo!(),
             _ => self.write_high_mem(address, value),
         }
     }
@@ -121,56 +374,573 @@ impl Memory {
     pub fn write_u16(&mut self, address: u16, value: u16) {
         let lower = value & 0xFF;
         let upper = value >> 8;
-        self.write_u8(address + 1, upper as u8);
+        self.write_u8(address.wrapping_add(1), upper as u8);
         self.write_u8(address, lower as u8);
     }
 
     pub fn write_special_regsiter(&mut self, address: u16, value: u8) {
-        if address > END_OF_ECHO_RAM {
+        if address == INTERRUPT_ENABLE_ADDRESS {
+            self.interrupt_enable = value;
+        } else if address > END_OF_ECHO_RAM {
             self.high_ram.write_u8(address - START_OF_HIGH_RAM, value);
         } else {
             panic!("Can't write a special register: {:x}", address);
         }
     }
+
+    //Copies out a range of memory even if the ppu has it locked, wrapping past 0xFFFF
+    pub fn dump(&self, start: u16, len: u16) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.read_u8_unchecked(start.wrapping_add(i)))
+            .collect()
+    }
+
+    //Sets a byte directly without any of the side effects a cpu write would have
+    pub fn poke(&mut self, address: u16, value: u8) {
+        match address {
+            START_OF_FIXED_ROM..=END_OF_BANKED_ROM if !self.rom_pokes_allowed => {
+                warn!("Ignoring poke into rom at {:x}", address);
+            }
+            //Patching the cartridge under the boot rom wouldn't show up so there is nothing to do
+            _ if self.boot_overlaid(address) => {
+                warn!("Ignoring poke into the boot rom at {:x}", address);
+            }
+            START_OF_FIXED_ROM..=END_OF_FIXED_ROM => {
+                self.cart.write_u8(address, value);
+            }
+            START_OF_BANKED_ROM..=END_OF_BANKED_ROM => {
+                self.cart
+                    .write_banked(self.mbc.rom_bank(), address - START_OF_BANKED_ROM, value)
+            }
+            START_OF_VRAM..=END_OF_VRAM => self.write_vram(address, value),
+            START_OF_CARTRIDGE_RAM..=END_OF_CARTRIDGE_RAM => {
+                self.mbc.write_ram(&mut self.cart_ram.bytes, address, value)
+            }
+            START_OF_INTERNAL_RAM..=END_OF_INTERNAL_RAM => {
+                let offset = self.iram_offset(address);
+                self.iram.write_u8(offset, value)
+            }
+            START_OF_ECHO_RAM..=END_OF_ECHO_RAM => {
+                let offset = self.iram_offset(address - START_OF_ECHO_RAM + START_OF_INTERNAL_RAM);
+                self.iram.write_u8(offset, value)
+            }
+            INTERRUPT_ENABLE_ADDRESS => self.interrupt_enable = value,
+            _ => self.high_ram.write_u8(address - START_OF_HIGH_RAM, value),
+        }
+    }
+
+    //Sets up the io registers the way the boot rom would have left them for when it is skipped
+    pub fn init_post_boot_io(&mut self) {
+        for (address, value) in POST_BOOT_IO {
+            self.write_special_regsiter(address, value);
+        }
+        self.boot_enabled = false;
+    }
+
+    //True until the boot rom hands over to the cartridge
+    pub fn booting(&self) -> bool {
+        self.boot.is_some() && self.boot_enabled
+    }
+
+    pub fn apu_enabled(&self) -> bool {
+        self.read_u8_unchecked(NR52) & APU_ENABLED != 0
+    }
+
+    pub fn add_cheat(&mut self, code: &str) -> Result<()> {
+        self.cheats.add(code)
+    }
+
+    //GameShark codes hold their values by writing them again every frame
+    pub fn apply_cheats(&mut self) {
+        let writes = self.cheats.ram_writes().collect::<Vec<_>>();
+        for (address, value) in writes {
+            self.poke(address, value);
+        }
+    }
+
+    //Records every write to address from now on until they are taken
+    pub fn watch(&mut self, address: u16) {
+        if !self.watchpoints.contains(&address) {
+            self.watchpoints.push(address);
+        }
+    }
+
+    //Hands back the writes to watched addresses since the last call, oldest first
+    pub fn take_watched_writes(&mut self) -> Vec<WatchedWrite> {
+        self.watched_writes.drain(..).collect()
+    }
+
+    pub fn set_current_pc(&mut self, pc: u16) {
+        self.current_pc = pc;
+    }
+
+    fn record_watched_write(&mut self, address: u16, value: u8) {
+        if self.watched_writes.len() >= MAX_WATCHED_WRITES {
+            self.watched_writes.pop_front();
+        }
+        self.watched_writes.push_back(WatchedWrite {
+            address,
+            value,
+            pc: self.current_pc,
+        });
+    }
+
+    //Lets pokes patch the cartridge, mostly useful for cheats
+    pub fn allow_rom_pokes(&mut self, allowed: bool) {
+        self.rom_pokes_allowed = allowed;
+    }
+
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    //The .sav file is the cartridge ram followed by whatever extra state the mbc keeps
+    pub fn load_save(&mut self, path: &Path) -> Result<()> {
+        let data = fs::read(path).map_err(|error| EmuError::from_io(path.to_path_buf(), error))?;
+        self.load_save_data(&data);
+        Ok(())
+    }
+
+    //Cartridge ram followed by anything the mbc keeps, like the rtc
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut data = self.cart_ram.bytes.clone();
+        data.extend(self.mbc.save_data());
+        data
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        let ram_size = self.cart_ram.bytes.len().min(data.len());
+        self.cart_ram.bytes[..ram_size].copy_from_slice(&data[..ram_size]);
+        self.mbc.load_save_data(&data[ram_size..]);
+    }
+
+    //The mbc3 clock reads the host's clock by default, frontends where std has no clock give it
+    //one of their own
+    pub fn set_rtc_clock(&mut self, clock: mbc::Clock) {
+        self.mbc.set_clock(clock);
+    }
+
+    pub fn write_save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.save_data())
+            .map_err(|error| EmuError::from_io(path.to_path_buf(), error))
+    }
+
+    //Called by the ppu as it changes modes, everything is accessible while the lcd is off
+    pub fn set_ppu_access(&mut self, vram_accessible: bool, oam_accessible: bool) {
+        self.vram_accessible = vram_accessible;
+        self.oam_accessible = oam_accessible;
+    }
+
+    //Whether LY was written since the last time the ppu checked
+    pub fn take_ly_reset(&mut self) -> bool {
+        std::mem::take(&mut self.ly_reset)
+    }
+
+    pub fn step_timer(&mut self, cpu_cycles: u16) {
+        self.timer.step(cpu_cycles);
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    pub fn speed_switch_armed(&self) -> bool {
+        self.speed_switch_armed
+    }
+
+    //Done by STOP once KEY1 has been armed
+    pub fn switch_speed(&mut self) {
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+    }
+
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    //Where an address in 0xC000-0xDFFF lives in iram, a DMG is always on bank 1
+    fn iram_offset(&self, address: u16) -> u16 {
+        if address < START_OF_SWITCHABLE_INTERNAL_RAM {
+            address - START_OF_INTERNAL_RAM
+        } else {
+            self.iram_bank as u16 * INTERNAL_RAM_BANK_SIZE as u16
+                + (address - START_OF_SWITCHABLE_INTERNAL_RAM)
+        }
+    }
+
+    //Reads a vram bank whichever one the cpu has selected, used by the ppu for the attribute map
+    pub fn read_vram(&self, bank: u8, address: u16) -> u8 {
+        self.vram
+            .read_u8(bank as u16 * RAM_BANK_SIZE as u16 + (address - START_OF_VRAM))
+    }
+
+    //Hands the ppu a run of vram straight from the bank instead of decoding every address, nothing
+    //is checked so a range running past the end of the bank panics
+    pub(crate) fn read_vram_slice(&self, bank: u8, address: u16, len: usize) -> &[u8] {
+        let start = bank as usize * RAM_BANK_SIZE + (address - START_OF_VRAM) as usize;
+        &self.vram.bytes[start..start + len]
+    }
+
+    fn write_vram(&mut self, address: u16, value: u8) {
+        self.vram.write_u8(
+            self.vram_bank as u16 * RAM_BANK_SIZE as u16 + (address - START_OF_VRAM),
+            value,
+        );
+        let tile = (address - START_OF_VRAM) as usize / VRAM_TILE_SIZE;
+        if tile < VRAM_TILES {
+            self.dirty_tiles[self.vram_bank as usize * VRAM_TILES + tile] = true;
+        }
+    }
+
+    //Whether a tile has been written since the last call for it, so the ppu knows to decode it again
+    pub fn take_dirty_tile(&mut self, bank: u8, tile_id: u16) -> bool {
+        std::mem::take(&mut self.dirty_tiles[bank as usize * VRAM_TILES + tile_id as usize])
+    }
+
+    //One of the 4 rgb555 colors of a CGB background palette
+    pub fn background_color(&self, palette: u8, color: u8) -> u16 {
+        let index = ((palette & 0x7) * 8 + (color & 0x3) * 2) as usize;
+        u16::from_le_bytes([
+            self.background_palettes[index],
+            self.background_palettes[index + 1],
+        ])
+    }
+
+    fn write_background_palette(&mut self, value: u8) {
+        let index = self.background_palette_index & PALETTE_INDEX_MASK;
+        self.background_palettes[index as usize] = value;
+        if self.background_palette_index & PALETTE_AUTO_INCREMENT != 0 {
+            self.background_palette_index =
+                PALETTE_AUTO_INCREMENT | ((index + 1) & PALETTE_INDEX_MASK);
+        }
+    }
+
+    //The unused bits read back as 1s
+    fn key1(&self) -> u8 {
+        let mut key1 = !(KEY1_DOUBLE_SPEED | KEY1_SWITCH_ARMED);
+        if self.double_speed {
+            key1 |= KEY1_DOUBLE_SPEED;
+        }
+        if self.speed_switch_armed {
+            key1 |= KEY1_SWITCH_ARMED;
+        }
+        key1
+    }
+
+    //For headless runs that need the same DIV every time, see Timer::set_frozen
+    pub fn fix_div(&mut self, value: u8) {
+        self.timer.set_div(value);
+        self.timer.set_frozen(true);
+    }
+
+    pub fn reset_div(&mut self) {
+        self.timer.reset_div();
+    }
+
+    pub fn press_button(&mut self, button: Button) {
+        if self.joypad.press(button) {
+            self.request_interrupt(JOYPAD_INTERRUPT);
+        }
+    }
+
+    pub fn release_button(&mut self, button: Button) {
+        self.joypad.release(button);
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: u8) {
+        let flags = self.read_u8(INTERRUPT_FLAG_ADDRESS);
+        self.write_u8(INTERRUPT_FLAG_ADDRESS, flags | interrupt);
+    }
+
+    //Interrupts that have been requested and are enabled, whether they get serviced is up to IME
+    pub fn pending_interrupts(&self) -> u8 {
+        self.read_u8(INTERRUPT_FLAG_ADDRESS) & self.interrupt_enable & INTERRUPT_MASK
+    }
+
+    //Called once the cpu starts servicing an interrupt
+    pub fn clear_interrupt(&mut self, interrupt: u8) {
+        let flags = self.read_u8(INTERRUPT_FLAG_ADDRESS);
+        self.write_u8(INTERRUPT_FLAG_ADDRESS, flags & !interrupt);
+    }
+
+    //Hands back everything sent over the serial port since the last call
+    pub fn take_serial_output(&mut self) -> String {
+        std::mem::take(&mut self.serial_output)
+    }
+
+    pub fn connect_link(&mut self, connected: bool) {
+        self.link_connected = connected;
+    }
+
+    //Times the internal clock while a cable is connected, the pulses are picked up by the link
+    pub fn step_serial(&mut self, cpu_cycles: u16) {
+        if !self.link_connected {
+            return;
+        }
+        let control = self.read_u8(SERIAL_CONTROL_ADDRESS);
+        if control & SERIAL_TRANSFER_ACTIVE == 0 || control & SERIAL_INTERNAL_CLOCK == 0 {
+            return;
+        }
+        self.serial_cycles += cpu_cycles;
+        while self.serial_cycles >= SERIAL_CYCLES_PER_BIT {
+            self.serial_cycles -= SERIAL_CYCLES_PER_BIT;
+            //The clock stops once all 8 bits have been sent
+            if self.serial_bits + self.serial_clocks < 8 {
+                self.serial_clocks += 1;
+            }
+        }
+    }
+
+    //Hands back how many bits the internal clock has sent out since the last call
+    pub fn take_serial_clocks(&mut self) -> u8 {
+        std::mem::take(&mut self.serial_clocks)
+    }
+
+    pub fn serial_transferring(&self) -> bool {
+        self.read_u8(SERIAL_CONTROL_ADDRESS) & SERIAL_TRANSFER_ACTIVE != 0
+    }
+
+    //Bits go out of the top of SB first
+    pub fn serial_out_bit(&self) -> bool {
+        self.read_u8(SERIAL_DATA_ADDRESS) & 0x80 != 0
+    }
+
+    //Shifts a bit in from the other end of the cable, the 8th one finishes the transfer
+    pub fn shift_serial(&mut self, bit_in: bool) {
+        let data = (self.read_u8(SERIAL_DATA_ADDRESS) << 1) | bit_in as u8;
+        self.high_ram
+            .write_u8(SERIAL_DATA_ADDRESS - START_OF_HIGH_RAM, data);
+        self.serial_bits += 1;
+        if self.serial_bits == 8 {
+            self.serial_bits = 0;
+            self.finish_serial_transfer();
+        }
+    }
+
+    fn finish_serial_transfer(&mut self) {
+        let control = self.read_u8(SERIAL_CONTROL_ADDRESS);
+        self.high_ram.write_u8(
+            SERIAL_CONTROL_ADDRESS - START_OF_HIGH_RAM,
+            control & !SERIAL_TRANSFER_ACTIVE,
+        );
+        self.request_interrupt(SERIAL_INTERRUPT);
+    }
+
+    //There is nothing on the other end of the link cable so transfers complete instantly
+    fn serial_transfer(&mut self) {
+        let data = self.read_u8(SERIAL_DATA_ADDRESS);
+        self.serial_output.push(data as char);
+        self.finish_serial_transfer();
+    }
+
     fn write_high_mem(&mut self, address: u16, value: u8) {
         //There are some high bits that when we write them we won't to change some variables
-        if address == BOOT_ROM_ADDRESS {
-            self.boot_enabled = false;
+        match address {
+            BOOT_ROM_ADDRESS => self.boot_enabled = false,
+            JOYPAD_ADDRESS => {
+                self.joypad.write(value);
+                return;
+            }
+            //Writing anything to DIV resets it and the value isn't stored
+            DIV_ADDRESS => {
+                self.reset_div();
+                return;
+            }
+            INTERRUPT_ENABLE_ADDRESS => {
+                self.interrupt_enable = value;
+                return;
+            }
+            LY_ADDRESS => {
+                self.high_ram.write_u8(address - START_OF_HIGH_RAM, 0);
+                self.ly_reset = true;
+                return;
+            }
+            //The mode and coincidence bits belong to the ppu and the top bit always reads 1
+            STAT => {
+                let ppu_bits = self.high_ram.read_u8(address - START_OF_HIGH_RAM) & STAT_READ_ONLY;
+                self.high_ram.write_u8(
+                    address - START_OF_HIGH_RAM,
+                    0x80 | (value & !STAT_READ_ONLY) | ppu_bits,
+                );
+                return;
+            }
+            //Only the arm bit can be written, the speed only changes through STOP
+            KEY1_ADDRESS if self.cgb_mode => {
+                self.speed_switch_armed = value & KEY1_SWITCH_ARMED != 0;
+                return;
+            }
+            VBK_ADDRESS if self.cgb_mode => {
+                self.vram_bank = value & 0x01;
+                return;
+            }
+            BCPS_ADDRESS if self.cgb_mode => {
+                self.background_palette_index =
+                    value & (PALETTE_AUTO_INCREMENT | PALETTE_INDEX_MASK);
+                return;
+            }
+            BCPD_ADDRESS if self.cgb_mode => {
+                self.write_background_palette(value);
+                return;
+            }
+            SVBK_ADDRESS if self.cgb_mode => {
+                self.iram_bank = (value & 0x07).max(1);
+                return;
+            }
+            //The channel status bits are read only, the apu keeps them up to date
+            NR52 => {
+                let mut status = self.high_ram.read_u8(NR52 - START_OF_HIGH_RAM) & 0x0F;
+                if value & APU_ENABLED == 0 {
+                    for register in FIRST_SOUND_REGISTER..=LAST_SOUND_REGISTER {
+                        self.high_ram.write_u8(register - START_OF_HIGH_RAM, 0);
+                    }
+                    status = 0;
+                }
+                self.high_ram.write_u8(
+                    address - START_OF_HIGH_RAM,
+                    (value & APU_ENABLED) | NR52_UNUSED_BITS | status,
+                );
+                return;
+            }
+            //The sound registers ignore writes while the apu is off
+            FIRST_SOUND_REGISTER..=LAST_SOUND_REGISTER if !self.apu_enabled() => return,
+            //A new transfer starts from the first bit
+            SERIAL_CONTROL_ADDRESS
+                if self.link_connected && value & SERIAL_TRANSFER_ACTIVE != 0 =>
+            {
+                self.serial_cycles = 0;
+                self.serial_bits = 0;
+                self.serial_clocks = 0;
+            }
+            SERIAL_CONTROL_ADDRESS if value == SERIAL_TRANSFER_START => {
+                self.high_ram.write_u8(address - START_OF_HIGH_RAM, value);
+                self.serial_transfer();
+                return;
+            }
+            _ => {}
         }
         self.high_ram.write_u8(address - START_OF_HIGH_RAM, value);
     }
 }
 
+//Ram size byte from the cartridge header, carts without ram still get a bank so the window is
+//backed by something
+fn cart_ram_size(ram_size: u8) -> Result<usize> {
+    match ram_size {
+        0x00 | 0x02 => Ok(RAM_BANK_SIZE),
+        0x01 => Ok(0x800),
+        0x03 => Ok(RAM_BANK_SIZE * 4),
+        0x04 => Ok(RAM_BANK_SIZE * 16),
+        0x05 => Ok(RAM_BANK_SIZE * 8),
+        _ => Err(EmuError::BadHeader(format!(
+            "unknown ram size 0x{:02x}",
+            ram_size
+        ))),
+    }
+}
+
+//Out of range reads see an undriven bus and writes go nowhere, either way something is wrong
+fn read_checked(bytes: &[u8], offset: usize) -> u8 {
+    match bytes.get(offset) {
+        Some(value) => *value,
+        None => {
+            warn!(
+                "Read at {:x} past the end of a {:x} byte chunk",
+                offset,
+                bytes.len()
+            );
+            0xFF
+        }
+    }
+}
+
+fn write_checked(bytes: &mut [u8], offset: usize, value: u8) {
+    let len = bytes.len();
+    match bytes.get_mut(offset) {
+        Some(byte) => *byte = value,
+        None => warn!(
+            "Write at {:x} past the end of a {:x} byte chunk",
+            offset, len
+        ),
+    }
+}
+
 impl RomChunk {
     pub fn new(rom_path: Option<&Path>) -> Result<Self> {
         if let Some(rom_path) = rom_path {
             Self::from_file(rom_path)
         } else {
             Ok(Self {
-                bytes: vec![0; ROM_BANK_SIZE * 2],
+                bytes: Rc::new(vec![0; ROM_BANK_SIZE * 2]),
             })
         }
     }
 
-    fn new_empty(size: usize) -> Self {
-        Self {
-            bytes: vec![0; size],
+    fn from_file(file_path: &Path) -> Result<Self> {
+        let bytes = fs::read(file_path)
+            .map_err(|error| EmuError::from_io(file_path.to_path_buf(), error))?;
+        Self::from_bytes(bytes)
+    }
+
+    //For roms that don't come from a file, like ones built into the binary or made up by tests
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        if bytes.len() < MIN_ROM_SIZE {
+            return Err(EmuError::RomTooSmall(bytes.len()));
         }
+        Ok(Self {
+            bytes: Rc::new(bytes),
+        })
     }
 
-    fn from_file(file_path: &Path) -> Result<Self> {
-        let mut f = File::open(file_path)?;
-        let mut buffer = Vec::new();
-        f.read_to_end(&mut buffer)?;
-        Ok(Self { bytes: buffer })
+    //Only meaningful for a cartridge, Memory::new makes sure it is long enough to have a header
+    pub fn header(&self) -> CartridgeHeader {
+        CartridgeHeader::parse(&self.bytes)
     }
 
     fn read_u8(&self, address: u16) -> u8 {
-        self.bytes[address as usize]
+        read_checked(&self.bytes, address as usize)
     }
 
     fn write_u8(&mut self, address: u16, value: u8) {
-        self.bytes[address as usize] = value;
+        write_checked(
+            &mut Rc::make_mut(&mut self.bytes)[..],
+            address as usize,
+            value,
+        );
+    }
+
+    //Bank numbers past the end of the rom wrap around like the unused bank bits are ignored
+    fn banked_offset(&self, bank: usize, address: u16) -> usize {
+        let banks = (self.bytes.len() / ROM_BANK_SIZE).max(1);
+        (bank % banks) * ROM_BANK_SIZE + address as usize
+    }
+
+    fn read_banked(&self, bank: usize, address: u16) -> u8 {
+        read_checked(&self.bytes, self.banked_offset(bank, address))
+    }
+
+    fn write_banked(&mut self, bank: usize, address: u16, value: u8) {
+        let offset = self.banked_offset(bank, address);
+        write_checked(&mut Rc::make_mut(&mut self.bytes)[..], offset, value);
+    }
+}
+
+impl RamFill {
+    //zero, ff or a run of hex bytes to repeat like 00ff
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "zero" => Some(RamFill::Zero),
+            "ff" => Some(RamFill::Ones),
+            _ if text.is_empty() || !text.len().is_multiple_of(2) => None,
+            _ => (0..text.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<_>>>()
+                .map(RamFill::Pattern),
+        }
     }
 }
 
@@ -180,11 +950,410 @@ impl RamChunk {
             bytes: vec![0; size],
         }
     }
+
+    fn filled(size: usize, fill: &RamFill) -> Self {
+        let bytes = match fill {
+            RamFill::Zero => vec![0; size],
+            RamFill::Ones => vec![0xFF; size],
+            RamFill::Pattern(pattern) => pattern.iter().copied().cycle().take(size).collect(),
+        };
+        Self { bytes }
+    }
+
     fn read_u8(&self, address: u16) -> u8 {
-        self.bytes[address as usize]
+        read_checked(&self.bytes, address as usize)
     }
 
     fn write_u8(&mut self, address: u16, value: u8) {
-        self.bytes[address as usize] = value;
+        write_checked(&mut self.bytes, address as usize, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WRAM_START: u16 = 0xC000;
+    const WRAM_END: u16 = 0xDFFF;
+
+    fn filled_memory(fill: &RamFill) -> Memory {
+        Memory::new_with_fill(None, RomChunk::new(None).unwrap(), Model::Dmg, fill).unwrap()
+    }
+
+    //A cgb running a rom that supports it
+    fn cgb_memory() -> Memory {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        Memory::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Cgb).unwrap()
+    }
+
+    //An mbc3 cart with ram so writes to 0x0000 can be seen, the first rom byte is 0xAB
+    fn wrapping_memory() -> Memory {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0000] = 0xAB;
+        rom[0x147] = 0x13;
+        rom[0x149] = 0x02;
+        Memory::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Dmg).unwrap()
+    }
+
+    #[test]
+    fn serial_transfers_are_captured() {
+        let mut memory = Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        memory.write_u8(INTERRUPT_FLAG_ADDRESS, 0);
+        for byte in b"ok\n" {
+            memory.write_u8(SERIAL_DATA_ADDRESS, *byte);
+            memory.write_u8(SERIAL_CONTROL_ADDRESS, 0x81);
+            assert_eq!(memory.read_u8(SERIAL_CONTROL_ADDRESS) & 0x80, 0);
+        }
+        assert_eq!(
+            memory.read_u8(INTERRUPT_FLAG_ADDRESS) & SERIAL_INTERRUPT,
+            SERIAL_INTERRUPT
+        );
+        assert_eq!(memory.take_serial_output(), "ok\n");
+        assert_eq!(memory.take_serial_output(), "");
+
+        //Nothing is sent until the transfer is started with the internal clock
+        memory.write_u8(SERIAL_DATA_ADDRESS, b'x');
+        memory.write_u8(SERIAL_CONTROL_ADDRESS, 0x01);
+        assert_eq!(memory.take_serial_output(), "");
+    }
+
+    #[test]
+    fn reads_past_the_end_of_a_chunk_are_open_bus() {
+        let mut ram = RamChunk::new(4);
+        ram.write_u8(3, 0x12);
+        assert_eq!(ram.read_u8(3), 0x12);
+        assert_eq!(ram.read_u8(4), 0xFF);
+        assert_eq!(ram.read_u8(0xFFFF), 0xFF);
+        //Writes past the end go nowhere
+        ram.write_u8(4, 0x34);
+        assert_eq!(ram.bytes, [0, 0, 0, 0x12]);
+        assert_eq!(RamChunk::new(0).read_u8(0), 0xFF);
+    }
+
+    #[test]
+    fn ten_byte_rom_file_is_too_small() {
+        let path = std::env::temp_dir().join(format!("rust_boi-tiny-{}.gb", std::process::id()));
+        fs::write(&path, [0; 10]).unwrap();
+        let result = RomChunk::new(Some(&path));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(EmuError::RomTooSmall(10))));
+
+        let result = RomChunk::new(Some(&path));
+        assert!(matches!(result, Err(EmuError::FileNotFound(missing)) if missing == path));
+    }
+
+    #[test]
+    fn dump_reads_vram_the_ppu_has_locked() {
+        let mut memory = Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        for (i, value) in [0x11, 0x22, 0x33, 0x44].into_iter().enumerate() {
+            memory.poke(0x8010 + i as u16, value);
+        }
+        memory.set_ppu_access(false, false);
+        assert_eq!(memory.read_u8(0x8010), 0xFF);
+        assert_eq!(memory.dump(0x800F, 6), [0x00, 0x11, 0x22, 0x33, 0x44, 0x00]);
+    }
+
+    #[test]
+    fn poked_wram_dumps_back_and_wraps_past_ffff() {
+        let mut memory = wrapping_memory();
+        memory.poke(WRAM_START, 0x12);
+        memory.poke(WRAM_START + 1, 0x34);
+        assert_eq!(memory.dump(WRAM_START, 2), [0x12, 0x34]);
+        assert_eq!(memory.read_u8(WRAM_START + 1), 0x34);
+
+        memory.poke(INTERRUPT_ENABLE_ADDRESS, 0x1F);
+        assert_eq!(memory.dump(0xFFFF, 2), [0x1F, 0xAB]);
+    }
+
+    #[test]
+    fn poke_only_reaches_rom_once_allowed() {
+        let mut memory = wrapping_memory();
+        //Poking 0x0A here would turn on ram if it went through the mbc
+        memory.poke(0x0000, 0x0A);
+        assert_eq!(memory.dump(0x0000, 1), [0xAB]);
+        memory.write_u8(START_OF_CARTRIDGE_RAM, 0x5A);
+        assert_eq!(memory.read_u8(START_OF_CARTRIDGE_RAM), 0xFF);
+
+        memory.allow_rom_pokes(true);
+        memory.poke(0x0000, 0x0A);
+        assert_eq!(memory.dump(0x0000, 1), [0x0A]);
+    }
+
+    #[test]
+    fn word_read_at_ffff_wraps_to_0000() {
+        let mut memory = wrapping_memory();
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, 0xCD);
+        assert_eq!(memory.read_u16(0xFFFF), 0xABCD);
+    }
+
+    #[test]
+    fn word_write_at_ffff_wraps_to_0000() {
+        let mut memory = wrapping_memory();
+        memory.write_u8(START_OF_CARTRIDGE_RAM, 0x5A);
+        assert_eq!(memory.read_u8(START_OF_CARTRIDGE_RAM), 0xFF);
+        //The high byte lands on the mbc ram enable register
+        memory.write_u16(0xFFFF, 0x0A12);
+        assert_eq!(memory.read_u8(INTERRUPT_ENABLE_ADDRESS), 0x12);
+        memory.write_u8(START_OF_CARTRIDGE_RAM, 0x5A);
+        assert_eq!(memory.read_u8(START_OF_CARTRIDGE_RAM), 0x5A);
+    }
+
+    #[test]
+    fn vram_slice_matches_single_reads() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let mut memory = Memory::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Cgb).unwrap();
+        for bank in 0..VRAM_BANKS as u8 {
+            memory.write_u8(VBK_ADDRESS, bank);
+            for address in START_OF_VRAM..=END_OF_VRAM {
+                memory.write_u8(address, (address as u8).wrapping_mul(7) ^ (bank * 0x80));
+            }
+        }
+
+        let ranges = [
+            (START_OF_VRAM, 16),
+            (0x8FF0, 32),
+            (0x9800, 1),
+            (0x9F00, 0x100),
+        ];
+        for bank in 0..VRAM_BANKS as u8 {
+            for (start, len) in ranges {
+                let expected = (0..len as u16)
+                    .map(|offset| memory.read_vram(bank, start + offset))
+                    .collect::<Vec<_>>();
+                assert_eq!(memory.read_vram_slice(bank, start, len), expected);
+            }
+        }
+        //Bank 1 must really be the other bank and not a copy of bank 0
+        assert_ne!(
+            memory.read_vram_slice(0, START_OF_VRAM, 16),
+            memory.read_vram_slice(1, START_OF_VRAM, 16)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn vram_slice_past_the_bank_panics() {
+        let memory = Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        memory.read_vram_slice(1, END_OF_VRAM, 2);
+    }
+
+    #[test]
+    fn ff_fill_shows_up_in_unwritten_wram() {
+        let mut memory = filled_memory(&RamFill::Ones);
+        memory.write_u8(WRAM_START, 0x12);
+        assert_eq!(memory.read_u8(WRAM_START), 0x12);
+        assert_eq!(memory.read_u8(WRAM_START + 1), 0xFF);
+        assert_eq!(memory.read_u8(WRAM_END), 0xFF);
+        assert_eq!(
+            Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg)
+                .unwrap()
+                .read_u8(WRAM_END),
+            0x00
+        );
+    }
+
+    #[test]
+    fn pattern_fill_repeats_through_wram() {
+        let memory = filled_memory(&RamFill::Pattern(vec![0x00, 0xFF, 0x5A]));
+        assert_eq!(memory.read_u8(WRAM_START), 0x00);
+        assert_eq!(memory.read_u8(WRAM_START + 1), 0xFF);
+        assert_eq!(memory.read_u8(WRAM_START + 2), 0x5A);
+        assert_eq!(memory.read_u8(WRAM_START + 3), 0x00);
+    }
+
+    #[test]
+    fn parses_ram_fills() {
+        assert_eq!(RamFill::parse("zero"), Some(RamFill::Zero));
+        assert_eq!(RamFill::parse("ff"), Some(RamFill::Ones));
+        assert_eq!(
+            RamFill::parse("00fF5a"),
+            Some(RamFill::Pattern(vec![0x00, 0xFF, 0x5A]))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_ram_fills() {
+        assert_eq!(RamFill::parse(""), None);
+        //Odd lengths leave half a byte over
+        assert_eq!(RamFill::parse("00f"), None);
+        assert_eq!(RamFill::parse("0g"), None);
+        assert_eq!(RamFill::parse("zz"), None);
+        //Two bytes long but not two hex digits
+        assert_eq!(RamFill::parse("é"), None);
+    }
+
+    #[test]
+    fn post_boot_io_holds_the_documented_values() {
+        let mut memory = Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        memory.init_post_boot_io();
+        for (address, value) in [
+            (0xFF40, 0x91), // LCDC
+            (0xFF42, 0x00), // SCY
+            (0xFF43, 0x00), // SCX
+            (0xFF47, 0xFC), // BGP
+            (0xFF05, 0x00), // TIMA
+            (0xFF24, 0x77), // NR50
+            (0xFF25, 0xF3), // NR51
+            (0xFF26, 0xF1), // NR52
+            (0xFFFF, 0x00), // IE
+        ] {
+            assert_eq!(memory.read_u8(address), value, "{:04x}", address);
+        }
+        assert!(memory.apu_enabled());
+        assert!(!memory.booting());
+    }
+
+    #[test]
+    fn vbk_switches_between_two_vram_banks() {
+        let mut memory = cgb_memory();
+        memory.write_u8(START_OF_VRAM, 0x11);
+        memory.write_u8(VBK_ADDRESS, 0x01);
+        assert_eq!(memory.read_u8(VBK_ADDRESS), 0xFF);
+        assert_eq!(memory.read_u8(START_OF_VRAM), 0x00);
+        memory.write_u8(START_OF_VRAM, 0x22);
+        memory.write_u8(VBK_ADDRESS, 0x00);
+        assert_eq!(memory.read_u8(VBK_ADDRESS), 0xFE);
+        assert_eq!(memory.read_u8(START_OF_VRAM), 0x11);
+        memory.write_u8(VBK_ADDRESS, 0x01);
+        assert_eq!(memory.read_u8(START_OF_VRAM), 0x22);
+
+        //A dmg only has the one bank
+        let mut memory = Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        memory.write_u8(START_OF_VRAM, 0x11);
+        memory.write_u8(VBK_ADDRESS, 0x01);
+        assert_eq!(memory.read_u8(START_OF_VRAM), 0x11);
+    }
+
+    #[test]
+    fn svbk_switches_the_upper_work_ram_bank() {
+        let mut memory = cgb_memory();
+        memory.write_u8(0xC000, 0xAA);
+        memory.write_u8(0xD000, 0x11);
+        memory.write_u8(SVBK_ADDRESS, 0x02);
+        assert_eq!(memory.read_u8(0xD000), 0x00);
+        memory.write_u8(0xD000, 0x22);
+        //Echo ram follows whichever bank is in
+        assert_eq!(memory.read_u8(0xF000), 0x22);
+        //Bank 0 is always at 0xC000
+        assert_eq!(memory.read_u8(0xC000), 0xAA);
+
+        //Selecting bank 0 gets bank 1
+        memory.write_u8(SVBK_ADDRESS, 0x00);
+        assert_eq!(memory.read_u8(SVBK_ADDRESS), 0xF9);
+        assert_eq!(memory.read_u8(0xD000), 0x11);
+        assert_eq!(memory.read_u8(0xF000), 0x11);
+        memory.write_u8(SVBK_ADDRESS, 0x02);
+        assert_eq!(memory.read_u8(0xD000), 0x22);
+    }
+
+    #[test]
+    fn only_cgb_roms_on_a_cgb_get_cgb_mode() {
+        let cgb_mode = |flag: u8, model: Model| {
+            let mut rom = vec![0; 0x8000];
+            rom[0x143] = flag;
+            let rom = RomChunk::from_bytes(rom).unwrap();
+            Memory::new(None, rom, model).unwrap().cgb_mode()
+        };
+        assert!(cgb_mode(0x80, Model::Cgb));
+        assert!(cgb_mode(0xC0, Model::Cgb));
+        assert!(!cgb_mode(0x00, Model::Cgb));
+        //A dmg runs even cgb only roms without it
+        assert!(!cgb_mode(0x80, Model::Dmg));
+        assert!(!cgb_mode(0xC0, Model::Dmg));
+    }
+
+    #[test]
+    fn game_genie_codes_patch_rom_reads() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x4A17] = 0xC8;
+        let mut memory = Memory::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Dmg).unwrap();
+        memory.add_cheat("00A-17B-C49").unwrap();
+        assert_eq!(memory.read_u8(0x4A17), 0x00);
+        assert_eq!(memory.read_u8(0x4A18), 0x00);
+
+        //The compare byte doesn't match so the rom shows through
+        let mut rom = vec![0; 0x8000];
+        rom[0x4A17] = 0x12;
+        let mut memory = Memory::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Dmg).unwrap();
+        memory.add_cheat("00A-17B-C49").unwrap();
+        assert_eq!(memory.read_u8(0x4A17), 0x12);
+    }
+
+    #[test]
+    fn cpu_writes_to_ly_never_set_a_line() {
+        let mut memory = Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        memory.init_post_boot_io();
+        //The ppu puts its own line in LY
+        memory.write_special_regsiter(LY_ADDRESS, 0x20);
+        assert_eq!(memory.read_u8(LY_ADDRESS), 0x20);
+        for value in [0x01, 0x55, 0x99, 0xFF] {
+            memory.write_u8(LY_ADDRESS, value);
+            assert_eq!(memory.read_u8(LY_ADDRESS), 0);
+            assert!(memory.take_ly_reset());
+        }
+        assert!(!memory.take_ly_reset());
+    }
+
+    #[test]
+    fn writes_to_watched_addresses_are_recorded() {
+        let mut memory = Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        memory.watch(0xFF40);
+        memory.watch(0xFF40);
+        memory.set_current_pc(0x0150);
+        memory.write_u8(0xFF40, 0x91);
+        memory.write_u8(0xFF41, 0x40);
+        memory.set_current_pc(0x0153);
+        memory.write_u8(0xFF40, 0x11);
+        assert_eq!(
+            memory.take_watched_writes(),
+            [
+                WatchedWrite {
+                    address: 0xFF40,
+                    value: 0x91,
+                    pc: 0x0150,
+                },
+                WatchedWrite {
+                    address: 0xFF40,
+                    value: 0x11,
+                    pc: 0x0153,
+                },
+            ]
+        );
+        assert!(memory.take_watched_writes().is_empty());
+
+        //Only the latest writes are kept
+        for value in 0..=MAX_WATCHED_WRITES {
+            memory.write_u8(0xFF40, value as u8);
+        }
+        let writes = memory.take_watched_writes();
+        assert_eq!(writes.len(), MAX_WATCHED_WRITES);
+        assert_eq!(writes[0].value, 1);
+    }
+
+    #[test]
+    fn boot_rom_covers_0x00_to_0xff_until_handed_over() {
+        let mut boot = vec![0; 0x100];
+        boot[0xFF] = 0xAB;
+        let mut rom = vec![0; 0x8000];
+        rom[0xFF] = 0x12;
+        rom[0x100] = 0x34;
+        let mut memory = Memory::new(
+            Some(RomChunk::from_bytes(boot).unwrap()),
+            RomChunk::from_bytes(rom).unwrap(),
+            Model::Dmg,
+        )
+        .unwrap();
+        assert!(memory.booting());
+        assert_eq!(memory.read_u8(0x00FF), 0xAB);
+        assert_eq!(memory.read_u8(0x0100), 0x34);
+
+        memory.write_u8(BOOT_ROM_ADDRESS, 0x01);
+        assert!(!memory.booting());
+        assert_eq!(memory.read_u8(0x00FF), 0x12);
+        //There is no turning it back on
+        memory.write_u8(BOOT_ROM_ADDRESS, 0x00);
+        assert_eq!(memory.read_u8(0x00FF), 0x12);
     }
 }
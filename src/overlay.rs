@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+use crate::ppu::set_pixel;
+
+//How long the counts are gathered for before the numbers on screen change
+const SAMPLE_PERIOD: Duration = Duration::from_secs(1);
+//The real hardware runs 4194304 / 70224 frames a second
+const GAMEBOY_FPS: f64 = 4_194_304.0 / 70_224.0;
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+//Text starts this far in from the top left corner with a dark box around it so it reads on any game
+const TEXT_MARGIN: usize = 1;
+const TEXT_COLOR: [u8; 3] = [0xFF, 0xFF, 0xFF];
+const BACKGROUND_COLOR: [u8; 3] = [0x00, 0x00, 0x00];
+
+//Counts frames to show how fast things are going, frames shown is the real fps while frames run
+//against the gameboy's own rate gives the emulated speed
+pub struct PerfOverlay {
+    sample_start: Instant,
+    frames_run: u32,
+    frames_shown: u32,
+    text: String,
+}
+
+impl Default for PerfOverlay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PerfOverlay {
+    pub fn new() -> Self {
+        Self {
+            sample_start: Instant::now(),
+            frames_run: 0,
+            frames_shown: 0,
+            text: "0 FPS 0%".to_string(),
+        }
+    }
+
+    pub fn frame_run(&mut self) {
+        self.frames_run += 1;
+    }
+
+    pub fn frame_shown(&mut self) {
+        self.frames_shown += 1;
+        let elapsed = self.sample_start.elapsed();
+        if elapsed >= SAMPLE_PERIOD {
+            let seconds = elapsed.as_secs_f64();
+            self.text = format!(
+                "{} FPS {}%",
+                (self.frames_shown as f64 / seconds).round(),
+                (self.frames_run as f64 / seconds / GAMEBOY_FPS * 100.0).round()
+            );
+            self.sample_start = Instant::now();
+            self.frames_run = 0;
+            self.frames_shown = 0;
+        }
+    }
+
+    pub fn draw(&self, framebuffer: &mut [u8]) {
+        draw_text(framebuffer, TEXT_MARGIN, TEXT_MARGIN, &self.text);
+    }
+}
+
+//Draws the text over whatever is in the framebuffer, anything hanging off the screen is clipped
+pub fn draw_text(framebuffer: &mut [u8], x: usize, y: usize, text: &str) {
+    let width = text.chars().count() * (GLYPH_WIDTH + 1) + 1;
+    fill_rect(
+        framebuffer,
+        x.saturating_sub(1),
+        y.saturating_sub(1),
+        width,
+        GLYPH_HEIGHT + 2,
+    );
+    for (index, character) in text.chars().enumerate() {
+        let rows = glyph(character);
+        let left = x + index * (GLYPH_WIDTH + 1);
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (0b100 >> column) != 0 {
+                    set_pixel(framebuffer, left + column, y + row, TEXT_COLOR);
+                }
+            }
+        }
+    }
+}
+
+fn fill_rect(framebuffer: &mut [u8], x: usize, y: usize, width: usize, height: usize) {
+    for row in y..y + height {
+        for column in x..x + width {
+            set_pixel(framebuffer, column, row, BACKGROUND_COLOR);
+        }
+    }
+}
+
+//A 3x5 font with just what the overlay needs, each row is 3 bits with the leftmost pixel in the top
+//bit. Anything else comes out blank
+fn glyph(character: char) -> [u8; GLYPH_HEIGHT] {
+    match character {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::{BYTES_PER_PIXEL, FRAMEBUFFER_SIZE, FRAMEBUFFER_STRIDE};
+
+    const GRAY: u8 = 0x80;
+
+    fn pixel(framebuffer: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let offset = y * FRAMEBUFFER_STRIDE + x * BYTES_PER_PIXEL as usize;
+        framebuffer[offset..offset + 3].try_into().unwrap()
+    }
+
+    #[test]
+    fn draws_text_in_the_top_left() {
+        let mut framebuffer = vec![GRAY; FRAMEBUFFER_SIZE];
+        PerfOverlay::new().draw(&mut framebuffer);
+
+        //The box starts in the very corner and the first glyph's top row is solid
+        assert_eq!(pixel(&framebuffer, 0, 0), BACKGROUND_COLOR);
+        assert_eq!(pixel(&framebuffer, TEXT_MARGIN, TEXT_MARGIN), TEXT_COLOR);
+        let top_left = (0..GLYPH_HEIGHT + 2)
+            .flat_map(|y| (0..40).map(move |x| (x, y)))
+            .filter(|(x, y)| pixel(&framebuffer, *x, *y) == TEXT_COLOR)
+            .count();
+        assert!(top_left > 10);
+        //Everything outside it is left alone
+        assert_eq!(pixel(&framebuffer, 80, 72), [GRAY; 3]);
+        assert_eq!(pixel(&framebuffer, 0, GLYPH_HEIGHT + 2), [GRAY; 3]);
+    }
+}
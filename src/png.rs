@@ -0,0 +1,130 @@
+//Just enough of a png encoder to write out screenshots, the image data is stored uncompressed
+//inside the zlib stream so there is nothing to pull in for deflate
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const BIT_DEPTH: u8 = 8;
+const COLOR_TYPE_RGB: u8 = 2;
+const FILTER_NONE: u8 = 0;
+// Stored deflate blocks can't hold more than this
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+// Deflate with a 32K window and no preset dictionary
+const ZLIB_HEADER: [u8; 2] = [0x78, 0x01];
+
+//Encodes tightly packed 8 bit rgb pixels as a png
+pub fn encode_rgb(width: u32, height: u32, pixels: &[u8]) -> Vec<u8> {
+    let mut png = SIGNATURE.to_vec();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    //compression, filter and interlace methods are all the default 0
+    header.extend([BIT_DEPTH, COLOR_TYPE_RGB, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    //Every row starts with the filter it was encoded with
+    let row_size = width as usize * 3;
+    let mut image = Vec::with_capacity((row_size + 1) * height as usize);
+    for row in pixels.chunks(row_size).take(height as usize) {
+        image.push(FILTER_NONE);
+        image.extend(row);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&image));
+
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend(chunk_type);
+    png.extend(data);
+    //The crc covers the type as well as the data
+    let crc = crc32(&png[start..]);
+    png.extend(crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = ZLIB_HEADER.to_vec();
+    let mut blocks: Vec<&[u8]> = data.chunks(MAX_STORED_BLOCK).collect();
+    //Even no data needs a final block
+    if blocks.is_empty() {
+        blocks.push(&[]);
+    }
+    let last = blocks.len() - 1;
+    for (i, block) in blocks.into_iter().enumerate() {
+        //Bit 0 marks the last block and the block type bits of 0 mean stored
+        stream.push((i == last) as u8);
+        let len = block.len() as u16;
+        stream.extend(len.to_le_bytes());
+        stream.extend((!len).to_le_bytes());
+        stream.extend(block);
+    }
+    stream.extend(adler32(data).to_be_bytes());
+    stream
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::{FRAMEBUFFER_SIZE, GAMEBOY_SCREEN_HEIGHT, GAMEBOY_SCREEN_WIDTH};
+
+    fn be_u32(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes(bytes[..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn framebuffer_gets_a_png_header() {
+        let pixels = vec![0x80; FRAMEBUFFER_SIZE];
+        let png = encode_rgb(GAMEBOY_SCREEN_WIDTH, GAMEBOY_SCREEN_HEIGHT, &pixels);
+        assert_eq!(png[..8], SIGNATURE);
+        //IHDR is always the first chunk and always 13 bytes
+        assert_eq!(be_u32(&png[8..]), 13);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(be_u32(&png[16..]), GAMEBOY_SCREEN_WIDTH);
+        assert_eq!(be_u32(&png[20..]), GAMEBOY_SCREEN_HEIGHT);
+        assert_eq!(png[24..29], [BIT_DEPTH, COLOR_TYPE_RGB, 0, 0, 0]);
+        assert_eq!(be_u32(&png[29..]), crc32(&png[12..29]));
+        //An empty IEND with its well known crc closes it off
+        assert_eq!(
+            png[png.len() - 12..],
+            [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]
+        );
+    }
+
+    #[test]
+    fn zlib_stream_holds_the_data_as_is() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+        let stream = zlib_stored(b"abc");
+        assert_eq!(stream[..2], ZLIB_HEADER);
+        //One final stored block of 3 bytes
+        assert_eq!(stream[2..7], [0x01, 0x03, 0x00, 0xFC, 0xFF]);
+        assert_eq!(&stream[7..10], b"abc");
+        assert_eq!(be_u32(&stream[10..]), adler32(b"abc"));
+    }
+}
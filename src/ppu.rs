@@ -1,29 +1,115 @@
-use std::vec;
+use std::{collections::VecDeque, vec};
 
-use log::{info, trace};
+use log::trace;
 
-use crate::{memory::Memory, sdl::BYTES_PER_PIXEL};
+use crate::memory::{Memory, LCD_STAT_INTERRUPT, LY_ADDRESS, VBLANK_INTERRUPT, VRAM_TILES};
 
 pub const GAMEBOY_SCREEN_WIDTH: u32 = 160;
 pub const GAMEBOY_SCREEN_HEIGHT: u32 = 144;
+pub const BYTES_PER_PIXEL: u32 = 3;
+pub const FRAMEBUFFER_SIZE: usize =
+    (GAMEBOY_SCREEN_WIDTH * GAMEBOY_SCREEN_HEIGHT * BYTES_PER_PIXEL) as usize;
+//Bytes from the start of one row of the framebuffer to the next
+pub const FRAMEBUFFER_STRIDE: usize = (GAMEBOY_SCREEN_WIDTH * BYTES_PER_PIXEL) as usize;
 
 const TILESET_START_ADDRESS: u16 = 0x8000;
 const TILE_SIZE: usize = 16;
+const TILE_PIXELS: usize = 64;
+//All of 0x8000-0x97FF laid out 16 tiles across and 24 down
+const TILESET_TILES: u16 = 384;
+const TILESET_TILES_PER_ROW: u16 = 16;
+pub const TILESET_WIDTH: u32 = 128;
+pub const TILESET_HEIGHT: u32 = 192;
+//Each background map is 32x32 tiles
+const TILEMAP_TILES_PER_ROW: u16 = 32;
+pub const TILEMAP_WIDTH: u32 = 256;
+pub const TILEMAP_HEIGHT: u32 = 256;
+pub const LCDC: u16 = 0xFF40;
+const LCDC_TILE_DATA_SELECT: u8 = 1 << 4;
 
+//Shades from lightest to darkest, what a DMG framebuffer is drawn with unless set_palette swaps it
+pub const DEFAULT_PALETTE: [[u8; 3]; 4] =
+    [[255, 255, 255], [160, 160, 160], [96, 96, 96], [0, 0, 0]];
+
+//Every line takes the same number of dots, whatever drawing doesn't use is left for HBLANK
+const DOTS_PER_LINE: u16 = 456;
+const OAM_DOTS: u16 = 80;
+const VRAM_DOTS: u16 = 168;
+//154 lines of 456 dots
+const DOTS_PER_FRAME: u32 = 70224;
+
+pub const STAT: u16 = 0xFF41;
+//The low 2 bits of STAT hold the mode the ppu is in
+const STAT_MODE: u8 = 0x03;
+//Set in STAT while LY matches LYC
+const STAT_COINCIDENCE: u8 = 1 << 2;
+//Each of these lets one condition raise the STAT interrupt
+const STAT_HBLANK_SOURCE: u8 = 1 << 3;
+const STAT_VBLANK_SOURCE: u8 = 1 << 4;
+const STAT_OAM_SOURCE: u8 = 1 << 5;
+const STAT_LYC_SOURCE: u8 = 1 << 6;
+const SCY: u16 = 0xFF42;
+const SCX: u16 = 0xFF43;
+const LYC: u16 = 0xFF45;
+const BGP: u16 = 0xFF47;
 const WX: u16 = 0xFF4B;
 const WY: u16 = 0xFF4A;
+//WX holds the window's left edge plus 7, so 7 puts it at column 0 and anything past 166 is off
+//the right edge of the screen
+const WINDOW_X_OFFSET: i32 = 7;
+const MAX_VISIBLE_WX: u8 = 166;
+
+//Only the most recent mode changes are kept if nobody takes them
+const MAX_MODE_CHANGES: usize = 4096;
 
+const OAM_SPRITES: u16 = 40;
+//OAM search stops after this many sprites on a line, the rest aren't drawn
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+#[derive(Clone)]
 pub struct Ppu {
     lcd_control: LcdControl,
     current_mode: PpuMode,
     dots_in_mode: u16,
+    //How long drawing takes on the current line
+    vram_dots: u16,
     scanline: u8,
+    //The window keeps its own line count which only moves on lines where it was drawn
+    window_line: u8,
     wx: u8,
     wy: u8,
-    total_cycles: u64,
+    //Read when drawing starts so writes partway through a line only show up from the next one
+    scx: u8,
+    scy: u8,
+    bgp: u8,
+    palette: [[u8; 3]; 4],
+    total_dots: u64,
+    //Time passed since the last blank frame while the lcd is off
+    lcd_off_dots: u32,
+    //All the enabled STAT sources are ORed into one line and only a rising edge requests the
+    //interrupt, so while one source holds it high the others can't trigger another
+    stat_line: bool,
+    //Every mode the ppu enters gets recorded while this is on, for lining up raster effects with
+    //the writes that cause them
+    record_mode_changes: bool,
+    mode_changes: VecDeque<ModeChange>,
+    //Lines of the framebuffer that have changed since take_dirty_lines was last called
+    dirty_lines: [bool; GAMEBOY_SCREEN_HEIGHT as usize],
+    //Decoded pixels for every tile in both vram banks, memory marks the tiles written since they
+    //were decoded so only those get done again
+    tile_cache_enabled: bool,
+    tile_cache: Vec<[u8; TILE_PIXELS]>,
 }
 
-#[derive(Default)]
+//The ppu entering a mode, dot counts every 4MHz dot since the ppu was created
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ModeChange {
+    pub scanline: u8,
+    pub mode: PpuMode,
+    pub dot: u64,
+}
+
+#[derive(Default, Clone)]
 struct LcdControl {
     draw_background: bool,
     draw_sprites: bool,
@@ -35,52 +121,123 @@ struct LcdControl {
     lcd_enabled: bool,
 }
 
+#[derive(Clone, Copy)]
 struct Tile {
-    id: u16,
-    data: Vec<u8>,
+    //Color numbers row by row
+    pixels: [u8; TILE_PIXELS],
+    x_flip: bool,
+    y_flip: bool,
+}
+
+//CGB keeps an attribute byte for every map entry at the same address in vram bank 1
+#[derive(Default, Clone, Copy)]
+struct TileAttributes {
+    palette: u8,
+    bank: u8,
+    x_flip: bool,
+    y_flip: bool,
 }
 
 struct Sprite {
     pub x: i32,
     pub y: i32,
     pub tile: u8,
-    //TODO implement flags
+    //Set when background colors 1-3 should be drawn over the sprite
+    pub behind_background: bool,
+    //TODO implement the rest of the flags
 }
 
 impl Sprite {
-    fn fetch(id: u16, memory: &mut Memory) -> Option<Self> {
+    fn fetch(id: u16, memory: &mut Memory) -> Self {
         //each sprite is 4 bytes wide as follow y, x, tile/pattern number, flags
         let sprite_address = 0xFE00 + (id * 4);
-        let y = memory.read_u8(sprite_address) as i32;
-        let x = memory.read_u8(sprite_address + 1) as i32;
-        if x == 0 || y == 0 {
-            return None;
+        let y = memory.read_u8_unchecked(sprite_address) as i32 - 16;
+        let x = memory.read_u8_unchecked(sprite_address + 1) as i32 - 8;
+        let tile = memory.read_u8_unchecked(sprite_address + 2);
+        let flags = memory.read_u8_unchecked(sprite_address + 3);
+        Self {
+            x,
+            y,
+            tile,
+            behind_background: flags & 0x80 != 0,
+        }
+    }
+
+    fn on_line(&self, line: u8, height: i32) -> bool {
+        let line = line as i32;
+        line >= self.y && line < self.y + height
+    }
+}
+
+//Which sprite wins where sprites overlap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpritePriority {
+    //The DMG draws the one furthest left on top with OAM order breaking ties
+    Coordinate,
+    //A CGB running a CGB game just goes by OAM order
+    OamOrder,
+}
+
+impl SpritePriority {
+    fn for_mode(cgb_mode: bool) -> Self {
+        if cgb_mode {
+            SpritePriority::OamOrder
+        } else {
+            SpritePriority::Coordinate
         }
-        let y = y - 16;
-        let x = x - 8;
-        let tile = memory.read_u8(sprite_address + 2);
-        Some(Self { x, y, tile })
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-enum PpuMode {
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpuMode {
     OAM,
     VRAM,
     HBLANK,
     VBLANK,
 }
 
+impl PpuMode {
+    //How the mode shows up in the bottom of STAT
+    fn stat_bits(self) -> u8 {
+        match self {
+            PpuMode::HBLANK => 0,
+            PpuMode::VBLANK => 1,
+            PpuMode::OAM => 2,
+            PpuMode::VRAM => 3,
+        }
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Ppu {
     pub fn new() -> Self {
         Self {
             lcd_control: LcdControl::default(),
             current_mode: PpuMode::OAM,
             dots_in_mode: 0,
+            vram_dots: VRAM_DOTS,
             scanline: 0,
+            window_line: 0,
             wx: 0,
             wy: 0,
-            total_cycles: 0,
+            scx: 0,
+            scy: 0,
+            bgp: 0,
+            palette: DEFAULT_PALETTE,
+            total_dots: 0,
+            lcd_off_dots: 0,
+            stat_line: false,
+            record_mode_changes: false,
+            mode_changes: VecDeque::new(),
+            dirty_lines: [false; GAMEBOY_SCREEN_HEIGHT as usize],
+            tile_cache_enabled: true,
+            tile_cache: vec![[0; TILE_PIXELS]; VRAM_TILES * 2],
         }
     }
 
@@ -97,37 +254,155 @@ impl Ppu {
         }
     }
 
+    fn latch_registers(&mut self, memory: &Memory) {
+        self.scx = memory.read_u8(SCX);
+        self.scy = memory.read_u8(SCY);
+        self.bgp = memory.read_u8(BGP);
+    }
+
     fn enter_mode(&mut self, mode: PpuMode, memory: &mut Memory) {
         self.current_mode = mode;
+        if self.record_mode_changes {
+            if self.mode_changes.len() >= MAX_MODE_CHANGES {
+                self.mode_changes.pop_front();
+            }
+            //Whatever is in dots_in_mode already belongs to the new mode
+            self.mode_changes.push_back(ModeChange {
+                scanline: self.scanline,
+                mode,
+                dot: self.total_dots - self.dots_in_mode as u64,
+            });
+        }
+        //Starting VBLANK always requests the vblank interrupt and can request STAT as well, each
+        //sets its own IF bit so a game enabling both gets both
+        if let PpuMode::VBLANK = mode {
+            memory.request_interrupt(VBLANK_INTERRUPT);
+        }
+        self.update_stat(memory);
         self.reset_window(mode, memory);
+        if let PpuMode::VRAM = mode {
+            self.latch_registers(memory);
+        }
+        //OAM is busy while searching for sprites and both are busy while drawing
+        match mode {
+            PpuMode::OAM => memory.set_ppu_access(true, false),
+            PpuMode::VRAM => memory.set_ppu_access(false, false),
+            PpuMode::HBLANK | PpuMode::VBLANK => memory.set_ppu_access(true, true),
+        }
     }
 
-    fn fetch_tile(&self, address: u16, memory: &mut Memory) -> Tile {
-        let tile_id = memory.read_u8(address) as u16;
-        //something is wrong here lol
-        if !self.lcd_control.background_tile_data_select && tile_id < 128 {
-            Tile::new(tile_id + 0x100, memory)
+    //Only tiles that have been written since they were last decoded get decoded again
+    fn cached_tile(&mut self, tile_id: u16, bank: u8, memory: &mut Memory) -> Tile {
+        if !self.tile_cache_enabled {
+            return Tile::new(tile_id, bank, memory);
+        }
+        let pixels = &mut self.tile_cache[bank as usize * VRAM_TILES + tile_id as usize];
+        if memory.take_dirty_tile(bank, tile_id) {
+            *pixels = Tile::new(tile_id, bank, memory).pixels;
+        }
+        Tile {
+            pixels: *pixels,
+            x_flip: false,
+            y_flip: false,
+        }
+    }
+
+    fn fetch_tile(&mut self, address: u16, memory: &mut Memory) -> (Tile, TileAttributes) {
+        let tile_id = memory.read_vram(0, address) as u16;
+        let attributes = if memory.cgb_mode() {
+            TileAttributes::new(memory.read_vram(1, address))
+        } else {
+            TileAttributes::default()
+        };
+        let tile_id = tile_data_index(tile_id, self.lcd_control.background_tile_data_select);
+        let tile = self
+            .cached_tile(tile_id, attributes.bank, memory)
+            .flipped(attributes.x_flip, attributes.y_flip);
+        (tile, attributes)
+    }
+
+    //CGB games pick a color palette per tile, everything else goes through the shades
+    fn background_color(&self, memory: &Memory, attributes: TileAttributes, pixel: u8) -> [u8; 3] {
+        if memory.cgb_mode() {
+            rgb555_to_rgb(memory.background_color(attributes.palette, pixel))
         } else {
-            Tile::new(tile_id, memory)
+            self.palletize(self.shade(pixel))
         }
     }
 
+    //BGP holds 2 bits per color number picking which shade it is drawn with
+    fn shade(&self, pixel: u8) -> u8 {
+        (self.bgp >> (pixel * 2)) & 0x3
+    }
+
     fn change_scanline(&mut self, scanline: u8, memory: &mut Memory) {
         trace!("Trying to update scanline to {:x}", scanline);
         self.scanline = scanline;
-        memory.write_special_regsiter(0xFF44, self.scanline);
+        memory.write_special_regsiter(LY_ADDRESS, self.scanline);
+        self.update_stat(memory);
+    }
+
+    //Puts the mode and LY=LYC into STAT and requests the STAT interrupt when one of its enabled
+    //sources has just become true
+    fn update_stat(&mut self, memory: &mut Memory) {
+        let stat = memory.read_u8(STAT) & !(STAT_MODE | STAT_COINCIDENCE);
+        let coincidence = memory.read_u8(LYC) == self.scanline;
+        //With the lcd off STAT reads as HBLANK
+        let mode = if self.lcd_control.lcd_enabled {
+            self.current_mode.stat_bits()
+        } else {
+            0
+        };
+        let coincidence_bit = if coincidence { STAT_COINCIDENCE } else { 0 };
+        memory.write_special_regsiter(STAT, stat | mode | coincidence_bit);
+
+        let source_active = |source: u8, active: bool| stat & source != 0 && active;
+        let line = self.lcd_control.lcd_enabled
+            && (source_active(
+                STAT_HBLANK_SOURCE,
+                matches!(self.current_mode, PpuMode::HBLANK),
+            ) || source_active(
+                STAT_VBLANK_SOURCE,
+                matches!(self.current_mode, PpuMode::VBLANK),
+            ) || source_active(STAT_OAM_SOURCE, matches!(self.current_mode, PpuMode::OAM))
+                || source_active(STAT_LYC_SOURCE, coincidence));
+        if line && !self.stat_line {
+            memory.request_interrupt(LCD_STAT_INTERRUPT);
+        }
+        self.stat_line = line;
     }
 
+    //Lines that come out the same as last frame aren't marked dirty so a frontend can skip them
     fn draw_scanline(&mut self, memory: &mut Memory, pixel_data: &mut [u8]) {
+        //VBLANK lines are off the bottom of the screen so there is nothing to draw
+        if self.scanline as u32 >= GAMEBOY_SCREEN_HEIGHT {
+            return;
+        }
+        let line = self.scanline as usize;
+        let range = line * FRAMEBUFFER_STRIDE..(line + 1) * FRAMEBUFFER_STRIDE;
+        let mut previous = [0; FRAMEBUFFER_STRIDE];
+        if let Some(bytes) = pixel_data.get(range.clone()) {
+            previous.copy_from_slice(bytes);
+        }
+        self.draw_line(memory, pixel_data);
+        if pixel_data.get(range) != Some(&previous[..]) {
+            self.dirty_lines[line] = true;
+        }
+    }
+
+    fn draw_line(&mut self, memory: &mut Memory, pixel_data: &mut [u8]) {
         self.lcd_control.update(memory);
 
-        let scy = memory.read_u8(0xff42);
-        let scx = memory.read_u8(0xff43);
+        let scy = self.scy;
+        let scx = self.scx;
 
         let mut hits = vec![false; GAMEBOY_SCREEN_WIDTH as usize];
+        //On CGB the background bit only takes away background priority over sprites
+        let background_priority = self.lcd_control.draw_background;
 
-        if self.lcd_control.draw_background {
-            let map_line = scy + self.scanline;
+        if self.lcd_control.draw_background || memory.cgb_mode() {
+            //The background map is 256x256 so scrolling wraps around both ways
+            let map_line = scy.wrapping_add(self.scanline);
             let map_line_offset = ((map_line as u16) >> 3) << 5;
             let map_offset = if self.lcd_control.background_tile_select {
                 0x9C00
@@ -136,22 +411,24 @@ impl Ppu {
             } + map_line_offset;
             let mut line_offset = (scx >> 3) as u16;
             let mut tile_id_address = map_offset + line_offset;
-            let mut tile = self.fetch_tile(tile_id_address, memory);
+            let (mut tile, mut attributes) = self.fetch_tile(tile_id_address, memory);
 
+            //Start part way into the first tile and keep fetching tiles as we cross them so the
+            //partial tile on the right edge gets drawn as well
             let mut x = scx & 7;
-            let y = (self.scanline + scy) & 7;
+            let y = map_line & 7;
             for i in 0..GAMEBOY_SCREEN_WIDTH {
                 let pixel = tile.value_at(x, y);
-                if pixel != 0 {
+                if pixel != 0 && background_priority {
                     hits[i as usize] = true;
                 }
 
                 //TODO need to convert the value using the pallete so it isn't a pure black screen
-                Self::draw_pixel(
+                set_pixel(
                     pixel_data,
                     i as usize,
                     self.scanline as usize,
-                    Self::palletize(pixel),
+                    self.background_color(memory, attributes, pixel),
                 );
 
                 x += 1;
@@ -159,12 +436,21 @@ impl Ppu {
                     x = 0;
                     line_offset = (line_offset + 1) & 31;
                     tile_id_address = map_offset + line_offset;
-                    tile = self.fetch_tile(tile_id_address, memory);
+                    (tile, attributes) = self.fetch_tile(tile_id_address, memory);
                 }
             }
+        } else {
+            //Nothing else would overwrite whatever the line held before so blank it to shade 0
+            let white = self.palletize(0);
+            for x in 0..GAMEBOY_SCREEN_WIDTH as usize {
+                set_pixel(pixel_data, x, self.scanline as usize, white);
+            }
         }
-        if self.lcd_control.window_display && self.scanline >= self.wy {
-            let map_line = self.scanline - self.wy;
+        //On DMG the background bit turns the window off along with the background
+        let window_enabled = self.lcd_control.window_display
+            && (self.lcd_control.draw_background || memory.cgb_mode());
+        if window_enabled && self.scanline >= self.wy && self.wx <= MAX_VISIBLE_WX {
+            let map_line = self.window_line;
             let map_line_offset = ((map_line as u16) >> 3) << 5;
 
             let map_offset = if self.lcd_control.window_tile_map_select {
@@ -173,117 +459,301 @@ impl Ppu {
                 0x9800
             } + map_line_offset;
 
-            let mut line_offset = (self.wx >> 3) as u16;
-            let mut tile_id = map_offset + line_offset;
-            let mut tile = self.fetch_tile(tile_id, memory);
+            //Below 7 the window starts off the left edge so its first few columns get cut off
+            let window_start = self.wx as i32 - WINDOW_X_OFFSET;
+            let first_column = window_start.max(0) as u32;
+            let mut window_x = (first_column as i32 - window_start) as u8;
+            let (mut tile, mut attributes) =
+                self.fetch_tile(map_offset + (window_x >> 3) as u16, memory);
 
-            let mut x = 0;
-            let y = ((self.scanline - self.wy) & 7) as u16;
+            let y = (self.window_line & 7) as u16;
 
-            for i in 0..GAMEBOY_SCREEN_WIDTH {
-                let val = tile.value_at(x, y as u8);
+            for i in first_column..GAMEBOY_SCREEN_WIDTH {
+                let val = tile.value_at(window_x & 7, y as u8);
 
-                if val != 0 {
+                if val != 0 && background_priority {
                     hits[i as usize] = true;
                 }
 
-                Self::draw_pixel(
+                set_pixel(
                     pixel_data,
                     i as usize,
                     self.scanline as usize,
-                    Self::palletize(val),
+                    self.background_color(memory, attributes, val),
                 );
 
-                x += 1;
+                window_x += 1;
 
-                if x == 8 {
-                    x = 0;
-                    line_offset = (line_offset + 1) & 31;
-                    tile_id = map_offset + line_offset;
-                    tile = self.fetch_tile(tile_id, memory);
+                if window_x & 7 == 0 {
+                    (tile, attributes) =
+                        self.fetch_tile(map_offset + (window_x >> 3) as u16, memory);
                 }
             }
+            self.window_line += 1;
         }
 
         if self.lcd_control.draw_sprites {
-            // you can draw up to 40 sprites in a scanline
-            for id in 0..40 {
-                if let Some(sprite) = Sprite::fetch(id, memory) {
-                    let sprite_tile = Tile::new(sprite.tile as u16, memory);
-                    //dumb way not right just drawing the sprite
-                    for x in 0..8u8 {
-                        let pixel = sprite_tile.value_at(x, self.scanline - sprite.y as u8);
-                        Self::draw_pixel(
-                            pixel_data,
-                            (sprite.x + x as i32) as usize,
-                            self.scanline as usize,
-                            Self::palletize(pixel),
-                        );
-                    }
+            self.draw_sprites(memory, pixel_data, &hits);
+        }
+    }
+
+    //hits marks the columns where the background or window drew something other than color 0
+    fn draw_sprites(&mut self, memory: &mut Memory, pixel_data: &mut [u8], hits: &[bool]) {
+        let height = if self.lcd_control.big_sprites { 16 } else { 8 };
+        //Only the first 10 sprites in OAM that are on this line get drawn, even ones that are off
+        //the side of the screen use up a slot
+        let mut sprites: Vec<Sprite> = (0..OAM_SPRITES)
+            .map(|id| Sprite::fetch(id, memory))
+            .filter(|sprite| sprite.on_line(self.scanline, height))
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+        //Whichever sprite wins a column has to come first, the sort is stable so OAM order still
+        //breaks ties between sprites at the same x
+        if let SpritePriority::Coordinate = SpritePriority::for_mode(memory.cgb_mode()) {
+            sprites.sort_by_key(|sprite| sprite.x);
+        }
+        //Once a sprite has an opaque pixel in a column the sprites behind it lose that column even
+        //if the background then ends up being drawn over it
+        let mut claimed = [false; GAMEBOY_SCREEN_WIDTH as usize];
+        for sprite in sprites.iter() {
+            let row = (self.scanline as i32 - sprite.y) as u16;
+            //Tall sprites are two tiles one after the other and ignore the bottom bit of the id
+            let tile_id = if height == 16 {
+                (sprite.tile & 0xFE) as u16 + row / 8
+            } else {
+                sprite.tile as u16
+            };
+            let sprite_tile = self.cached_tile(tile_id, 0, memory);
+            for x in 0..8u8 {
+                let screen_x = sprite.x + x as i32;
+                if screen_x < 0 || screen_x >= GAMEBOY_SCREEN_WIDTH as i32 {
+                    continue;
+                }
+                let pixel = sprite_tile.value_at(x, (row % 8) as u8);
+                //Color 0 is transparent for sprites
+                if pixel == 0 || claimed[screen_x as usize] {
+                    continue;
                 }
+                claimed[screen_x as usize] = true;
+                if sprite.behind_background && hits[screen_x as usize] {
+                    continue;
+                }
+                set_pixel(
+                    pixel_data,
+                    screen_x as usize,
+                    self.scanline as usize,
+                    self.palletize(pixel),
+                );
             }
         }
     }
 
-    fn palletize(pixel: u8) -> u8 {
-        let pallete = [255, 160, 96, 0];
-        pallete[(pixel & 0x3) as usize]
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcd_control.lcd_enabled
     }
 
-    fn draw_pixel(pixel_data: &mut [u8], x: usize, y: usize, pixel: u8) {
-        let offset = (GAMEBOY_SCREEN_WIDTH * 3) as usize * y;
-        for i in 0..BYTES_PER_PIXEL as usize {
-            pixel_data[(x * 3) + offset + i] = pixel;
+    pub fn set_mode_change_recording(&mut self, enabled: bool) {
+        self.record_mode_changes = enabled;
+        if !enabled {
+            self.mode_changes.clear();
         }
     }
-    pub fn step(&mut self, memory: &mut Memory, pixel_data: &mut [u8]) -> bool {
-        //each cpu cycle is 4 dots
-        self.dots_in_mode += memory.cpu_cycles * 4;
-        self.total_cycles += memory.cpu_cycles as u64;
 
+    //Hands back every mode change recorded since the last call, oldest first
+    pub fn take_mode_changes(&mut self) -> Vec<ModeChange> {
+        self.mode_changes.drain(..).collect()
+    }
+
+    //Hands back which lines have changed since the last call and starts tracking afresh
+    pub fn take_dirty_lines(&mut self) -> [bool; GAMEBOY_SCREEN_HEIGHT as usize] {
+        std::mem::replace(
+            &mut self.dirty_lines,
+            [false; GAMEBOY_SCREEN_HEIGHT as usize],
+        )
+    }
+
+    //Turning the cache off decodes every tile as it is drawn, for checking the cache isn't to blame
+    //for a glitch
+    pub fn set_tile_cache(&mut self, enabled: bool) {
+        self.tile_cache_enabled = enabled;
+    }
+
+    //Swap out the rgb colors used for each of the 4 shades
+    pub fn set_palette(&mut self, palette: [[u8; 3]; 4]) {
+        self.palette = palette;
+    }
+
+    //Draws every tile in vram into a TILESET_WIDTH x TILESET_HEIGHT buffer for debugging, this
+    //doesn't touch any of the scanline state so it can be called at any time
+    pub fn render_tileset(&self, memory: &Memory, out: &mut [u8]) {
+        let bgp = memory.read_u8(BGP);
+        for tile_id in 0..TILESET_TILES {
+            let tile = Tile::new(tile_id, 0, memory);
+            let tile_x = (tile_id % TILESET_TILES_PER_ROW) as usize * 8;
+            let tile_y = (tile_id / TILESET_TILES_PER_ROW) as usize * 8;
+            for y in 0..8u8 {
+                for x in 0..8u8 {
+                    //BGP holds 2 bits per color number picking which shade it is drawn with
+                    let shade = (bgp >> (tile.value_at(x, y) * 2)) & 0x3;
+                    let offset =
+                        ((tile_y + y as usize) * TILESET_WIDTH as usize + tile_x + x as usize)
+                            * BYTES_PER_PIXEL as usize;
+                    out[offset..offset + BYTES_PER_PIXEL as usize]
+                        .copy_from_slice(&self.palletize(shade));
+                }
+            }
+        }
+    }
+
+    //Draws the whole 32x32 tile map at 0x9800, or 0x9C00 with map_select, into a TILEMAP_WIDTH x
+    //TILEMAP_HEIGHT buffer so the parts scrolled off screen can be seen too. Like render_tileset
+    //it reads LCDC and BGP straight from memory and leaves the scanline state alone
+    pub fn render_tilemap(&self, memory: &Memory, map_select: bool, out: &mut [u8]) {
+        let map_address = if map_select { 0x9C00 } else { 0x9800 };
+        let unsigned_tile_data = memory.read_u8(LCDC) & LCDC_TILE_DATA_SELECT != 0;
+        let bgp = memory.read_u8(BGP);
+        for map_index in 0..TILEMAP_TILES_PER_ROW * TILEMAP_TILES_PER_ROW {
+            let tile_id = memory.read_vram(0, map_address + map_index) as u16;
+            let tile = Tile::new(tile_data_index(tile_id, unsigned_tile_data), 0, memory);
+            let tile_x = (map_index % TILEMAP_TILES_PER_ROW) as usize * 8;
+            let tile_y = (map_index / TILEMAP_TILES_PER_ROW) as usize * 8;
+            for y in 0..8u8 {
+                for x in 0..8u8 {
+                    let shade = (bgp >> (tile.value_at(x, y) * 2)) & 0x3;
+                    let offset =
+                        ((tile_y + y as usize) * TILEMAP_WIDTH as usize + tile_x + x as usize)
+                            * BYTES_PER_PIXEL as usize;
+                    out[offset..offset + BYTES_PER_PIXEL as usize]
+                        .copy_from_slice(&self.palletize(shade));
+                }
+            }
+        }
+    }
+
+    fn palletize(&self, pixel: u8) -> [u8; 3] {
+        self.palette[(pixel & 0x3) as usize]
+    }
+
+    //Turning the lcd off resets LY and blanks the screen until it comes back on
+    fn disable_lcd(&mut self, memory: &mut Memory, pixel_data: &mut [u8]) {
+        self.change_scanline(0, memory);
+        self.window_line = 0;
+        self.dots_in_mode = 0;
+        self.lcd_off_dots = 0;
+        self.enter_mode(PpuMode::HBLANK, memory);
+        let white = self.palletize(0);
+        for (line, row) in pixel_data.chunks_mut(FRAMEBUFFER_STRIDE).enumerate() {
+            if row
+                .chunks(BYTES_PER_PIXEL as usize)
+                .any(|pixel| pixel != white)
+            {
+                row.chunks_mut(BYTES_PER_PIXEL as usize)
+                    .for_each(|pixel| pixel.copy_from_slice(&white));
+                self.dirty_lines[line] = true;
+            }
+        }
+    }
+
+    //Used when the lcd comes back on and when the cpu writes LY, either way drawing starts again
+    //from the top of a frame
+    fn restart_frame(&mut self, memory: &mut Memory) {
+        self.change_scanline(0, memory);
+        self.window_line = 0;
+        self.dots_in_mode = 0;
+        self.enter_mode(PpuMode::OAM, memory);
+    }
+
+    //Dots run at 4MHz whatever speed the cpu is running at so the caller converts cpu cycles
+    pub fn step(&mut self, memory: &mut Memory, pixel_data: &mut [u8], dots: u16) -> bool {
+        self.total_dots += dots as u64;
+
+        let was_enabled = self.lcd_control.lcd_enabled;
+        self.lcd_control.update(memory);
+        if !self.lcd_control.lcd_enabled {
+            if was_enabled {
+                self.disable_lcd(memory, pixel_data);
+                //redraw once so the blank screen shows up
+                return true;
+            }
+            //Nothing gets drawn but frames still need to finish on time so anything waiting on
+            //them doesn't stall while a game has the screen off
+            self.lcd_off_dots += dots as u32;
+            if self.lcd_off_dots >= DOTS_PER_FRAME {
+                self.lcd_off_dots -= DOTS_PER_FRAME;
+                return true;
+            }
+            return false;
+        }
+        if memory.take_ly_reset() || !was_enabled {
+            self.restart_frame(memory);
+        }
+        //LYC and the STAT sources can be changed partway through a line
+        self.update_stat(memory);
+
+        self.dots_in_mode += dots;
+
+        //When the cpu runs in batches there can be enough dots for several modes at once
+        while let Some(frame_completed) = self.next_mode(memory, pixel_data) {
+            //Whatever is left over carries into the next frame on the next step
+            if frame_completed {
+                return true;
+            }
+        }
+        false
+    }
+
+    //Moves on to the next mode if enough dots have passed, returning whether that finished a frame
+    fn next_mode(&mut self, memory: &mut Memory, pixel_data: &mut [u8]) -> Option<bool> {
         match self.current_mode {
             PpuMode::OAM => {
-                //80 dots in OAM
-                if self.dots_in_mode >= 80 {
-                    self.dots_in_mode -= 80;
-                    self.enter_mode(PpuMode::VRAM, memory);
+                if self.dots_in_mode < OAM_DOTS {
+                    return None;
                 }
-                false
+                self.dots_in_mode -= OAM_DOTS;
+                //The fine scroll pixels still get fetched and thrown away which takes time
+                //TODO sprites add roughly another 10 dots each
+                self.vram_dots = VRAM_DOTS + (memory.read_u8(SCX) & 7) as u16;
+                self.enter_mode(PpuMode::VRAM, memory);
+                Some(false)
             }
             PpuMode::VRAM => {
-                //168 dots plus 10 more per sprite in VRAM
-                if self.dots_in_mode >= 168 {
-                    self.dots_in_mode -= 168;
-                    self.enter_mode(PpuMode::HBLANK, memory);
-                    self.draw_scanline(memory, pixel_data);
+                if self.dots_in_mode < self.vram_dots {
+                    return None;
                 }
-                false
+                self.dots_in_mode -= self.vram_dots;
+                self.enter_mode(PpuMode::HBLANK, memory);
+                self.draw_scanline(memory, pixel_data);
+                Some(false)
             }
             PpuMode::HBLANK => {
-                if self.dots_in_mode >= 208 {
-                    self.dots_in_mode -= 208;
-                    self.change_scanline(self.scanline + 1, memory);
-                    if self.scanline == 144 {
-                        self.enter_mode(PpuMode::VBLANK, memory);
-                    } else {
-                        self.enter_mode(PpuMode::OAM, memory);
-                    }
+                let hblank_dots = DOTS_PER_LINE - OAM_DOTS - self.vram_dots;
+                if self.dots_in_mode < hblank_dots {
+                    return None;
+                }
+                self.dots_in_mode -= hblank_dots;
+                self.change_scanline(self.scanline + 1, memory);
+                //Line 143 is the last visible one so reaching 144 starts VBLANK
+                if self.scanline == 144 {
+                    self.enter_mode(PpuMode::VBLANK, memory);
+                } else {
+                    self.enter_mode(PpuMode::OAM, memory);
                 }
-                false
+                Some(false)
             }
             PpuMode::VBLANK => {
-                if self.dots_in_mode >= 456 {
-                    self.dots_in_mode -= 456;
-                    self.change_scanline(self.scanline + 1, memory);
-                    self.dots_in_mode = 0;
+                if self.dots_in_mode < DOTS_PER_LINE {
+                    return None;
                 }
-
+                self.dots_in_mode -= DOTS_PER_LINE;
+                //Line 153 gets its full 456 dots before wrapping so a frame is 154 lines
                 if self.scanline == 153 {
                     self.change_scanline(0, memory);
+                    self.window_line = 0;
                     self.enter_mode(PpuMode::OAM, memory);
-                    return true;
+                    return Some(true);
                 }
-                false
+                self.change_scanline(self.scanline + 1, memory);
+                Some(false)
             }
         }
     }
@@ -313,23 +783,845 @@ impl LcdControl {
     }
 }
 
+impl TileAttributes {
+    // Bit 7 - BG to OBJ priority
+    // Bit 6 - Vertical flip
+    // Bit 5 - Horizontal flip
+    // Bit 3 - Tile vram bank
+    // Bit 0-2 - Background palette
+    fn new(value: u8) -> Self {
+        Self {
+            palette: value & 0x7,
+            bank: (value >> 3) & 1,
+            x_flip: value & (1 << 5) != 0,
+            y_flip: value & (1 << 6) != 0,
+        }
+    }
+}
+
+//Turns a tile id from a map into an index from 0x8000. With LCDC bit 4 clear ids are signed and
+//count from 0x9000 so 0-127 land on tiles 256-383 and 128-255 share tiles with the sprites
+fn tile_data_index(tile_id: u16, unsigned_tile_data: bool) -> u16 {
+    if !unsigned_tile_data && tile_id < 128 {
+        tile_id + 0x100
+    } else {
+        tile_id
+    }
+}
+
+//Anything off the edge of the screen is clipped rather than spilling into the next row. The overlay
+//draws with this too
+pub(crate) fn set_pixel(framebuffer: &mut [u8], x: usize, y: usize, color: [u8; 3]) {
+    if x >= GAMEBOY_SCREEN_WIDTH as usize || y >= GAMEBOY_SCREEN_HEIGHT as usize {
+        return;
+    }
+    let offset = y * FRAMEBUFFER_STRIDE + x * BYTES_PER_PIXEL as usize;
+    if let Some(out) = framebuffer.get_mut(offset..offset + BYTES_PER_PIXEL as usize) {
+        out.copy_from_slice(&color);
+    }
+}
+
+//Each 5 bit channel is stretched out to 8 bits so full intensity is still 255
+fn rgb555_to_rgb(color: u16) -> [u8; 3] {
+    let channel = |shift: u16| {
+        let value = ((color >> shift) & 0x1F) as u8;
+        (value << 3) | (value >> 2)
+    };
+    [channel(0), channel(5), channel(10)]
+}
+
+//The rgb color the default palette draws a DMG shade with, 0 being the lightest
+pub fn shade_to_rgb(shade: u8) -> [u8; 3] {
+    DEFAULT_PALETTE[(shade & 0x3) as usize]
+}
+
 impl Tile {
-    fn new(tile_id: u16, memory: &mut Memory) -> Self {
+    fn new(tile_id: u16, bank: u8, memory: &Memory) -> Self {
         let tile_address = TILESET_START_ADDRESS + (TILE_SIZE as u16 * tile_id);
-        let data = [0; TILE_SIZE];
-        let data = data
-            .into_iter()
-            .enumerate()
-            .map(|(i, _)| memory.read_u8(tile_address + i as u16))
-            .collect();
-        Self { id: tile_id, data }
+        let data = memory.read_vram_slice(bank, tile_address, TILE_SIZE);
+        //Each row is two bytes, the low bits of every pixel then the high bits with the leftmost
+        //pixel in the top bit
+        let mut pixels = [0; TILE_PIXELS];
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let mask_x = 0x80 >> (i % 8);
+            let low = data[i / 8 * 2] & mask_x != 0;
+            let high = data[i / 8 * 2 + 1] & mask_x != 0;
+            *pixel = low as u8 | (high as u8) << 1;
+        }
+        Self {
+            pixels,
+            x_flip: false,
+            y_flip: false,
+        }
+    }
+
+    fn flipped(self, x_flip: bool, y_flip: bool) -> Self {
+        Self {
+            x_flip,
+            y_flip,
+            ..self
+        }
     }
 
     fn value_at(&self, x: u8, y: u8) -> u8 {
-        let mask_x = 1 << (7 - x);
-        let y = y as usize * 2;
-        let low = (self.data[y] & mask_x != 0) as u8;
-        let high = ((self.data[y] & mask_x != 0) as u8) << 1;
-        low | high
+        let x = if self.x_flip { 7 - x } else { x };
+        let y = if self.y_flip { 7 - y } else { y };
+        self.pixels[y as usize * 8 + x as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::Model;
+    use crate::memory::{RomChunk, INTERRUPT_ENABLE_ADDRESS, INTERRUPT_FLAG_ADDRESS};
+
+    //What draw_sprites leaves alone so drawn pixels stand out
+    const UNTOUCHED: [u8; 3] = [0x12, 0x34, 0x56];
+
+    //A dmg with a blank rom and everything set up the way the boot rom leaves it
+    fn setup() -> (Ppu, Memory) {
+        let mut memory = Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        memory.init_post_boot_io();
+        (Ppu::new(), memory)
+    }
+
+    //The same but a cgb running a rom that supports it
+    fn setup_cgb() -> (Ppu, Memory) {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        let rom = RomChunk::from_bytes(rom).unwrap();
+        let mut memory = Memory::new(None, rom, Model::Cgb).unwrap();
+        memory.init_post_boot_io();
+        (Ppu::new(), memory)
+    }
+
+    //Every pixel of the tile gets the same shade
+    fn solid_tile(memory: &mut Memory, tile: u16, shade: u8) {
+        let low = if shade & 1 != 0 { 0xFF } else { 0x00 };
+        let high = if shade & 2 != 0 { 0xFF } else { 0x00 };
+        let address = TILESET_START_ADDRESS + tile * TILE_SIZE as u16;
+        for row in 0..8 {
+            memory.write_u8(address + row * 2, low);
+            memory.write_u8(address + row * 2 + 1, high);
+        }
+    }
+
+    //Takes y and x as they are written to OAM, 16 and 8 past the top left of the screen
+    fn place_sprite(memory: &mut Memory, id: u16, y: u8, x: u8, tile: u8) {
+        let address = 0xFE00 + id * 4;
+        memory.write_u8(address, y);
+        memory.write_u8(address + 1, x);
+        memory.write_u8(address + 2, tile);
+        memory.write_u8(address + 3, 0);
+    }
+
+    //Draws just the sprites on one line and hands back the colors it ended up with
+    fn sprite_line(ppu: &mut Ppu, memory: &mut Memory, line: u8, tall: bool) -> Vec<[u8; 3]> {
+        memory.write_u8(LCDC, if tall { 0x97 } else { 0x93 });
+        ppu.lcd_control.update(memory);
+        ppu.scanline = line;
+        let mut pixel_data = UNTOUCHED.repeat(FRAMEBUFFER_SIZE / 3);
+        let hits = [false; GAMEBOY_SCREEN_WIDTH as usize];
+        ppu.draw_sprites(memory, &mut pixel_data, &hits);
+        pixel_data[line as usize * FRAMEBUFFER_STRIDE..(line as usize + 1) * FRAMEBUFFER_STRIDE]
+            .chunks(3)
+            .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect()
+    }
+
+    //Draws one whole line with whatever registers the test set up and hands back its colors
+    fn drawn_line(ppu: &mut Ppu, memory: &mut Memory, line: u8) -> Vec<[u8; 3]> {
+        ppu.scanline = line;
+        ppu.latch_registers(memory);
+        ppu.reset_window(PpuMode::OAM, memory);
+        let mut pixel_data = UNTOUCHED.repeat(FRAMEBUFFER_SIZE / 3);
+        ppu.draw_scanline(memory, &mut pixel_data);
+        pixel_data[line as usize * FRAMEBUFFER_STRIDE..(line as usize + 1) * FRAMEBUFFER_STRIDE]
+            .chunks(3)
+            .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect()
+    }
+
+    #[test]
+    fn dmg_background_off_blanks_the_line() {
+        let (mut ppu, mut memory) = setup();
+        solid_tile(&mut memory, 0, 3);
+        memory.write_u8(LCDC, 0x91);
+        assert_eq!(drawn_line(&mut ppu, &mut memory, 0), [shade_to_rgb(3); 160]);
+        memory.write_u8(LCDC, 0x90);
+        assert_eq!(drawn_line(&mut ppu, &mut memory, 0), [shade_to_rgb(0); 160]);
+    }
+
+    #[test]
+    fn cgb_background_off_still_draws_the_background() {
+        let (mut ppu, mut memory) = setup_cgb();
+        solid_tile(&mut memory, 0, 3);
+        memory.write_u8(LCDC, 0x91);
+        let enabled = drawn_line(&mut ppu, &mut memory, 0);
+        assert!(enabled.iter().all(|pixel| *pixel != UNTOUCHED));
+        memory.write_u8(LCDC, 0x90);
+        assert_eq!(drawn_line(&mut ppu, &mut memory, 0), enabled);
+    }
+
+    #[test]
+    fn scx_wraps_around_the_background_map() {
+        let (mut ppu, mut memory) = setup();
+        memory.write_u8(BGP, 0xE4);
+        solid_tile(&mut memory, 2, 1);
+        solid_tile(&mut memory, 3, 3);
+        //Map columns 31 and 20 are color 1, column 0 is color 3 and the rest color 0
+        memory.write_u8(0x981F, 2);
+        memory.write_u8(0x9814, 2);
+        memory.write_u8(0x9800, 3);
+
+        //The right edge at 259 is back at the start of the map
+        memory.write_u8(SCX, 100);
+        let line = drawn_line(&mut ppu, &mut memory, 0);
+        assert_eq!(line[147], shade_to_rgb(0));
+        assert_eq!(line[148..156], [shade_to_rgb(1); 8]);
+        assert_eq!(line[156..160], [shade_to_rgb(3); 4]);
+
+        //The left edge starts in the last column and runs into the first
+        memory.write_u8(SCX, 252);
+        let line = drawn_line(&mut ppu, &mut memory, 0);
+        assert_eq!(line[..4], [shade_to_rgb(1); 4]);
+        assert_eq!(line[4..12], [shade_to_rgb(3); 8]);
+        assert_eq!(line[12], shade_to_rgb(0));
+
+        //A fine scroll still draws the partial tile on the right
+        memory.write_u8(SCX, 3);
+        let line = drawn_line(&mut ppu, &mut memory, 0);
+        assert_eq!(line[..5], [shade_to_rgb(3); 5]);
+        assert_eq!(line[156], shade_to_rgb(0));
+        assert_eq!(line[157..160], [shade_to_rgb(1); 3]);
+    }
+
+    //A blank background under a window of tile 1 from the 0x9C00 map, every window tile has
+    //color 3 in its first column and color 1 in the rest
+    fn setup_window(wx: u8) -> (Ppu, Memory) {
+        let (ppu, mut memory) = setup();
+        memory.write_u8(BGP, 0xE4);
+        for row in 0..8 {
+            memory.write_u8(TILESET_START_ADDRESS + TILE_SIZE as u16 + row * 2, 0xFF);
+            memory.write_u8(TILESET_START_ADDRESS + TILE_SIZE as u16 + row * 2 + 1, 0x80);
+        }
+        for column in 0..32 {
+            memory.write_u8(0x9C00 + column, 1);
+        }
+        memory.write_u8(LCDC, 0xF1);
+        memory.write_u8(WY, 0);
+        memory.write_u8(WX, wx);
+        (ppu, memory)
+    }
+
+    //The shade of every column of line 0
+    fn window_shades(wx: u8) -> Vec<u8> {
+        let (mut ppu, mut memory) = setup_window(wx);
+        drawn_line(&mut ppu, &mut memory, 0)
+            .iter()
+            .map(|pixel| (0..4).find(|shade| shade_to_rgb(*shade) == *pixel).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn wx_7_puts_the_window_at_column_0() {
+        let shades = window_shades(7);
+        for (x, shade) in shades.iter().enumerate() {
+            assert_eq!(*shade, if x % 8 == 0 { 3 } else { 1 }, "column {}", x);
+        }
+    }
+
+    #[test]
+    fn wx_0_cuts_off_the_first_7_window_columns() {
+        let shades = window_shades(0);
+        for (x, shade) in shades.iter().enumerate() {
+            assert_eq!(*shade, if x % 8 == 1 { 3 } else { 1 }, "column {}", x);
+        }
+    }
+
+    #[test]
+    fn wx_166_only_draws_column_159() {
+        let shades = window_shades(166);
+        assert_eq!(shades[..159], [0; 159]);
+        assert_eq!(shades[159], 3);
+        assert_eq!(window_shades(167), [0; 160]);
+    }
+
+    #[test]
+    fn window_line_only_advances_when_the_window_is_drawn() {
+        let (mut ppu, mut memory) = setup_window(7);
+        //Row 1 of the window tile is blank so it is easy to tell apart from the rest
+        memory.write_u8(TILESET_START_ADDRESS + TILE_SIZE as u16 + 2, 0x00);
+        memory.write_u8(TILESET_START_ADDRESS + TILE_SIZE as u16 + 3, 0x00);
+
+        drawn_line(&mut ppu, &mut memory, 0);
+        assert_eq!(ppu.window_line, 1);
+        memory.write_u8(LCDC, 0xD1);
+        drawn_line(&mut ppu, &mut memory, 1);
+        drawn_line(&mut ppu, &mut memory, 2);
+        assert_eq!(ppu.window_line, 1);
+
+        //Line 3 carries on from row 1 of the window rather than row 3
+        memory.write_u8(LCDC, 0xF1);
+        let line = drawn_line(&mut ppu, &mut memory, 3);
+        assert_eq!(line, [shade_to_rgb(0); 160]);
+        assert_eq!(ppu.window_line, 2);
+        let line = drawn_line(&mut ppu, &mut memory, 4);
+        assert_eq!(line[..2], [shade_to_rgb(3), shade_to_rgb(1)]);
+        assert_eq!(ppu.window_line, 3);
+
+        //Below WY the window isn't drawn either
+        memory.write_u8(WY, 10);
+        drawn_line(&mut ppu, &mut memory, 5);
+        assert_eq!(ppu.window_line, 3);
+    }
+
+    //Sprite 0 sits at x 20 in shade 1 and sprite 1 overlaps its left half at x 16 in shade 2
+    fn overlapping_sprites(ppu: &mut Ppu, memory: &mut Memory) -> Vec<[u8; 3]> {
+        solid_tile(memory, 1, 1);
+        solid_tile(memory, 2, 2);
+        place_sprite(memory, 0, 16, 28, 1);
+        place_sprite(memory, 1, 16, 24, 2);
+        sprite_line(ppu, memory, 0, false)
+    }
+
+    #[test]
+    fn dmg_draws_the_leftmost_sprite_on_top() {
+        let (mut ppu, mut memory) = setup();
+        let line = overlapping_sprites(&mut ppu, &mut memory);
+        assert_eq!(line[16..24], [shade_to_rgb(2); 8]);
+        assert_eq!(line[24..28], [shade_to_rgb(1); 4]);
+        assert_eq!(line[28], UNTOUCHED);
+    }
+
+    #[test]
+    fn cgb_draws_the_first_sprite_in_oam_on_top() {
+        let (mut ppu, mut memory) = setup_cgb();
+        let line = overlapping_sprites(&mut ppu, &mut memory);
+        assert_eq!(line[16..20], [shade_to_rgb(2); 4]);
+        assert_eq!(line[20..28], [shade_to_rgb(1); 8]);
+        assert_eq!(line[28], UNTOUCHED);
+    }
+
+    //Whether a shade 1 sprite placed at the raw oam y and x 8 leaves anything on the line
+    fn sprite_drawn_on(raw_y: u8, line: u8, tall: bool) -> bool {
+        let (mut ppu, mut memory) = setup();
+        solid_tile(&mut memory, 0, 1);
+        solid_tile(&mut memory, 1, 1);
+        place_sprite(&mut memory, 0, raw_y, 8, 0);
+        sprite_line(&mut ppu, &mut memory, line, tall)
+            .iter()
+            .any(|pixel| *pixel != UNTOUCHED)
+    }
+
+    #[test]
+    fn short_sprite_only_draws_on_its_8_lines() {
+        //Raw y 36 puts the top of the sprite on line 20
+        assert!(!sprite_drawn_on(36, 19, false));
+        assert!(sprite_drawn_on(36, 20, false));
+        assert!(sprite_drawn_on(36, 27, false));
+        assert!(!sprite_drawn_on(36, 28, false));
+        //Partly above the screen, then fully above it, then wrapped round past the bottom
+        assert!(sprite_drawn_on(9, 0, false));
+        assert!(!sprite_drawn_on(9, 1, false));
+        assert!(!sprite_drawn_on(8, 0, false));
+        assert!(!sprite_drawn_on(0, 0, false));
+        assert!(!sprite_drawn_on(250, 0, false));
+    }
+
+    #[test]
+    fn tall_sprite_only_draws_on_its_16_lines() {
+        assert!(!sprite_drawn_on(36, 19, true));
+        assert!(sprite_drawn_on(36, 20, true));
+        assert!(sprite_drawn_on(36, 35, true));
+        assert!(!sprite_drawn_on(36, 36, true));
+        assert!(sprite_drawn_on(8, 7, true));
+        assert!(!sprite_drawn_on(8, 8, true));
+        assert!(!sprite_drawn_on(0, 0, true));
+        assert!(!sprite_drawn_on(250, 0, true));
+    }
+
+    #[test]
+    fn behind_background_sprites_only_show_over_color_0() {
+        let (mut ppu, mut memory) = setup();
+        memory.write_u8(BGP, 0xE4);
+        solid_tile(&mut memory, 1, 2);
+        solid_tile(&mut memory, 2, 1);
+        solid_tile(&mut memory, 3, 3);
+        //The background is color 0 apart from color 1 at x 8-15 and 24-31 and color 3 at x 16-23
+        memory.write_u8(0x9801, 2);
+        memory.write_u8(0x9802, 3);
+        memory.write_u8(0x9803, 2);
+        //Two behind background sprites at x 4 and 16 and a normal one at x 24
+        place_sprite(&mut memory, 0, 16, 12, 1);
+        place_sprite(&mut memory, 1, 16, 24, 1);
+        place_sprite(&mut memory, 2, 16, 32, 1);
+        memory.write_u8(0xFE03, 0x80);
+        memory.write_u8(0xFE07, 0x80);
+        memory.write_u8(LCDC, 0x93);
+
+        let line = drawn_line(&mut ppu, &mut memory, 0);
+        assert_eq!(line[..4], [shade_to_rgb(0); 4]);
+        assert_eq!(line[4..8], [shade_to_rgb(2); 4]);
+        assert_eq!(line[8..16], [shade_to_rgb(1); 8]);
+        assert_eq!(line[16..24], [shade_to_rgb(3); 8]);
+        assert_eq!(line[24..32], [shade_to_rgb(2); 8]);
+    }
+
+    #[test]
+    fn only_the_first_10_sprites_in_oam_are_drawn() {
+        let (mut ppu, mut memory) = setup();
+        solid_tile(&mut memory, 1, 1);
+        //Laid out right to left so OAM order and x order disagree, sprite 11 ends up leftmost
+        for id in 0..12 {
+            place_sprite(&mut memory, id, 16, 8 + 12 * (11 - id as u8), 1);
+        }
+        let line = sprite_line(&mut ppu, &mut memory, 0, false);
+        let drawn = |x: usize| line[x..x + 8].iter().all(|pixel| *pixel == shade_to_rgb(1));
+        for id in 0..12 {
+            let x = 12 * (11 - id);
+            assert_eq!(drawn(x), id < 10, "sprite {}", id);
+        }
+        assert_eq!(line.iter().filter(|pixel| **pixel != UNTOUCHED).count(), 80);
+    }
+
+    #[test]
+    fn sprites_off_the_sides_are_clipped() {
+        let (mut ppu, mut memory) = setup();
+        solid_tile(&mut memory, 1, 1);
+        //x -4 and 158 on line 0, then 158 again on the last line where a wrap would run off the buffer
+        place_sprite(&mut memory, 0, 16, 4, 1);
+        place_sprite(&mut memory, 1, 16, 166, 1);
+        memory.write_u8(LCDC, 0x93);
+        ppu.lcd_control.update(&memory);
+        let hits = [false; GAMEBOY_SCREEN_WIDTH as usize];
+        let mut pixel_data = UNTOUCHED.repeat(FRAMEBUFFER_SIZE / 3);
+        ppu.scanline = 0;
+        ppu.draw_sprites(&mut memory, &mut pixel_data, &hits);
+        //Sprite 0 moves fully above the screen
+        place_sprite(&mut memory, 0, 0, 0, 0);
+        place_sprite(&mut memory, 1, 159, 166, 1);
+        ppu.scanline = 143;
+        ppu.draw_sprites(&mut memory, &mut pixel_data, &hits);
+
+        let drawn = |x: usize, y: usize| pixel(&pixel_data, x, y) != UNTOUCHED;
+        for x in 0..GAMEBOY_SCREEN_WIDTH as usize {
+            assert_eq!(drawn(x, 0), !(4..158).contains(&x), "column {}", x);
+            assert!(!drawn(x, 1), "column {} of line 1", x);
+            assert_eq!(drawn(x, 143), x >= 158, "column {} of line 143", x);
+        }
+        assert_eq!(pixel(&pixel_data, 0, 0), shade_to_rgb(1));
+    }
+
+    #[test]
+    fn sprite_at_raw_16_8_draws_at_the_top_left() {
+        let (mut ppu, mut memory) = setup();
+        solid_tile(&mut memory, 1, 1);
+        place_sprite(&mut memory, 0, 16, 8, 1);
+        let line = sprite_line(&mut ppu, &mut memory, 0, false);
+        assert_eq!(line[0..8], [shade_to_rgb(1); 8]);
+        assert_eq!(line[8], UNTOUCHED);
+        assert_ne!(sprite_line(&mut ppu, &mut memory, 7, false)[0], UNTOUCHED);
+        assert_eq!(sprite_line(&mut ppu, &mut memory, 8, false)[0], UNTOUCHED);
+    }
+
+    //Runs a whole frame from the start of line 0 a few dots at a time and lists every interrupt
+    //the ppu requested along with the mode and line it was in
+    fn frame_interrupts(ppu: &mut Ppu, memory: &mut Memory) -> Vec<(u8, PpuMode, u8)> {
+        let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+        ppu.step(memory, &mut pixel_data, 0);
+        memory.write_u8(INTERRUPT_FLAG_ADDRESS, 0);
+        let mut requested = Vec::new();
+        for _ in 0..DOTS_PER_FRAME / 4 {
+            ppu.step(memory, &mut pixel_data, 4);
+            let flags = memory.read_u8(INTERRUPT_FLAG_ADDRESS) & 0x1F;
+            if flags != 0 {
+                requested.push((flags, ppu.current_mode, ppu.scanline));
+                memory.write_u8(INTERRUPT_FLAG_ADDRESS, 0);
+            }
+        }
+        requested
+    }
+
+    //Just the STAT interrupts and where they happened
+    fn stat_interrupts(sources: u8, lyc: u8) -> Vec<(PpuMode, u8)> {
+        let (mut ppu, mut memory) = setup();
+        memory.write_u8(STAT, sources);
+        memory.write_u8(LYC, lyc);
+        frame_interrupts(&mut ppu, &mut memory)
+            .into_iter()
+            .filter(|(flags, _, _)| flags & LCD_STAT_INTERRUPT != 0)
+            .map(|(_, mode, line)| (mode, line))
+            .collect()
+    }
+
+    #[test]
+    fn hblank_source_fires_entering_every_hblank() {
+        let expected = (0..144)
+            .map(|line| (PpuMode::HBLANK, line))
+            .collect::<Vec<_>>();
+        assert_eq!(stat_interrupts(STAT_HBLANK_SOURCE, 0xFF), expected);
+    }
+
+    #[test]
+    fn vblank_source_fires_entering_vblank() {
+        assert_eq!(
+            stat_interrupts(STAT_VBLANK_SOURCE, 0xFF),
+            [(PpuMode::VBLANK, 144)]
+        );
+    }
+
+    #[test]
+    fn oam_source_fires_entering_every_oam_search() {
+        //The frame ends with line 0 of the next one starting its search
+        let expected = (1..144)
+            .chain([0])
+            .map(|line| (PpuMode::OAM, line))
+            .collect::<Vec<_>>();
+        assert_eq!(stat_interrupts(STAT_OAM_SOURCE, 0xFF), expected);
+    }
+
+    #[test]
+    fn lyc_source_fires_when_ly_reaches_lyc() {
+        assert_eq!(stat_interrupts(STAT_LYC_SOURCE, 5), [(PpuMode::OAM, 5)]);
+        assert_eq!(stat_interrupts(0, 5), []);
+    }
+
+    #[test]
+    fn a_source_going_true_while_the_line_is_high_is_blocked() {
+        //Line 4's HBLANK holds the line high right into LY=LYC on line 5, which then holds it
+        //through line 5's own HBLANK so neither of those fire
+        let expected = (0..144)
+            .filter(|line| *line != 5)
+            .map(|line| (PpuMode::HBLANK, line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stat_interrupts(STAT_HBLANK_SOURCE | STAT_LYC_SOURCE, 5),
+            expected
+        );
+    }
+
+    #[test]
+    fn vblank_requests_both_interrupts_once() {
+        let (mut ppu, mut memory) = setup();
+        memory.write_u8(
+            INTERRUPT_ENABLE_ADDRESS,
+            VBLANK_INTERRUPT | LCD_STAT_INTERRUPT,
+        );
+        memory.write_u8(STAT, STAT_VBLANK_SOURCE);
+        memory.write_u8(LYC, 0xFF);
+        assert_eq!(
+            frame_interrupts(&mut ppu, &mut memory),
+            [(VBLANK_INTERRUPT | LCD_STAT_INTERRUPT, PpuMode::VBLANK, 144)]
+        );
+    }
+
+    #[test]
+    fn records_every_mode_over_a_frame() {
+        let (mut ppu, mut memory) = setup();
+        ppu.set_mode_change_recording(true);
+        frame_interrupts(&mut ppu, &mut memory);
+        let changes = ppu.take_mode_changes();
+
+        //Each visible line searches oam, draws and then waits out the rest of its dots
+        let mut expected = Vec::new();
+        for line in 0..144 {
+            let start = line as u64 * DOTS_PER_LINE as u64;
+            expected.push((line, PpuMode::OAM, start));
+            expected.push((line, PpuMode::VRAM, start + OAM_DOTS as u64));
+            expected.push((line, PpuMode::HBLANK, start + (OAM_DOTS + VRAM_DOTS) as u64));
+        }
+        expected.push((144, PpuMode::VBLANK, 144 * DOTS_PER_LINE as u64));
+        expected.push((0, PpuMode::OAM, DOTS_PER_FRAME as u64));
+        let changes = changes
+            .iter()
+            .map(|change| (change.scanline, change.mode, change.dot))
+            .collect::<Vec<_>>();
+        assert_eq!(changes, expected);
+        assert!(ppu.take_mode_changes().is_empty());
+    }
+
+    #[test]
+    fn each_frame_draws_144_lines_once_each() {
+        let (mut ppu, mut memory) = setup();
+        //Anything left at 0x55 was never drawn over
+        let mut pixel_data = vec![0x55; FRAMEBUFFER_SIZE];
+        ppu.set_mode_change_recording(true);
+        draw_frame(&mut ppu, &mut memory, &mut pixel_data);
+        //A line is drawn as it enters HBLANK
+        let drawn = ppu
+            .take_mode_changes()
+            .iter()
+            .filter(|change| change.mode == PpuMode::HBLANK)
+            .map(|change| change.scanline)
+            .collect::<Vec<_>>();
+        assert_eq!(drawn, (0..144).collect::<Vec<_>>());
+        assert!(pixel_data.iter().all(|byte| *byte != 0x55));
+    }
+
+    //Steps until the ppu says a frame is done
+    fn draw_frame(ppu: &mut Ppu, memory: &mut Memory, pixel_data: &mut [u8]) {
+        while !ppu.step(memory, pixel_data, 4) {}
+    }
+
+    #[test]
+    fn lcd_off_holds_ly_at_0_and_stops_the_modes() {
+        let (mut ppu, mut memory) = setup();
+        let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+        //Get partway down the screen first
+        for _ in 0..1000 {
+            ppu.step(&mut memory, &mut pixel_data, 4);
+        }
+        assert_ne!(memory.read_u8(LY_ADDRESS), 0);
+
+        memory.write_u8(LCDC, 0x11);
+        assert!(ppu.step(&mut memory, &mut pixel_data, 4));
+        assert!(pixel_data.chunks(3).all(|pixel| pixel == shade_to_rgb(0)));
+        ppu.set_mode_change_recording(true);
+        for _ in 0..DOTS_PER_FRAME / 4 {
+            ppu.step(&mut memory, &mut pixel_data, 4);
+            assert_eq!(memory.read_u8(LY_ADDRESS), 0);
+            assert_eq!(memory.read_u8(STAT) & STAT_MODE, 0);
+        }
+        assert!(ppu.take_mode_changes().is_empty());
+
+        //Turning it back on starts a new frame from line 0
+        memory.write_u8(LCDC, 0x91);
+        ppu.step(&mut memory, &mut pixel_data, 4);
+        assert_eq!(memory.read_u8(LY_ADDRESS), 0);
+        assert_eq!(memory.read_u8(STAT) & STAT_MODE, PpuMode::OAM.stat_bits());
+    }
+
+    #[test]
+    fn only_lines_that_change_are_dirty() {
+        let (mut ppu, mut memory) = setup();
+        let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+        draw_frame(&mut ppu, &mut memory, &mut pixel_data);
+        assert!(ppu.take_dirty_lines().iter().all(|dirty| *dirty));
+
+        //Nothing was written so the same frame comes out again
+        draw_frame(&mut ppu, &mut memory, &mut pixel_data);
+        assert!(ppu.take_dirty_lines().iter().all(|dirty| !*dirty));
+
+        //The whole map is tile 0 so its top row shows up every 8 lines
+        memory.write_u8(TILESET_START_ADDRESS, 0xFF);
+        draw_frame(&mut ppu, &mut memory, &mut pixel_data);
+        for (line, dirty) in ppu.take_dirty_lines().iter().enumerate() {
+            assert_eq!(*dirty, line % 8 == 0, "line {}", line);
+        }
+    }
+
+    #[test]
+    fn writing_a_tile_redecodes_it() {
+        let (mut ppu, mut memory) = setup();
+        memory.write_u8(TILESET_START_ADDRESS, 0xFF);
+        assert_eq!(ppu.cached_tile(0, 0, &mut memory).pixels[..8], [1; 8]);
+
+        //The high bits of the top row, every pixel in it goes to shade 3
+        memory.write_u8(TILESET_START_ADDRESS + 1, 0xFF);
+        let tile = ppu.cached_tile(0, 0, &mut memory);
+        assert_eq!(tile.pixels[..8], [3; 8]);
+        assert_eq!(tile.pixels[8..], [0; TILE_PIXELS - 8]);
+        assert!(!memory.take_dirty_tile(0, 0));
+
+        //Writes to other tiles leave this one alone
+        memory.write_u8(TILESET_START_ADDRESS + TILE_SIZE as u16, 0xFF);
+        assert_eq!(ppu.cached_tile(0, 0, &mut memory).pixels[..8], [3; 8]);
+        assert!(memory.take_dirty_tile(0, 1));
+    }
+
+    fn pixel(pixel_data: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let offset = y * FRAMEBUFFER_STRIDE + x * BYTES_PER_PIXEL as usize;
+        pixel_data[offset..offset + BYTES_PER_PIXEL as usize]
+            .try_into()
+            .unwrap()
+    }
+
+    #[test]
+    fn shades_go_from_lightest_to_darkest() {
+        let brightness = |shade: u8| shade_to_rgb(shade).iter().map(|c| *c as u32).sum::<u32>();
+        assert_eq!(shade_to_rgb(0), [255, 255, 255]);
+        assert_eq!(shade_to_rgb(3), [0, 0, 0]);
+        assert!(brightness(0) > brightness(1));
+        assert!(brightness(1) > brightness(2));
+        assert!(brightness(2) > brightness(3));
+        //Only the low 2 bits pick the shade
+        assert_eq!(shade_to_rgb(4), shade_to_rgb(0));
+        assert_eq!(shade_to_rgb(0xFF), shade_to_rgb(3));
+    }
+
+    #[test]
+    fn frame_is_drawn_through_the_palette() {
+        let (mut ppu, mut memory) = setup();
+        let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+        //BGP 0xFC sends color 0 to shade 0 and the rest to shade 3, the top row of tile 0 is color 3
+        memory.write_u8(TILESET_START_ADDRESS, 0xFF);
+        memory.write_u8(TILESET_START_ADDRESS + 1, 0xFF);
+        draw_frame(&mut ppu, &mut memory, &mut pixel_data);
+        assert_eq!(pixel(&pixel_data, 0, 0), shade_to_rgb(3));
+        assert_eq!(pixel(&pixel_data, 0, 1), shade_to_rgb(0));
+
+        let palette = [
+            [0xE0, 0xF8, 0xD0],
+            [0x88, 0xC0, 0x70],
+            [0x34, 0x68, 0x56],
+            [0x08, 0x18, 0x20],
+        ];
+        ppu.set_palette(palette);
+        draw_frame(&mut ppu, &mut memory, &mut pixel_data);
+        assert_eq!(pixel(&pixel_data, 0, 0), palette[3]);
+        assert_eq!(pixel(&pixel_data, 0, 1), palette[0]);
+    }
+
+    #[test]
+    fn tilemap_cell_shows_its_tile() {
+        let (ppu, mut memory) = setup();
+        //Every color number draws as its own shade
+        memory.write_u8(BGP, 0xE4);
+        let tile = 5;
+        let rows = [(0x0F, 0x33), (0xF0, 0x55), (0xFF, 0x00), (0x00, 0xFF)];
+        for y in 0..8 {
+            let (low, high) = rows[y % rows.len()];
+            let address = TILESET_START_ADDRESS + tile * TILE_SIZE as u16 + y as u16 * 2;
+            memory.write_u8(address, low);
+            memory.write_u8(address + 1, high);
+        }
+        //Column 3 and row 2 of the map at 0x9C00
+        let (cell_x, cell_y) = (3, 2);
+        memory.write_u8(0x9C00 + cell_y as u16 * 32 + cell_x as u16, tile as u8);
+
+        let mut out = vec![0; (TILEMAP_WIDTH * TILEMAP_HEIGHT * BYTES_PER_PIXEL) as usize];
+        let at = |out: &[u8], x: usize, y: usize| {
+            let offset = (y * TILEMAP_WIDTH as usize + x) * BYTES_PER_PIXEL as usize;
+            <[u8; 3]>::try_from(&out[offset..offset + BYTES_PER_PIXEL as usize]).unwrap()
+        };
+        ppu.render_tilemap(&memory, true, &mut out);
+        for y in 0..8 {
+            let (low, high) = rows[y % rows.len()];
+            for x in 0..8 {
+                let color = (low >> (7 - x)) & 1 | ((high >> (7 - x)) & 1) << 1;
+                assert_eq!(
+                    at(&out, cell_x * 8 + x, cell_y * 8 + y),
+                    shade_to_rgb(color),
+                    "pixel {},{}",
+                    x,
+                    y
+                );
+            }
+        }
+        //The cells around it are still tile 0
+        assert_eq!(at(&out, cell_x * 8 - 1, cell_y * 8), shade_to_rgb(0));
+        assert_eq!(at(&out, cell_x * 8 + 8, cell_y * 8 + 7), shade_to_rgb(0));
+
+        //The other map never had the tile written into it
+        ppu.render_tilemap(&memory, false, &mut out);
+        assert_eq!(at(&out, cell_x * 8, cell_y * 8 + 3), shade_to_rgb(0));
+    }
+
+    #[test]
+    fn tileset_cell_shows_its_tile() {
+        let (ppu, mut memory) = setup();
+        memory.write_u8(BGP, 0xE4);
+        //Tile 0x123 is in the 0x9000 block and sits in column 3 of row 18
+        let tile = 0x123;
+        let rows = [(0x0F, 0x33), (0xF0, 0x55), (0xFF, 0x00), (0x00, 0xFF)];
+        for y in 0..8 {
+            let (low, high) = rows[y % rows.len()];
+            let address = TILESET_START_ADDRESS + tile * TILE_SIZE as u16 + y as u16 * 2;
+            memory.write_u8(address, low);
+            memory.write_u8(address + 1, high);
+        }
+
+        let mut out = vec![0; (TILESET_WIDTH * TILESET_HEIGHT * BYTES_PER_PIXEL) as usize];
+        ppu.render_tileset(&memory, &mut out);
+        let at = |x: usize, y: usize| {
+            let offset = (y * TILESET_WIDTH as usize + x) * BYTES_PER_PIXEL as usize;
+            <[u8; 3]>::try_from(&out[offset..offset + BYTES_PER_PIXEL as usize]).unwrap()
+        };
+        let (cell_x, cell_y) = (3 * 8, 18 * 8);
+        for y in 0..8 {
+            let (low, high) = rows[y % rows.len()];
+            for x in 0..8 {
+                let color = (low >> (7 - x)) & 1 | ((high >> (7 - x)) & 1) << 1;
+                assert_eq!(
+                    at(cell_x + x, cell_y + y),
+                    shade_to_rgb(color),
+                    "pixel {},{}",
+                    x,
+                    y
+                );
+            }
+        }
+        //The tiles either side are still blank
+        assert_eq!(at(cell_x - 1, cell_y + 3), shade_to_rgb(0));
+        assert_eq!(at(cell_x + 8, cell_y + 3), shade_to_rgb(0));
+    }
+
+    #[test]
+    fn vram_reads_ff_during_mode_3_but_the_ppu_still_draws() {
+        let (mut ppu, mut memory) = setup();
+        let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+        //Color 2 is a clear low byte and BGP 0xFC draws it as shade 3
+        solid_tile(&mut memory, 0, 2);
+        while memory.read_u8(STAT) & STAT_MODE != 3 {
+            ppu.step(&mut memory, &mut pixel_data, 4);
+        }
+        assert_eq!(memory.read_u8(TILESET_START_ADDRESS), 0xFF);
+        assert_eq!(memory.read_u8(0xFE00), 0xFF);
+        while memory.read_u8(STAT) & STAT_MODE == 3 {
+            ppu.step(&mut memory, &mut pixel_data, 4);
+        }
+        //Hblank hands vram back to the cpu
+        assert_eq!(memory.read_u8(TILESET_START_ADDRESS), 0x00);
+        assert_eq!(memory.read_u8(0xFE00), 0x00);
+
+        draw_frame(&mut ppu, &mut memory, &mut pixel_data);
+        assert_eq!(pixel(&pixel_data, 0, 0), shade_to_rgb(3));
+        assert_eq!(pixel(&pixel_data, 159, 143), shade_to_rgb(3));
+    }
+
+    #[test]
+    fn vblank_lines_draw_nothing() {
+        //The window covers the whole screen so drawing any line would also move the window on
+        let (mut ppu, mut memory) = setup_window(7);
+        place_sprite(&mut memory, 0, 160, 16, 1);
+        //Room for every line of a frame so a stray write past the screen would show up
+        let untouched = UNTOUCHED.repeat(FRAMEBUFFER_STRIDE / 3 * 154);
+        let mut pixel_data = untouched.clone();
+        for line in 144..154 {
+            ppu.scanline = line;
+            ppu.latch_registers(&memory);
+            ppu.reset_window(PpuMode::OAM, &mut memory);
+            ppu.draw_scanline(&mut memory, &mut pixel_data);
+        }
+        assert!(pixel_data == untouched);
+        assert!(!ppu.take_dirty_lines().contains(&true));
+        assert_eq!(ppu.window_line, 0);
+    }
+
+    #[test]
+    fn writing_ly_restarts_the_frame() {
+        let (mut ppu, mut memory) = setup();
+        let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+        while ppu.scanline < 50 {
+            ppu.step(&mut memory, &mut pixel_data, 4);
+        }
+        memory.write_u8(LY_ADDRESS, 0x55);
+        assert_eq!(memory.read_u8(LY_ADDRESS), 0);
+        ppu.step(&mut memory, &mut pixel_data, 4);
+        assert_eq!(ppu.scanline, 0);
+        assert_eq!(ppu.current_mode, PpuMode::OAM);
+        assert_eq!(memory.read_u8(LY_ADDRESS), 0);
+
+        //LYC reads back and the coincidence bit follows it
+        memory.write_u8(LYC, 0x00);
+        assert_eq!(memory.read_u8(LYC), 0x00);
+        ppu.step(&mut memory, &mut pixel_data, 4);
+        assert_ne!(memory.read_u8(STAT) & STAT_COINCIDENCE, 0);
+        memory.write_u8(LYC, 0x55);
+        assert_eq!(memory.read_u8(LYC), 0x55);
+        ppu.step(&mut memory, &mut pixel_data, 4);
+        assert_eq!(memory.read_u8(STAT) & STAT_COINCIDENCE, 0);
     }
 }
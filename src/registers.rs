@@ -1,6 +1,6 @@
 use crate::memory::Memory;
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct Registers {
     pc: u16,
     sp: u16,
@@ -9,6 +9,13 @@ pub struct Registers {
     de: RegisterPair,
     hl: RegisterPair,
     ime: bool,
+    //Set by ei, interrupts only get turned on once the instruction after it has run
+    ime_pending: bool,
+    stopped: bool,
+    halted: bool,
+    //Set when halt is skipped with IME off and an interrupt already pending, the next opcode is
+    //fetched without moving the pc so the byte after the halt gets read twice
+    halt_bug: bool,
 }
 
 #[derive(Copy, Clone, Default, Debug)]
@@ -17,12 +24,13 @@ pub struct RegisterPair {
     pub low: u8,
 }
 
+//F is left out since no instruction uses it as an operand, the flags are only reachable through
+//the flag methods and as the low half of AF
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum R8 {
     B,
     C,
     A,
-    F,
     D,
     E,
     H,
@@ -39,12 +47,69 @@ pub enum R16 {
     HL,
 }
 
+impl R8 {
+    //Takes an upper case name
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "A" => Some(R8::A),
+            "B" => Some(R8::B),
+            "C" => Some(R8::C),
+            "D" => Some(R8::D),
+            "E" => Some(R8::E),
+            "H" => Some(R8::H),
+            "L" => Some(R8::L),
+            _ => None,
+        }
+    }
+}
+
+impl R16 {
+    //Takes an upper case name
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "AF" => Some(R16::AF),
+            "BC" => Some(R16::BC),
+            "DE" => Some(R16::DE),
+            "HL" => Some(R16::HL),
+            "SP" => Some(R16::SP),
+            "PC" => Some(R16::PC),
+            _ => None,
+        }
+    }
+}
+
 pub const ZERO_FLAG: u8 = 0x80;
 pub const SUBTRACT_FLAG: u8 = 0x40;
 pub const HALF_CARRY_FLAG: u8 = 0x20;
 pub const CARRY_FLAG: u8 = 0x10;
+// The lower nibble of F doesn't exist on hardware and always reads back as 0
+const FLAGS_MASK: u8 = 0xF0;
 
 impl Registers {
+    //Where the DMG boot rom leaves everything when it jumps to the cartridge at 0x100
+    pub fn post_boot() -> Self {
+        let mut registers = Self::default();
+        registers.write_r16(R16::AF, 0x01B0);
+        registers.write_r16(R16::BC, 0x0013);
+        registers.write_r16(R16::DE, 0x00D8);
+        registers.write_r16(R16::HL, 0x014D);
+        registers.write_r16(R16::SP, 0xFFFE);
+        registers.set_pc(0x0100);
+        registers
+    }
+
+    //The CGB boot rom leaves 0x11 in A which is how games tell they are running on one
+    pub fn post_boot_cgb() -> Self {
+        let mut registers = Self::default();
+        registers.write_r16(R16::AF, 0x1180);
+        registers.write_r16(R16::BC, 0x0000);
+        registers.write_r16(R16::DE, 0xFF56);
+        registers.write_r16(R16::HL, 0x000D);
+        registers.write_r16(R16::SP, 0xFFFE);
+        registers.set_pc(0x0100);
+        registers
+    }
+
     pub fn get_pc(&self) -> u16 {
         self.read_r16(R16::PC)
     }
@@ -58,7 +123,7 @@ impl Registers {
     }
 
     pub fn get_flags(&self) -> u8 {
-        self.read_r8(R8::F)
+        self.af.low
     }
 
     pub fn set_flags(
@@ -68,7 +133,7 @@ impl Registers {
         half_carry: Option<bool>,
         carry: Option<bool>,
     ) {
-        let mut flags = self.read_r8(R8::F);
+        let mut flags = self.get_flags();
         if let Some(zero) = zero {
             flags = Registers::set_bit_flag(flags, ZERO_FLAG, zero);
         }
@@ -81,7 +146,7 @@ impl Registers {
         if let Some(carry) = carry {
             flags = Registers::set_bit_flag(flags, CARRY_FLAG, carry);
         }
-        self.write_r8(R8::F, flags);
+        self.af.low = flags & FLAGS_MASK;
     }
 
     pub fn set_bit_flag(flags: u8, bit: u8, set: bool) -> u8 {
@@ -91,28 +156,64 @@ impl Registers {
         }
     }
 
-    // pub fn zero_flag(&self) -> bool {
-    //     self.get_flags() & ZERO_FLAG == ZERO_FLAG
-    // }
-    // pub fn sub_flag(&self) -> bool {
-    //     self.get_flags() & SUBTRACT_FLAG == SUBTRACT_FLAG
-    // }
-    // pub fn half_carry_flag(&self) -> bool {
-    //     self.get_flags() & HALF_CARRY_FLAG == HALF_CARRY_FLAG
-    // }
+    pub fn zero_flag(&self) -> bool {
+        self.get_flags() & ZERO_FLAG == ZERO_FLAG
+    }
+    pub fn sub_flag(&self) -> bool {
+        self.get_flags() & SUBTRACT_FLAG == SUBTRACT_FLAG
+    }
+    pub fn half_carry_flag(&self) -> bool {
+        self.get_flags() & HALF_CARRY_FLAG == HALF_CARRY_FLAG
+    }
     pub fn carry_flag(&self) -> bool {
         self.get_flags() & CARRY_FLAG == CARRY_FLAG
     }
 
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
     pub fn set_ime(&mut self, value: bool) {
         self.ime = value;
     }
+
+    pub fn ime_pending(&self) -> bool {
+        self.ime_pending
+    }
+
+    pub fn set_ime_pending(&mut self, value: bool) {
+        self.ime_pending = value;
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn set_stopped(&mut self, value: bool) {
+        self.stopped = value;
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn set_halted(&mut self, value: bool) {
+        self.halted = value;
+    }
+
+    pub fn halt_bug(&self) -> bool {
+        self.halt_bug
+    }
+
+    pub fn set_halt_bug(&mut self, value: bool) {
+        self.halt_bug = value;
+    }
+
     pub fn read_r8(&self, register: R8) -> u8 {
         match register {
             R8::B => self.bc.high,
             R8::C => self.bc.low,
             R8::A => self.af.high,
-            R8::F => self.af.low,
             R8::D => self.de.high,
             R8::E => self.de.low,
             R8::H => self.hl.high,
@@ -125,7 +226,6 @@ impl Registers {
             R8::B => self.bc.high = value,
             R8::C => self.bc.low = value,
             R8::A => self.af.high = value,
-            R8::F => self.af.low = value,
             R8::D => self.de.high = value,
             R8::E => self.de.low = value,
             R8::H => self.hl.high = value,
@@ -133,6 +233,58 @@ impl Registers {
         }
     }
 
+    //Reads any register by its name like "a", "f" or "hl", F being the only one read_r8 can't reach
+    pub fn get_by_name(&self, name: &str) -> Option<u16> {
+        let name = name.to_ascii_uppercase();
+        if name == "F" {
+            return Some(self.get_flags() as u16);
+        }
+        R8::from_name(&name)
+            .map(|register| self.read_r8(register) as u16)
+            .or_else(|| R16::from_name(&name).map(|register| self.read_r16(register)))
+    }
+
+    //Writes any register by its name, 8 bit ones only keep the low byte. Returns false for a name
+    //that isn't a register
+    pub fn set_by_name(&mut self, name: &str, value: u16) -> bool {
+        let name = name.to_ascii_uppercase();
+        if name == "F" {
+            self.af.low = value as u8 & FLAGS_MASK;
+        } else if let Some(register) = R8::from_name(&name) {
+            self.write_r8(register, value as u8);
+        } else if let Some(register) = R16::from_name(&name) {
+            self.write_r16(register, value);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    //The 16 bit registers in a compact form for trace logs, pc is left out since the log has it
+    pub fn summary(&self) -> String {
+        format!(
+            "AF:{:04X} BC:{:04X} DE:{:04X} HL:{:04X} SP:{:04X}",
+            self.read_r16(R16::AF),
+            self.read_r16(R16::BC),
+            self.read_r16(R16::DE),
+            self.read_r16(R16::HL),
+            self.read_r16(R16::SP)
+        )
+    }
+
+    //The flags as letters with a - for each one that is clear
+    pub fn flags_summary(&self) -> String {
+        [
+            (self.zero_flag(), 'Z'),
+            (self.sub_flag(), 'N'),
+            (self.half_carry_flag(), 'H'),
+            (self.carry_flag(), 'C'),
+        ]
+        .iter()
+        .map(|(set, letter)| if *set { *letter } else { '-' })
+        .collect()
+    }
+
     pub fn read_r16(&self, register: R16) -> u16 {
         match register {
             R16::PC => self.pc,
@@ -149,7 +301,7 @@ impl Registers {
             R16::PC => self.pc = value,
             R16::SP => self.sp = value,
             R16::BC => self.bc = RegisterPair::from(value),
-            R16::AF => self.af = RegisterPair::from(value),
+            R16::AF => self.af = RegisterPair::from(value & (0xFF00 | FLAGS_MASK as u16)),
             R16::DE => self.de = RegisterPair::from(value),
             R16::HL => self.hl = RegisterPair::from(value),
         }
@@ -157,23 +309,23 @@ impl Registers {
 
     // Stack goodness
     pub fn stack_push16(&mut self, value: u16, memory: &mut Memory) {
-        self.sp -= 1;
+        self.sp = self.sp.wrapping_sub(1);
         let higher = ((0xFF00 & value) >> 8) as u8;
         memory.write_u8(self.sp, higher);
-        self.sp -= 1;
+        self.sp = self.sp.wrapping_sub(1);
         let lower = (0x00FF & value) as u8;
         memory.write_u8(self.sp, lower);
     }
     pub fn stack_pop16(&mut self, memory: &mut Memory) -> u16 {
         let lower = memory.read_u8(self.sp) as u16;
-        self.sp += 1;
+        self.sp = self.sp.wrapping_add(1);
         let higher = memory.read_u8(self.sp) as u16;
-        self.sp += 1;
+        self.sp = self.sp.wrapping_add(1);
         (higher << 8) | lower
     }
     pub fn stack_peek16(&self, memory: &Memory) -> u16 {
         let lower = memory.read_u8(self.sp);
-        let upper = memory.read_u8(self.sp + 1);
+        let upper = memory.read_u8(self.sp.wrapping_add(1));
         ((upper as u16) << 8) | (lower as u16)
     }
 }
@@ -192,3 +344,94 @@ impl From<RegisterPair> for u16 {
         (value.high as u16) << 8 | value.low as u16
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(f: u8) -> [bool; 4] {
+        let mut registers = Registers::default();
+        registers.write_r16(R16::AF, f as u16);
+        [
+            registers.zero_flag(),
+            registers.sub_flag(),
+            registers.half_carry_flag(),
+            registers.carry_flag(),
+        ]
+    }
+
+    #[test]
+    fn flag_accessors_read_their_own_bit() {
+        assert_eq!(flags(0x00), [false; 4]);
+        assert_eq!(flags(ZERO_FLAG), [true, false, false, false]);
+        assert_eq!(flags(SUBTRACT_FLAG), [false, true, false, false]);
+        assert_eq!(flags(HALF_CARRY_FLAG), [false, false, true, false]);
+        assert_eq!(flags(CARRY_FLAG), [false, false, false, true]);
+        assert_eq!(flags(0xA0), [true, false, true, false]);
+        assert_eq!(flags(0xF0), [true; 4]);
+        //The low nibble of F doesn't hold any flags
+        assert_eq!(flags(0x0F), [false; 4]);
+    }
+
+    #[test]
+    fn stack_peek_at_ffff_wraps_to_0000() {
+        use crate::gameboy::Model;
+        use crate::memory::{RomChunk, INTERRUPT_ENABLE_ADDRESS};
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x0000] = 0xAB;
+        let mut memory = Memory::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Dmg).unwrap();
+        memory.write_u8(INTERRUPT_ENABLE_ADDRESS, 0xCD);
+        let mut registers = Registers::default();
+        registers.write_r16(R16::SP, 0xFFFF);
+        assert_eq!(registers.stack_peek16(&memory), 0xABCD);
+        assert_eq!(registers.read_r16(R16::SP), 0xFFFF);
+    }
+
+    #[test]
+    fn setting_hl_splits_into_h_and_l() {
+        let mut registers = Registers::default();
+        assert!(registers.set_by_name("HL", 0xBEEF));
+        assert_eq!(registers.get_by_name("H"), Some(0xBE));
+        assert_eq!(registers.get_by_name("L"), Some(0xEF));
+        assert_eq!(registers.get_by_name("hl"), Some(0xBEEF));
+    }
+
+    #[test]
+    fn names_cover_f_and_pc_and_reject_the_rest() {
+        let mut registers = Registers::default();
+        //The low bits of F don't exist
+        assert!(registers.set_by_name("f", 0x1FF));
+        assert_eq!(registers.get_by_name("F"), Some(0xF0));
+        assert!(registers.set_by_name("a", 0x1234));
+        assert_eq!(registers.get_by_name("AF"), Some(0x34F0));
+        assert!(registers.set_by_name("PC", 0x0150));
+        assert_eq!(registers.get_pc(), 0x0150);
+        assert!(!registers.set_by_name("X", 0));
+        assert_eq!(registers.get_by_name("IX"), None);
+    }
+
+    #[test]
+    fn flags_and_r8_writes_stay_apart() {
+        const ALL_R8: [R8; 7] = [R8::B, R8::C, R8::A, R8::D, R8::E, R8::H, R8::L];
+        let mut registers = Registers::default();
+        for register in ALL_R8 {
+            registers.write_r8(register, 0xFF);
+        }
+        assert_eq!(registers.get_flags(), 0x00);
+
+        registers.set_flags(Some(true), Some(true), Some(true), Some(true));
+        assert_eq!(registers.get_flags(), 0xF0);
+        registers.set_flags(Some(false), None, Some(false), None);
+        assert_eq!(registers.get_flags(), SUBTRACT_FLAG | CARRY_FLAG);
+        for register in ALL_R8 {
+            assert_eq!(registers.read_r8(register), 0xFF);
+        }
+        //A shares AF with the flags but only the high byte
+        registers.write_r8(R8::A, 0x00);
+        assert_eq!(
+            registers.read_r16(R16::AF),
+            (SUBTRACT_FLAG | CARRY_FLAG) as u16
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+
+use crate::gameboy::{GameBoy, SaveState};
+
+//Off unless --rewind asks for it since every snapshot is a whole copy of the machine. With a
+//snapshot every 5 frames keeping 120 of them goes back about 10 seconds
+pub const DEFAULT_REWIND_DEPTH: usize = 0;
+pub const DEFAULT_REWIND_INTERVAL: u32 = 5;
+
+//Keeps save states from the last few seconds so a mistake can be taken back. Each one is stored
+//with the frame that was on screen when it was taken so rewinding can show it without running
+pub struct Rewind {
+    snapshots: VecDeque<(SaveState, Vec<u8>)>,
+    //Most snapshots to keep, the oldest is dropped to make room
+    depth: usize,
+    //Frames between snapshots
+    interval: u32,
+    frames_since_snapshot: u32,
+}
+
+impl Rewind {
+    pub fn new(depth: usize, interval: u32) -> Self {
+        Self {
+            snapshots: VecDeque::with_capacity(depth),
+            depth: depth.max(1),
+            interval: interval.max(1),
+            frames_since_snapshot: 0,
+        }
+    }
+
+    //Called after every frame, only every interval frames actually get saved
+    pub fn record(&mut self, gameboy: &GameBoy, pixel_data: &[u8]) {
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot < self.interval {
+            return;
+        }
+        self.frames_since_snapshot = 0;
+        if self.snapshots.len() >= self.depth {
+            self.snapshots.pop_front();
+        }
+        self.snapshots
+            .push_back((gameboy.save_state(), pixel_data.to_vec()));
+    }
+
+    //Loads the newest snapshot and its frame then forgets it so the next call goes further back,
+    //returns false once there is nothing left to go back to
+    pub fn rewind(&mut self, gameboy: &mut GameBoy, pixel_data: &mut [u8]) -> bool {
+        match self.snapshots.pop_back() {
+            Some((state, frame)) => {
+                gameboy.load_state(state);
+                pixel_data.copy_from_slice(&frame);
+                self.frames_since_snapshot = 0;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rewind;
+    use crate::{
+        cpu_state::CpuState,
+        gameboy::{GameBoy, Model},
+        memory::RomChunk,
+        ppu::FRAMEBUFFER_SIZE,
+    };
+
+    #[test]
+    fn rewinding_restores_the_newest_snapshot_and_its_frame() {
+        let mut gameboy = GameBoy::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        let mut rewind = Rewind::new(3, 1);
+        for snapshot in 1..=3 {
+            let state = CpuState::new().a(snapshot).pc(0x100 * snapshot as u16);
+            gameboy.cpu.load_state(&state);
+            rewind.record(&gameboy, &vec![snapshot; FRAMEBUFFER_SIZE]);
+        }
+        gameboy.cpu.load_state(&CpuState::new().a(4).pc(0x400));
+
+        let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+        for snapshot in [3, 2] {
+            assert!(rewind.rewind(&mut gameboy, &mut pixel_data));
+            let state = gameboy.cpu.capture_state();
+            assert_eq!(
+                state,
+                CpuState::new().a(snapshot).pc(0x100 * snapshot as u16)
+            );
+            assert!(pixel_data.iter().all(|pixel| *pixel == snapshot));
+        }
+    }
+}
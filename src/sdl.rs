@@ -1,87 +1,584 @@
-use sdl2::{event::Event, keyboard::Keycode, pixels::PixelFormatEnum, rect::Rect, EventPump};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use log::warn;
+use sdl2::{
+    audio::{AudioCallback, AudioDevice, AudioSpecDesired},
+    controller::{self, Axis, GameController},
+    event::{Event, WindowEvent},
+    keyboard::Keycode,
+    pixels::{Color, PixelFormatEnum},
+    rect::Rect,
+    render::{Canvas, Texture},
+    video::Window,
+    AudioSubsystem, EventPump, GameControllerSubsystem,
+};
 
 use crate::{
+    apu::{Apu, DEFAULT_SAMPLE_RATE},
+    display::{axis_button, Display, InputState},
+    emu::Emu,
     gameboy::GameBoy,
-    ppu::{GAMEBOY_SCREEN_HEIGHT, GAMEBOY_SCREEN_WIDTH},
+    joypad::Button,
+    ppu::{BYTES_PER_PIXEL, GAMEBOY_SCREEN_HEIGHT, GAMEBOY_SCREEN_WIDTH},
 };
 
-const SDL_SCALE: u32 = 8;
+const BYTES_PER_ROW: u32 = GAMEBOY_SCREEN_WIDTH * BYTES_PER_PIXEL;
+
+const AUDIO_CHANNELS: u8 = 2;
+const AUDIO_BUFFER_SAMPLES: u16 = 1024;
+//How far the stick has to be pushed out of the full range of 32767 to count as a d-pad press
+const DEFAULT_STICK_THRESHOLD: i16 = 16384;
+
+//Which keyboard key drives each of the gameboy's buttons
+pub struct KeyMap {
+    pub up: Keycode,
+    pub down: Keycode,
+    pub left: Keycode,
+    pub right: Keycode,
+    pub a: Keycode,
+    pub b: Keycode,
+    pub start: Keycode,
+    pub select: Keycode,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self {
+            up: Keycode::Up,
+            down: Keycode::Down,
+            left: Keycode::Left,
+            right: Keycode::Right,
+            a: Keycode::Z,
+            b: Keycode::X,
+            start: Keycode::Return,
+            select: Keycode::RShift,
+        }
+    }
+}
+
+impl KeyMap {
+    fn button(&self, keycode: Keycode) -> Option<Button> {
+        [
+            (self.up, Button::Up),
+            (self.down, Button::Down),
+            (self.left, Button::Left),
+            (self.right, Button::Right),
+            (self.a, Button::A),
+            (self.b, Button::B),
+            (self.start, Button::Start),
+            (self.select, Button::Select),
+        ]
+        .into_iter()
+        .find(|(key, _)| *key == keycode)
+        .map(|(_, button)| button)
+    }
+}
+
+//Which controller button drives each of the gameboy's buttons, the left stick also works as the
+//d-pad once it is pushed past the threshold
+pub struct PadMap {
+    pub up: controller::Button,
+    pub down: controller::Button,
+    pub left: controller::Button,
+    pub right: controller::Button,
+    pub a: controller::Button,
+    pub b: controller::Button,
+    pub start: controller::Button,
+    pub select: controller::Button,
+    pub stick_threshold: i16,
+}
+
+//A and B sit the same way round as on the gameboy, so sdl's B on the right is the gameboy's A
+impl Default for PadMap {
+    fn default() -> Self {
+        Self {
+            up: controller::Button::DPadUp,
+            down: controller::Button::DPadDown,
+            left: controller::Button::DPadLeft,
+            right: controller::Button::DPadRight,
+            a: controller::Button::B,
+            b: controller::Button::A,
+            start: controller::Button::Start,
+            select: controller::Button::Back,
+            stick_threshold: DEFAULT_STICK_THRESHOLD,
+        }
+    }
+}
+
+impl PadMap {
+    fn button(&self, pad_button: controller::Button) -> Option<Button> {
+        [
+            (self.up, Button::Up),
+            (self.down, Button::Down),
+            (self.left, Button::Left),
+            (self.right, Button::Right),
+            (self.a, Button::A),
+            (self.b, Button::B),
+            (self.start, Button::Start),
+            (self.select, Button::Select),
+        ]
+        .into_iter()
+        .find(|(pad, _)| *pad == pad_button)
+        .map(|(_, button)| button)
+    }
+}
+
+//Draws frames into an sdl window and turns keyboard and controller events into input
+pub struct SdlDisplay<'a> {
+    key_map: KeyMap,
+    pad_map: PadMap,
+    //None if sdl couldn't start its controller support, the keyboard still works without it
+    controller_subsystem: Option<GameControllerSubsystem>,
+    //Controllers only send events while they are open
+    controllers: Vec<GameController>,
+    //The direction each axis of the left stick is holding down, x then y
+    stick: [Option<Button>; 2],
+    canvas: Canvas<Window>,
+    texture: Texture<'a>,
+    //Where in the window the screen gets drawn, worked out again whenever the window is resized
+    destination: Rect,
+    event_pump: EventPump,
+    input: InputState,
+    audio_device: Option<AudioDevice<AudioOutput>>,
+    audio_samples: Arc<Mutex<VecDeque<i16>>>,
+}
+
+//Plays whatever the emulator has queued up from SDL's audio thread
+struct AudioOutput {
+    samples: Arc<Mutex<VecDeque<i16>>>,
+}
+
+impl AudioCallback for AudioOutput {
+    type Channel = i16;
+
+    fn callback(&mut self, out: &mut [i16]) {
+        let mut samples = self.samples.lock().unwrap();
+        for sample in out.iter_mut() {
+            //If the emulator falls behind play silence instead of garbage
+            *sample = samples.pop_front().unwrap_or(0);
+        }
+    }
+}
+
+impl<'a> SdlDisplay<'a> {
+    fn open_audio(&mut self, audio_subsystem: &AudioSubsystem, gameboy: &mut GameBoy) {
+        let desired_spec = AudioSpecDesired {
+            freq: Some(DEFAULT_SAMPLE_RATE as i32),
+            channels: Some(AUDIO_CHANNELS),
+            samples: Some(AUDIO_BUFFER_SAMPLES),
+        };
+        let samples = self.audio_samples.clone();
+        let device = audio_subsystem
+            .open_playback(None, &desired_spec, |_spec| AudioOutput { samples })
+            .unwrap();
+        gameboy.apu.set_sample_rate(device.spec().freq as u32);
+        device.resume();
+        self.audio_device = Some(device);
+    }
+
+    fn window_size(scale: u32) -> (u32, u32) {
+        (GAMEBOY_SCREEN_WIDTH * scale, GAMEBOY_SCREEN_HEIGHT * scale)
+    }
 
-const WINDOW_WIDTH: u32 = GAMEBOY_SCREEN_WIDTH * SDL_SCALE;
-const WINDOW_HEIGHT: u32 = GAMEBOY_SCREEN_HEIGHT * SDL_SCALE;
+    //Copies rows start up to end of the framebuffer into the texture
+    fn upload_lines(&mut self, framebuffer: &[u8], start: usize, end: usize) {
+        let row = BYTES_PER_ROW as usize;
+        let lines = Rect::new(0, start as i32, GAMEBOY_SCREEN_WIDTH, (end - start) as u32);
+        self.texture
+            .update(lines, &framebuffer[start * row..end * row], row)
+            .unwrap();
+    }
 
-pub const BYTES_PER_PIXEL: u32 = 3;
-pub const BYTES_PER_ROW: u32 = GAMEBOY_SCREEN_WIDTH * BYTES_PER_PIXEL;
+    fn draw(&mut self) {
+        let gameboy_display_dims = Rect::new(0, 0, GAMEBOY_SCREEN_WIDTH, GAMEBOY_SCREEN_HEIGHT);
+        //Clear first so the bars around the screen stay black
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
+        self.canvas
+            .copy(&self.texture, gameboy_display_dims, self.destination)
+            .unwrap();
+        self.canvas.present();
+    }
 
-pub struct Emu {
-    paused: bool,
+    fn update_destination(&mut self) {
+        let (width, height) = self.canvas.output_size().unwrap();
+        self.destination = fit_rect(width, height);
+    }
 }
 
-impl Emu {
-    pub fn new() -> Self {
-        Self { paused: false }
+//The biggest rect with the gameboy's 10:9 shape that fits in the window, centered so whatever is
+//left over becomes black bars on either the sides or the top and bottom
+fn fit_rect(window_width: u32, window_height: u32) -> Rect {
+    //Cross multiplying compares the shapes without any rounding
+    let (width, height) =
+        if window_width * GAMEBOY_SCREEN_HEIGHT > window_height * GAMEBOY_SCREEN_WIDTH {
+            (
+                window_height * GAMEBOY_SCREEN_WIDTH / GAMEBOY_SCREEN_HEIGHT,
+                window_height,
+            )
+        } else {
+            (
+                window_width,
+                window_width * GAMEBOY_SCREEN_HEIGHT / GAMEBOY_SCREEN_WIDTH,
+            )
+        };
+    Rect::new(
+        ((window_width - width) / 2) as i32,
+        ((window_height - height) / 2) as i32,
+        width,
+        height,
+    )
+}
+
+impl<'a> Display for SdlDisplay<'a> {
+    fn present(&mut self, framebuffer: &[u8]) {
+        self.upload_lines(framebuffer, 0, GAMEBOY_SCREEN_HEIGHT as usize);
+        self.draw();
+    }
+
+    //Each run of changed lines goes up in one update and an unchanged frame uploads nothing
+    fn present_dirty(&mut self, framebuffer: &[u8], dirty_lines: &[bool]) {
+        let mut line = 0;
+        while line < dirty_lines.len() {
+            if !dirty_lines[line] {
+                line += 1;
+                continue;
+            }
+            let start = line;
+            while line < dirty_lines.len() && dirty_lines[line] {
+                line += 1;
+            }
+            self.upload_lines(framebuffer, start, line);
+        }
+        self.draw();
     }
 
-    fn handle_events(&mut self, event_pump: &mut EventPump) {
-        for event in event_pump.poll_iter() {
+    fn poll_input(&mut self) -> InputState {
+        let input = &mut self.input;
+        input.clear_events();
+        let mut resized = false;
+        for event in self.event_pump.poll_iter() {
             match event {
-                // should probably handle this differently for exiting
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                } => resized = true,
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
+                } => input.quit = true,
                 Event::KeyDown {
                     keycode: Some(Keycode::Space),
                     ..
                 } => {
-                    self.paused = !self.paused;
+                    input.toggle_pause = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::N),
+                    ..
+                } => {
+                    input.step_frame = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::S),
+                    ..
+                } => {
+                    input.step_instruction = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => {
+                    input.turbo = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    repeat: false,
+                    ..
+                } => {
+                    input.screenshot = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::B),
+                    repeat: false,
+                    ..
+                } => {
+                    input.toggle_frame_blend = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => {
+                    input.reset = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    repeat: false,
+                    ..
+                } => {
+                    input.toggle_perf_overlay = true;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => {
+                    input.rewind = true;
+                }
+                Event::KeyUp {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => {
+                    input.turbo = false;
+                }
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => {
+                    input.rewind = false;
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    repeat: false,
+                    ..
+                } => {
+                    if let Some(button) = self.key_map.button(keycode) {
+                        input.key_down(button);
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(button) = self.key_map.button(keycode) {
+                        input.key_up(button);
+                    }
+                }
+                //Sdl sends this for controllers already plugged in at startup as well
+                Event::ControllerDeviceAdded { which, .. } => {
+                    if let Some(subsystem) = &self.controller_subsystem {
+                        match subsystem.open(which) {
+                            Ok(controller) => self.controllers.push(controller),
+                            Err(error) => warn!("Couldn't open controller {}: {}", which, error),
+                        }
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers
+                        .retain(|controller| controller.instance_id() != which);
+                }
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(button) = self.pad_map.button(button) {
+                        input.key_down(button);
+                    }
+                }
+                Event::ControllerButtonUp { button, .. } => {
+                    if let Some(button) = self.pad_map.button(button) {
+                        input.key_up(button);
+                    }
+                }
+                Event::ControllerAxisMotion { axis, value, .. } => {
+                    let (index, negative, positive) = match axis {
+                        Axis::LeftX => (0, Button::Left, Button::Right),
+                        Axis::LeftY => (1, Button::Up, Button::Down),
+                        _ => continue,
+                    };
+                    //Only touch the buttons when the stick changes direction so it doesn't fight
+                    //with the d-pad and keyboard every time it wobbles
+                    let pushed =
+                        axis_button(value, self.pad_map.stick_threshold, negative, positive);
+                    if pushed != self.stick[index] {
+                        if let Some(released) = self.stick[index] {
+                            input.key_up(released);
+                        }
+                        if let Some(pressed) = pushed {
+                            input.key_down(pressed);
+                        }
+                        self.stick[index] = pushed;
+                    }
                 }
                 _ => (),
             }
         }
+        let input = input.clone();
+        if resized {
+            self.update_destination();
+        }
+        input
     }
 
-    pub fn run(&mut self, mut gameboy: GameBoy) {
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
-        let window = video_subsystem
-            .window("rust_boi", WINDOW_WIDTH, WINDOW_HEIGHT)
-            .position_centered()
-            .build()
-            .unwrap();
-        let mut canvas = window.into_canvas().build().unwrap();
-        let mut event_pump = sdl_context.event_pump().unwrap();
-        let texture_creator = canvas.texture_creator();
-        let mut texture = texture_creator
-            .create_texture_static(
-                PixelFormatEnum::RGB24,
-                GAMEBOY_SCREEN_WIDTH,
-                GAMEBOY_SCREEN_HEIGHT,
-            )
-            .unwrap();
-        let mut pixel_data =
-            vec![0; GAMEBOY_SCREEN_WIDTH as usize * GAMEBOY_SCREEN_HEIGHT as usize * 3];
-        loop {
-            //handle events
-            self.handle_events(&mut event_pump);
-            if !self.paused {
-                let need_to_redraw = gameboy.step(&mut pixel_data);
-
-                if need_to_redraw {
-                    //redraw the screen
-                    let gameboy_display_dims =
-                        Rect::new(0, 0, GAMEBOY_SCREEN_WIDTH, GAMEBOY_SCREEN_HEIGHT);
-                    let sld_window_dims = Rect::new(0, 0, WINDOW_WIDTH, WINDOW_HEIGHT);
-                    texture
-                        .update(gameboy_display_dims, &pixel_data, BYTES_PER_ROW as usize)
-                        .unwrap();
-                    canvas
-                        .copy(&texture, gameboy_display_dims, sld_window_dims)
-                        .unwrap();
-                    canvas.present();
-                }
-            }
+    //Move the audio generated since the last frame over to the audio thread
+    fn play_audio(&mut self, apu: &mut Apu) {
+        let max_queued = match &self.audio_device {
+            //Keep at most half a second queued so we don't drift behind the video
+            Some(device) => device.spec().freq as usize * AUDIO_CHANNELS as usize / 2,
+            None => return,
+        };
+        queue_samples(apu, &self.audio_samples, max_queued);
+    }
+}
+
+//Drains the apu into the audio thread's queue, dropping the oldest samples past max_queued
+fn queue_samples(apu: &mut Apu, queue: &Mutex<VecDeque<i16>>, max_queued: usize) {
+    let mut frame_samples = vec![0; max_queued];
+    let count = apu.read_samples(&mut frame_samples);
+    let mut samples = queue.lock().unwrap();
+    samples.extend(&frame_samples[..count]);
+    while samples.len() > max_queued {
+        samples.pop_front();
+    }
+}
+
+//Opens a window scale times the size of the gameboy's screen and runs the emulator in it
+pub fn run(emu: &mut Emu, gameboy: &mut GameBoy, key_map: KeyMap, pad_map: PadMap, scale: u32) {
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let controller_subsystem = sdl_context
+        .game_controller()
+        .map_err(|error| warn!("Controllers won't work: {}", error))
+        .ok();
+    let title = match gameboy.memory.header().title.as_str() {
+        "" => "rust_boi".to_string(),
+        game => format!("rust_boi - {}", game),
+    };
+    let (window_width, window_height) = SdlDisplay::window_size(scale.max(1));
+    let window = video_subsystem
+        .window(&title, window_width, window_height)
+        .position_centered()
+        .resizable()
+        .build()
+        .unwrap();
+    let canvas = window.into_canvas().build().unwrap();
+    let event_pump = sdl_context.event_pump().unwrap();
+    //Nearest neighbour scaling keeps every gameboy pixel a crisp square instead of blurring
+    sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", "0");
+    let texture_creator = canvas.texture_creator();
+    let texture = texture_creator
+        .create_texture_static(
+            PixelFormatEnum::RGB24,
+            GAMEBOY_SCREEN_WIDTH,
+            GAMEBOY_SCREEN_HEIGHT,
+        )
+        .unwrap();
+    let mut display = SdlDisplay {
+        key_map,
+        pad_map,
+        controller_subsystem,
+        controllers: Vec::new(),
+        stick: [None; 2],
+        canvas,
+        texture,
+        destination: Rect::new(0, 0, window_width, window_height),
+        event_pump,
+        input: InputState::default(),
+        audio_device: None,
+        audio_samples: Arc::new(Mutex::new(VecDeque::new())),
+    };
+    display.update_destination();
+    display.open_audio(&audio_subsystem, gameboy);
+    emu.run(&mut display, gameboy);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gameboy::Model,
+        joypad::JOYPAD_ADDRESS,
+        memory::{Memory, RomChunk},
+    };
+
+    //A little over a frame of channel 2 playing a tone
+    fn playing_apu() -> Apu {
+        let mut memory = Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        memory.init_post_boot_io();
+        for (register, value) in [
+            (0xFF16, 0x80),
+            (0xFF17, 0xF0),
+            (0xFF18, 0x00),
+            (0xFF19, 0x87),
+        ] {
+            memory.write_u8(register, value);
         }
+        let mut apu = Apu::new();
+        for _ in 0..20_000 {
+            apu.step(&mut memory, 4);
+        }
+        apu
+    }
+
+    #[test]
+    fn callback_drains_the_queue_then_plays_silence() {
+        let mut apu = playing_apu();
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        queue_samples(&mut apu, &queue, 100);
+        assert_eq!(queue.lock().unwrap().len(), 100);
+        let queued = queue.lock().unwrap().iter().copied().collect::<Vec<_>>();
+        assert!(queued.iter().any(|sample| *sample != 0));
+
+        let mut output = AudioOutput {
+            samples: queue.clone(),
+        };
+        let mut out = [1; 64];
+        output.callback(&mut out);
+        assert_eq!(out[..], queued[..64]);
+        assert_eq!(queue.lock().unwrap().len(), 36);
+
+        //Only 36 are left so the rest of the buffer is silence
+        output.callback(&mut out);
+        assert_eq!(out[..36], queued[64..]);
+        assert_eq!(out[36..], [0; 28]);
+        assert!(queue.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn remapped_key_presses_its_button() {
+        let key_map = KeyMap {
+            a: Keycode::K,
+            ..KeyMap::default()
+        };
+        assert_eq!(key_map.button(Keycode::Z), None);
+        let mut input = InputState::default();
+        input.key_down(key_map.button(Keycode::K).unwrap());
+
+        let mut gameboy = GameBoy::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+        gameboy.inject_input(&input);
+        //Select the action buttons, pressed ones read as 0 with a in bit 0
+        gameboy.memory.write_u8(JOYPAD_ADDRESS, 0x10);
+        assert_eq!(gameboy.memory.read_u8(JOYPAD_ADDRESS) & 0x0F, 0x0E);
+    }
+
+    #[test]
+    fn window_is_the_screen_times_the_scale() {
+        assert_eq!(SdlDisplay::window_size(1), (160, 144));
+        assert_eq!(SdlDisplay::window_size(3), (480, 432));
+        //The frame fills a window of any scale edge to edge
+        let (width, height) = SdlDisplay::window_size(8);
+        assert_eq!((width, height), (1280, 1152));
+        assert_eq!(fit_rect(width, height), Rect::new(0, 0, width, height));
+    }
+
+    #[test]
+    fn fit_rect_fills_a_window_with_the_same_shape() {
+        assert_eq!(fit_rect(320, 288), Rect::new(0, 0, 320, 288));
+        assert_eq!(fit_rect(160, 144), Rect::new(0, 0, 160, 144));
+    }
+
+    #[test]
+    fn fit_rect_pillarboxes_wide_windows() {
+        assert_eq!(fit_rect(800, 288), Rect::new(240, 0, 320, 288));
+        //Sizes that don't divide evenly round down
+        assert_eq!(fit_rect(1000, 700), Rect::new(111, 0, 777, 700));
+    }
+
+    #[test]
+    fn fit_rect_letterboxes_tall_windows() {
+        assert_eq!(fit_rect(320, 600), Rect::new(0, 156, 320, 288));
+        assert_eq!(fit_rect(500, 1000), Rect::new(0, 275, 500, 450));
     }
 }
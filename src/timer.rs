@@ -0,0 +1,71 @@
+pub const DIV_ADDRESS: u16 = 0xFF04;
+
+#[derive(Default, Clone)]
+pub struct Timer {
+    //DIV is the upper byte of this internal counter which runs at the full 4MHz clock
+    div_counter: u16,
+    //Holds DIV still so games that seed randomness from it behave the same every run
+    frozen: bool,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn step(&mut self, cpu_cycles: u16) {
+        if self.frozen {
+            return;
+        }
+        //each cpu cycle is 4 clocks
+        self.div_counter = self.div_counter.wrapping_add(cpu_cycles * 4);
+    }
+
+    pub fn div(&self) -> u8 {
+        (self.div_counter >> 8) as u8
+    }
+
+    //Starts the counter so DIV reads back as value
+    pub fn set_div(&mut self, value: u8) {
+        self.div_counter = (value as u16) << 8;
+    }
+
+    pub fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    //Any write to DIV resets the whole internal counter
+    pub fn reset_div(&mut self) {
+        self.div_counter = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frozen_div_holds_its_value() {
+        let mut timer = Timer::new();
+        timer.set_div(0x42);
+        timer.set_frozen(true);
+        for _ in 0..1000 {
+            timer.step(64);
+            assert_eq!(timer.div(), 0x42);
+        }
+        //Thawing lets it count again from where it was held, one tick every 64 cycles
+        timer.set_frozen(false);
+        timer.step(64);
+        assert_eq!(timer.div(), 0x43);
+    }
+
+    #[test]
+    fn div_ticks_every_64_cycles_and_wraps() {
+        let mut timer = Timer::new();
+        timer.set_div(0xFF);
+        timer.step(63);
+        assert_eq!(timer.div(), 0xFF);
+        timer.step(1);
+        assert_eq!(timer.div(), 0x00);
+    }
+}
@@ -0,0 +1,84 @@
+use log::warn;
+
+use crate::cpu::Cpu;
+
+//How many of the last opcodes get printed when the watchdog goes off
+const RECENT_OPCODES: usize = 16;
+
+//Warns when the ppu goes too long without reaching vblank, which usually means the rom is stuck in
+//a loop waiting on something we don't emulate properly yet
+pub struct Watchdog {
+    //Machine cycles allowed between vblanks
+    budget: u64,
+    cycles_since_vblank: u64,
+    //Only warn once per hang instead of every budget
+    fired: bool,
+}
+
+impl Watchdog {
+    pub fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            cycles_since_vblank: 0,
+            fired: false,
+        }
+    }
+
+    //Called after each instruction, returns true the first time the budget runs out without a vblank
+    pub fn check(&mut self, cycles: u16, vblank: bool, cpu: &Cpu) -> bool {
+        if vblank {
+            self.cycles_since_vblank = 0;
+            self.fired = false;
+            return false;
+        }
+        self.cycles_since_vblank += cycles as u64;
+        if self.fired || self.cycles_since_vblank < self.budget {
+            return false;
+        }
+        self.fired = true;
+        let history = cpu.history();
+        let recent = history[history.len().saturating_sub(RECENT_OPCODES)..]
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        warn!(
+            "No vblank for {} cycles, stuck at pc {:04X} after:\n{}",
+            self.cycles_since_vblank,
+            cpu.registers.get_pc(),
+            recent
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fires_once_the_budget_passes_without_a_vblank() {
+        let cpu = Cpu::new();
+        let mut watchdog = Watchdog::new(100);
+        for _ in 0..24 {
+            assert!(!watchdog.check(4, false, &cpu));
+        }
+        assert!(watchdog.check(4, false, &cpu));
+        //Still stuck but it already said so
+        assert!(!watchdog.check(4, false, &cpu));
+
+        //A vblank starts the count again
+        assert!(!watchdog.check(4, true, &cpu));
+        assert!(!watchdog.check(96, false, &cpu));
+        assert!(watchdog.check(4, false, &cpu));
+    }
+
+    #[test]
+    fn stays_quiet_while_frames_complete() {
+        let cpu = Cpu::new();
+        let mut watchdog = Watchdog::new(100);
+        for step in 1..1000 {
+            assert!(!watchdog.check(4, step % 20 == 0, &cpu));
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use rust_boi::{
+    gameboy::{GameBoy, Model},
+    memory::RomChunk,
+};
+
+#[test]
+fn disassembles_across_stop_and_prefixed_ops() {
+    let mut rom = vec![0; 0x8000];
+    let code = [
+        0x00, 0x10, 0x00, 0x3E, 0x42, 0xCB, 0x7C, 0xC3, 0x50, 0x01, 0x18, 0xFE, 0xD3,
+    ];
+    rom[0x150..0x150 + code.len()].copy_from_slice(&code);
+    let gameboy = GameBoy::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Dmg).unwrap();
+    assert_eq!(
+        gameboy.disassemble_rom(0x150, 0x15C),
+        "0150: 00       nop\n\
+         0151: 10 00    stop 0\n\
+         0153: 3E 42    ld a, 0x42\n\
+         0155: CB 7C    bit 7, h\n\
+         0157: C3 50 01 jp 0x0150\n\
+         015A: 18 FE    jr 0x015A\n\
+         015C: D3       db 0xD3\n"
+    );
+}
@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+
+use rust_boi::{
+    display::{Display, InputState},
+    emu::Emu,
+    gameboy::{GameBoy, Model},
+    memory::RomChunk,
+    ppu::FRAMEBUFFER_SIZE,
+};
+
+const LY_ADDRESS: u16 = 0xFF44;
+
+//Hands out the inputs it was given one poll at a time and quits once they run out, keeping
+//every frame it is asked to show
+struct ScriptedDisplay {
+    inputs: VecDeque<InputState>,
+    presented: Vec<Vec<u8>>,
+}
+
+impl ScriptedDisplay {
+    fn new(inputs: Vec<InputState>) -> Self {
+        Self {
+            inputs: inputs.into(),
+            presented: Vec::new(),
+        }
+    }
+}
+
+impl Display for ScriptedDisplay {
+    fn present(&mut self, framebuffer: &[u8]) {
+        self.presented.push(framebuffer.to_vec());
+    }
+
+    fn poll_input(&mut self) -> InputState {
+        self.inputs.pop_front().unwrap_or_else(|| {
+            let mut input = InputState::default();
+            input.quit = true;
+            input
+        })
+    }
+}
+
+//A 32KB rom that sits in a jr -2 loop at the entry point forever
+fn spin_rom() -> RomChunk {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100] = 0x18;
+    rom[0x101] = 0xFE;
+    RomChunk::from_bytes(rom).unwrap()
+}
+
+fn step_frame() -> InputState {
+    let mut input = InputState::default();
+    input.step_frame = true;
+    input
+}
+
+fn paused_emu() -> Emu {
+    let mut emu = Emu::new();
+    emu.set_paused(true);
+    emu
+}
+
+#[test]
+fn stepping_while_paused_runs_exactly_one_frame() {
+    let mut gameboy = GameBoy::new(None, spin_rom(), Model::Dmg).unwrap();
+    //Polls without a step shouldn't run anything
+    let mut display = ScriptedDisplay::new(vec![InputState::default(), step_frame()]);
+    paused_emu().run(&mut display, &mut gameboy);
+    assert_eq!(gameboy.frame_count(), 1);
+    assert_eq!(display.presented.len(), 1);
+    let ly = gameboy.memory.read_u8(LY_ADDRESS);
+    let cycles = gameboy.cpu.total_cycles();
+
+    let mut display = ScriptedDisplay::new(vec![
+        step_frame(),
+        InputState::default(),
+        InputState::default(),
+    ]);
+    paused_emu().run(&mut display, &mut gameboy);
+    assert_eq!(gameboy.frame_count(), 2);
+    assert_eq!(display.presented.len(), 1);
+    //Back on the same line a whole frame of 154 lines of 114 cycles later
+    assert_eq!(gameboy.memory.read_u8(LY_ADDRESS), ly);
+    let stepped = gameboy.cpu.total_cycles() - cycles;
+    assert!((17556..17556 + 6).contains(&stepped), "{}", stepped);
+}
+
+#[test]
+fn runs_a_frame_for_every_poll_and_shows_each_one() {
+    //ld hl,BGP then inc (hl) forever so every frame comes out a different shade
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x106].copy_from_slice(&[0x21, 0x47, 0xFF, 0x34, 0x18, 0xFD]);
+    let mut gameboy = GameBoy::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Dmg).unwrap();
+    let mut display = ScriptedDisplay::new(vec![InputState::default(); 5]);
+    Emu::new().run(&mut display, &mut gameboy);
+
+    assert_eq!(gameboy.frame_count(), 5);
+    assert_eq!(display.presented.len(), 5);
+    assert!(display
+        .presented
+        .iter()
+        .all(|frame| frame.len() == FRAMEBUFFER_SIZE));
+    assert!(display.presented.windows(2).all(|pair| pair[0] != pair[1]));
+}
+
+#[test]
+fn quitting_on_the_first_poll_runs_nothing() {
+    let mut gameboy = GameBoy::new(None, spin_rom(), Model::Dmg).unwrap();
+    let mut display = ScriptedDisplay::new(Vec::new());
+    Emu::new().run(&mut display, &mut gameboy);
+    assert_eq!(gameboy.frame_count(), 0);
+    assert!(display.presented.is_empty());
+}
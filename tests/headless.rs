@@ -0,0 +1,281 @@
+use std::time::Instant;
+
+use rust_boi::{
+    display::InputState,
+    gameboy::{GameBoy, Model},
+    joypad::{Button, JOYPAD_ADDRESS},
+    memory::RomChunk,
+    ppu::FRAMEBUFFER_SIZE,
+    timer::DIV_ADDRESS,
+};
+
+//A frame is 154 lines of 456 dots
+const FRAME_CLOCKS: u32 = 70224;
+//Writing 0x0A anywhere below 0x2000 turns cartridge ram on
+const RAM_ENABLE_ADDRESS: u16 = 0x0000;
+const RAM_ENABLE: u8 = 0x0A;
+const CART_RAM_ADDRESS: u16 = 0xA000;
+//Speed switch register on the cgb
+const KEY1_ADDRESS: u16 = 0xFF4D;
+
+//A 32KB rom that sits in a jr -2 loop at the entry point forever
+fn spin_rom() -> RomChunk {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100] = 0x18;
+    rom[0x101] = 0xFE;
+    RomChunk::from_bytes(rom).unwrap()
+}
+
+#[test]
+fn runs_frames_and_takes_input_without_a_window() {
+    let mut gameboy = GameBoy::new(None, spin_rom(), Model::Dmg).unwrap();
+    gameboy.run_frame();
+    //A whole frame can overrun by the end of the instruction that finishes it
+    let clocks = gameboy.run_frame();
+    assert!((FRAME_CLOCKS..FRAME_CLOCKS + 24).contains(&clocks));
+    assert_eq!(gameboy.framebuffer().len(), FRAMEBUFFER_SIZE);
+
+    let mut input = InputState::default();
+    input.key_down(Button::Start);
+    gameboy.inject_input(&input);
+    gameboy.run_frame();
+    //Select the action buttons, pressed ones read as 0 with start in bit 3
+    gameboy.memory.write_u8(JOYPAD_ADDRESS, 0x10);
+    assert_eq!(gameboy.memory.read_u8(JOYPAD_ADDRESS) & 0x0F, 0x07);
+
+    //Released on the next poll
+    input.clear_events();
+    input.key_up(Button::Start);
+    gameboy.inject_input(&input);
+    assert_eq!(gameboy.memory.read_u8(JOYPAD_ADDRESS) & 0x0F, 0x0F);
+}
+
+#[test]
+fn a_tap_inside_one_poll_still_presses_the_button() {
+    let mut gameboy = GameBoy::new(None, spin_rom(), Model::Dmg).unwrap();
+    gameboy.memory.write_u8(JOYPAD_ADDRESS, 0x10);
+
+    let mut input = InputState::default();
+    input.key_down(Button::A);
+    input.key_up(Button::A);
+    gameboy.inject_input(&input);
+    //A is bit 0 of the action buttons
+    assert_eq!(gameboy.memory.read_u8(JOYPAD_ADDRESS) & 0x0F, 0x0E);
+
+    input.clear_events();
+    gameboy.inject_input(&input);
+    assert_eq!(gameboy.memory.read_u8(JOYPAD_ADDRESS) & 0x0F, 0x0F);
+}
+
+#[test]
+fn steps_a_single_instruction() {
+    let mut gameboy = GameBoy::new(None, spin_rom(), Model::Dmg).unwrap();
+    let mut pixels = vec![0; FRAMEBUFFER_SIZE];
+    let (clocks, redraw) = gameboy.step_instruction(&mut pixels);
+    //jr is 3 machine cycles and jumping back to itself leaves pc where it started
+    assert_eq!(clocks, 12);
+    assert!(!redraw);
+    assert_eq!(gameboy.cpu.capture_state().pc, 0x100);
+}
+
+#[test]
+fn reset_recovers_from_a_lock_up_and_keeps_the_save() {
+    //An mbc1 with battery backed ram whose first instruction is illegal
+    let mut rom = vec![0; 0x8000];
+    rom[0x100] = 0xD3;
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x02;
+    let mut gameboy = GameBoy::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Dmg).unwrap();
+    let power_on = gameboy.save_state();
+    let mut pixels = vec![0; FRAMEBUFFER_SIZE];
+
+    gameboy.memory.write_u8(RAM_ENABLE_ADDRESS, RAM_ENABLE);
+    gameboy.memory.write_u8(CART_RAM_ADDRESS, 0x5A);
+    gameboy.step_instruction(&mut pixels);
+    assert!(gameboy.cpu.locked());
+
+    gameboy.reset(&power_on);
+    assert!(!gameboy.cpu.locked());
+    assert_eq!(gameboy.cpu.capture_state().pc, 0x100);
+    gameboy.memory.write_u8(RAM_ENABLE_ADDRESS, RAM_ENABLE);
+    assert_eq!(gameboy.memory.read_u8(CART_RAM_ADDRESS), 0x5A);
+}
+
+#[test]
+fn step_frame_gives_up_at_the_instruction_cap() {
+    //Turns the lcd off and then spins forever
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x106].copy_from_slice(&[0xAF, 0xE0, 0x40, 0x18, 0xFE, 0x00]);
+    let mut gameboy = GameBoy::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Dmg).unwrap();
+    gameboy.set_instruction_cap(1000);
+    let mut pixels = vec![0; FRAMEBUFFER_SIZE];
+    //Switching the lcd off finishes a frame straight away to show the blank screen
+    gameboy.step_frame(&mut pixels);
+
+    let start = gameboy.instruction_count();
+    let clocks = gameboy.step_frame(&mut pixels);
+    assert_eq!(gameboy.instruction_count() - start, 1000);
+    //jr is 3 machine cycles, far short of a frame
+    assert_eq!(clocks, 1000 * 12);
+    assert_eq!(gameboy.cpu.capture_state().pc, 0x103);
+}
+
+#[test]
+fn boot_rom_hands_over_at_0x100() {
+    //Counts bc down from 0x100 then turns itself off through 0xFF50 in its last two bytes, the
+    //way the real one does, so the cartridge runs on from 0x100
+    let mut boot = vec![0; 0x100];
+    boot[..11].copy_from_slice(&[
+        0x31, 0xFE, 0xFF, 0x01, 0x00, 0x01, 0x0B, 0x78, 0xB1, 0x20, 0xFB,
+    ]);
+    boot[11..14].copy_from_slice(&[0xC3, 0xFC, 0x00]);
+    boot[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+    let boot = RomChunk::from_bytes(boot).unwrap();
+    let mut gameboy = GameBoy::new(Some(boot), spin_rom(), Model::Dmg).unwrap();
+    let mut pixels = vec![0; FRAMEBUFFER_SIZE];
+
+    let mut clocks = 0;
+    while gameboy.memory.booting() {
+        clocks += gameboy.step_instruction(&mut pixels).0 as u32;
+    }
+    assert_eq!(gameboy.cpu.capture_state().pc, 0x100);
+    //Both loads, 255 trips round the loop at 7 cycles, the last one at 6 and then the hand over
+    assert_eq!(clocks, (3 + 3 + 255 * 7 + 6 + 4 + 2 + 3) * 4);
+}
+
+#[test]
+fn runs_a_rom_built_in_memory() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x134..0x13C].copy_from_slice(b"INMEMORY");
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x02;
+    //ld a,0x42 then ld (0xC000),a then spin
+    rom[0x100..0x107].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+    //Too short to be a boot rom, and then too short to hold a cartridge header
+    assert!(RomChunk::from_bytes(rom[..0xFF].to_vec()).is_err());
+    let no_header = RomChunk::from_bytes(rom[..0x100].to_vec()).unwrap();
+    assert!(GameBoy::new(None, no_header, Model::Dmg).is_err());
+
+    let mut gameboy = GameBoy::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Dmg).unwrap();
+    let header = gameboy.memory.header();
+    assert_eq!(header.title, "INMEMORY");
+    assert_eq!(header.cartridge_type, 0x03);
+    assert_eq!(header.ram_size, 0x02);
+
+    let mut pixels = vec![0; FRAMEBUFFER_SIZE];
+    for _ in 0..3 {
+        gameboy.step_instruction(&mut pixels);
+    }
+    assert_eq!(gameboy.memory.read_u8(0xC000), 0x42);
+    assert_eq!(gameboy.cpu.capture_state().pc, 0x105);
+}
+
+#[test]
+fn run_frames_hands_back_each_new_frame() {
+    //ld hl,BGP then inc (hl) forever so the background shade keeps moving
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x106].copy_from_slice(&[0x21, 0x47, 0xFF, 0x34, 0x18, 0xFD]);
+    let mut gameboy = GameBoy::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Dmg).unwrap();
+
+    let first = gameboy.run_frames(2);
+    assert_eq!(first.len(), FRAMEBUFFER_SIZE);
+    assert!(first.iter().any(|byte| *byte != 0));
+    let second = gameboy.run_frames(1);
+    assert_eq!(second.len(), FRAMEBUFFER_SIZE);
+    assert_ne!(first, second);
+}
+
+#[test]
+fn ppu_and_timer_run_on_the_cycles_the_cpu_reports() {
+    let mut gameboy = GameBoy::new(None, spin_rom(), Model::Dmg).unwrap();
+    let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+    gameboy.step_frame(&mut pixel_data);
+    //Any write to DIV starts its counter over
+    gameboy.memory.write_u8(DIV_ADDRESS, 0);
+    let start = gameboy.cpu.total_cycles();
+
+    let clocks = (0..10)
+        .map(|_| gameboy.step_frame(&mut pixel_data) as u64)
+        .sum::<u64>();
+    let cycles = gameboy.cpu.total_cycles() - start;
+    assert_eq!(clocks, cycles * 4);
+    //The ppu only finishes frames on the clocks it was given, so ten of them can only be off by
+    //however far the first and last instructions ran past the end of a frame
+    let frames = 10 * FRAME_CLOCKS as u64;
+    assert!((frames - 24..frames + 24).contains(&clocks));
+    //DIV ticks every 256 clocks
+    assert_eq!(gameboy.memory.read_u8(DIV_ADDRESS), (clocks / 256) as u8);
+}
+
+#[test]
+fn step_frame_takes_a_frame_of_clocks_on_an_idle_machine() {
+    //di then halt with nothing enabled, so the cpu sleeps for good
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x102].copy_from_slice(&[0xF3, 0x76]);
+    let mut gameboy = GameBoy::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Dmg).unwrap();
+    let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+    gameboy.step_frame(&mut pixel_data);
+    for _ in 0..3 {
+        let clocks = gameboy.step_frame(&mut pixel_data);
+        assert!((FRAME_CLOCKS - 24..FRAME_CLOCKS + 24).contains(&clocks));
+    }
+    assert_eq!(gameboy.frame_count(), 4);
+}
+
+#[test]
+fn arming_key1_then_stop_switches_to_double_speed() {
+    //ld a,0x01, ldh (KEY1),a, stop, then spin
+    let mut rom = vec![0; 0x8000];
+    rom[0x143] = 0x80;
+    rom[0x100..0x108].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE]);
+    let mut gameboy = GameBoy::new(None, RomChunk::from_bytes(rom).unwrap(), Model::Cgb).unwrap();
+    let mut pixel_data = vec![0; FRAMEBUFFER_SIZE];
+    for _ in 0..2 {
+        gameboy.step_instruction(&mut pixel_data);
+    }
+    assert_eq!(gameboy.memory.read_u8(KEY1_ADDRESS) & 0x81, 0x01);
+    //Stop does the switch instead of stopping and starts DIV over
+    gameboy.step_instruction(&mut pixel_data);
+    assert!(gameboy.memory.double_speed());
+    assert_eq!(gameboy.memory.read_u8(KEY1_ADDRESS) & 0x81, 0x80);
+    assert_eq!(gameboy.cpu.capture_state().pc, 0x106);
+
+    //The timer runs off the cpu so against the 4MHz clock it now ticks twice as fast
+    let mut clocks = 0;
+    while clocks < 10_000 {
+        clocks += gameboy.step_instruction(&mut pixel_data).0 as u32;
+    }
+    assert_eq!(
+        gameboy.memory.read_u8(DIV_ADDRESS),
+        (clocks * 2 / 256) as u8
+    );
+}
+
+#[test]
+fn batching_instructions_draws_the_same_frames() {
+    //With the lcd off fill tile 0 with 0x5A, then turn it back on and spin
+    let code = [
+        0xAF, 0xE0, 0x40, 0x21, 0x00, 0x80, 0x3E, 0x5A, 0x06, 0x10, 0x22, 0x05, 0x20, 0xFC, 0x3E,
+        0x91, 0xE0, 0x40, 0x18, 0xFE,
+    ];
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+    let run = |batch_size: u32| {
+        let rom = RomChunk::from_bytes(rom.clone()).unwrap();
+        let mut gameboy = GameBoy::new(None, rom, Model::Dmg).unwrap();
+        gameboy.set_batch_size(batch_size);
+        let start = Instant::now();
+        let frame = gameboy.run_frames(5);
+        (frame, start.elapsed())
+    };
+    let (single, single_time) = run(1);
+    let (batched, batched_time) = run(64);
+    println!(
+        "5 frames took {:?} one at a time and {:?} in batches of 64",
+        single_time, batched_time
+    );
+    assert_eq!(single, batched);
+    //Stripes of the darkest and lightest shade
+    assert!(single.contains(&0x00));
+    assert!(single.contains(&0xFF));
+}
@@ -0,0 +1,44 @@
+use rust_boi::{
+    gameboy::{GameBoy, Model},
+    link,
+    memory::{RomChunk, INTERRUPT_FLAG_ADDRESS, SERIAL_INTERRUPT},
+    ppu::FRAMEBUFFER_SIZE,
+};
+
+const SERIAL_DATA_ADDRESS: u16 = 0xFF01;
+
+//A rom that puts data in SB, starts a transfer with the given control value and then spins
+fn transfer_rom(data: u8, control: u8) -> RomChunk {
+    let mut rom = vec![0; 0x8000];
+    let code = [
+        0x3E, data, 0xE0, 0x01, 0x3E, control, 0xE0, 0x02, 0x18, 0xFE,
+    ];
+    rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+    RomChunk::from_bytes(rom).unwrap()
+}
+
+#[test]
+fn linked_gameboys_swap_a_byte() {
+    //The first one drives the clock and the second waits on it
+    let mut first = GameBoy::new(None, transfer_rom(0x42, 0x81), Model::Dmg).unwrap();
+    let mut second = GameBoy::new(None, transfer_rom(0x99, 0x80), Model::Dmg).unwrap();
+    link::connect(&mut first, &mut second);
+    let mut first_pixels = vec![0; FRAMEBUFFER_SIZE];
+    let mut second_pixels = vec![0; FRAMEBUFFER_SIZE];
+    link::run_linked_frame(
+        &mut first,
+        &mut second,
+        &mut first_pixels,
+        &mut second_pixels,
+    );
+
+    for (gameboy, received) in [(&mut first, 0x99), (&mut second, 0x42)] {
+        assert_eq!(gameboy.memory.read_u8(SERIAL_DATA_ADDRESS), received);
+        assert!(!gameboy.memory.serial_transferring());
+        assert_ne!(
+            gameboy.memory.read_u8(INTERRUPT_FLAG_ADDRESS) & SERIAL_INTERRUPT,
+            0
+        );
+        assert_eq!(gameboy.memory.take_serial_output(), "");
+    }
+}
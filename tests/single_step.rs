@@ -0,0 +1,101 @@
+use std::{fs, path::Path};
+
+use rust_boi::{
+    cpu_state::CpuState,
+    gameboy::{GameBoy, Model},
+    memory::{RomChunk, INTERRUPT_ENABLE_ADDRESS, INTERRUPT_FLAG_ADDRESS},
+};
+use serde_json::Value;
+
+//Cases in the sm83 single step format, every json file holds a list of cases for one opcode with
+//the registers and ram before and after running it
+const FIXTURE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/single_step");
+
+fn parse_state(state: &Value) -> (CpuState, Vec<(u16, u8)>) {
+    let byte = |key: &str| {
+        state[key]
+            .as_u64()
+            .unwrap_or_else(|| panic!("missing {}", key)) as u8
+    };
+    let word = |key: &str| {
+        state[key]
+            .as_u64()
+            .unwrap_or_else(|| panic!("missing {}", key)) as u16
+    };
+    let mut ram = state["ram"]
+        .as_array()
+        .expect("missing ram")
+        .iter()
+        .map(|entry| {
+            (
+                entry[0].as_u64().expect("bad ram address") as u16,
+                entry[1].as_u64().expect("bad ram value") as u8,
+            )
+        })
+        .collect::<Vec<_>>();
+    //IE lives at the top of memory so it is set up the same way as the rest of ram
+    ram.push((INTERRUPT_ENABLE_ADDRESS, byte("ie")));
+    let cpu_state = CpuState::new()
+        .a(byte("a"))
+        .f(byte("f"))
+        .b(byte("b"))
+        .c(byte("c"))
+        .d(byte("d"))
+        .e(byte("e"))
+        .h(byte("h"))
+        .l(byte("l"))
+        .sp(word("sp"))
+        .pc(word("pc"))
+        .ime(byte("ime") != 0);
+    (cpu_state, ram)
+}
+
+fn run_case(gameboy: &mut GameBoy, case: &Value) {
+    let name = case["name"].as_str().unwrap_or("unnamed");
+    let (initial, initial_ram) = parse_state(&case["initial"]);
+    let (expected, expected_ram) = parse_state(&case["final"]);
+
+    //Leftover interrupt requests would get serviced instead of running the instruction
+    gameboy.memory.poke(INTERRUPT_FLAG_ADDRESS, 0);
+    for (address, value) in initial_ram {
+        gameboy.memory.poke(address, value);
+    }
+    gameboy.cpu.load_state(&initial);
+    gameboy.cpu.step(&mut gameboy.memory);
+
+    assert_eq!(gameboy.cpu.capture_state(), expected, "{}", name);
+    for (address, value) in expected_ram {
+        assert_eq!(
+            gameboy.memory.read_u8(address),
+            value,
+            "{} at {:04X}",
+            name,
+            address
+        );
+    }
+}
+
+#[test]
+fn single_step_cases_pass() {
+    let mut paths = fs::read_dir(Path::new(FIXTURE_DIR))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    //Memory is treated as flat so the cases can put code anywhere, and one machine runs them all
+    //so nothing from an earlier case like a stop is allowed to leak into the next one
+    let mut gameboy = GameBoy::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+    gameboy.memory.allow_rom_pokes(true);
+    for path in paths {
+        let cases: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        for case in cases.as_array().expect("expected a list of cases") {
+            run_case(&mut gameboy, case);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::path::Path;
+
+use rust_boi::{
+    gameboy::{GameBoy, Model},
+    memory::RomChunk,
+};
+
+// Point this at a blargg test rom (e.g. cpu_instrs.gb) to run it as part of cargo test, without it
+// there is nothing to run and the test passes straight away
+const TEST_ROM_ENV: &str = "RUST_BOI_TEST_ROM";
+// Set this to a number from 0 to 255 to hold DIV at that value so test runs are repeatable
+const FIXED_DIV_ENV: &str = "RUST_BOI_FIXED_DIV";
+// About a minute of emulated time which is plenty for the individual cpu_instrs roms
+const TEST_ROM_MAX_FRAMES: u32 = 60 * 60;
+
+//Blargg's roms print their results over serial so run until one of them shows up
+fn run_test_rom(mut gameboy: GameBoy) -> String {
+    let mut serial_output = String::new();
+    for _ in 0..TEST_ROM_MAX_FRAMES {
+        gameboy.run_frames(1);
+        serial_output.push_str(&gameboy.memory.take_serial_output());
+        if serial_output.contains("Passed") || serial_output.contains("Failed") {
+            break;
+        }
+        //Nothing more is ever going to come out
+        if gameboy.cpu.locked() {
+            serial_output.push_str("\nLocked up on an illegal opcode");
+            break;
+        }
+    }
+    serial_output
+}
+
+#[test]
+fn test_rom_passes() {
+    let Ok(path) = std::env::var(TEST_ROM_ENV) else {
+        eprintln!("{} isn't set so there is no test rom to run", TEST_ROM_ENV);
+        return;
+    };
+    let cart_rom = RomChunk::new(Some(Path::new(&path))).unwrap();
+    let mut gameboy = GameBoy::new(None, cart_rom, Model::Cgb).unwrap();
+    if let Ok(div) = std::env::var(FIXED_DIV_ENV) {
+        let div = div
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be from 0 to 255", FIXED_DIV_ENV));
+        gameboy.memory.fix_div(div);
+    }
+    let output = run_test_rom(gameboy);
+    assert!(
+        output.contains("Passed") && !output.contains("Failed"),
+        "{} didn't pass:\n{}",
+        path,
+        output
+    );
+}
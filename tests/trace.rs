@@ -0,0 +1,99 @@
+use std::{cell::RefCell, sync::Once};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rust_boi::{
+    cpu::Cpu,
+    cpu_state::CpuState,
+    gameboy::Model,
+    instructions::InstructionCategory,
+    memory::{Memory, RomChunk},
+};
+
+const CODE: u16 = 0xC000;
+
+thread_local! {
+    //Each test runs on its own thread so it only ever sees its own lines
+    static CAPTURED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+//Keeps every info line logged on the current thread
+struct CapturingLogger;
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            CAPTURED.with(|captured| captured.borrow_mut().push(record.args().to_string()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger;
+static INIT: Once = Once::new();
+
+//Hands back whatever the current thread has logged since the last call
+fn take_logs() -> Vec<String> {
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Info);
+    });
+    CAPTURED.with(|captured| captured.borrow_mut().drain(..).collect())
+}
+
+//Puts code in work ram and points the cpu at it
+fn setup(code: &[u8]) -> (Cpu, Memory) {
+    let mut memory = Memory::new(None, RomChunk::new(None).unwrap(), Model::Dmg).unwrap();
+    for (i, byte) in code.iter().enumerate() {
+        memory.write_u8(CODE + i as u16, *byte);
+    }
+    let mut cpu = Cpu::new();
+    cpu.load_state(&CpuState::new().pc(CODE).sp(0xFFFE));
+    take_logs();
+    (cpu, memory)
+}
+
+#[test]
+fn trace_shows_where_a_jump_goes() {
+    //jp 0xC000
+    let (mut cpu, mut memory) = setup(&[0xC3, 0x00, 0xC0]);
+    cpu.set_trace(true);
+    cpu.step(&mut memory);
+    let logs = take_logs();
+    assert!(
+        logs.iter().any(|line| line.starts_with("C000: jp 0xC000")
+            && line.ends_with("SP:FFFE -> AF:0000 BC:0000 DE:0000 HL:0000 SP:FFFE")),
+        "{:?}",
+        logs
+    );
+
+    //Nothing is traced while it is off
+    cpu.set_trace(false);
+    cpu.step(&mut memory);
+    assert!(!take_logs().iter().any(|line| line.contains("->")));
+}
+
+#[test]
+fn only_logged_categories_are_logged() {
+    //add a,b then jp 0xC001 back onto itself
+    let (mut cpu, mut memory) = setup(&[0x80, 0xC3, 0x01, 0xC0]);
+    cpu.set_logged_categories(&[InstructionCategory::Jump]);
+    cpu.step(&mut memory);
+    assert_eq!(take_logs(), Vec::<String>::new());
+    cpu.step(&mut memory);
+    let logs = take_logs();
+    assert!(
+        logs.iter().any(|line| line.contains("pc c001")),
+        "{:?}",
+        logs
+    );
+
+    cpu.set_logged_categories(&InstructionCategory::ALL);
+    cpu.load_state(&CpuState::new().pc(CODE));
+    cpu.step(&mut memory);
+    assert!(!take_logs().is_empty());
+}